@@ -0,0 +1,238 @@
+//! Backups of a wallet, in a single portable file: a snapshot of its aggregated state along with
+//! its descriptors and settings.
+//!
+//! A snapshot is taken from a loaded wallet while holding the manager's lock, like the processing
+//! of notifications, so it always reflects the wallet as of a single tip. A wallet restored from
+//! it starts from this tip, which must be known to the node, and catches up from there.
+
+use bdk_chain::{
+    bitcoin::{
+        self,
+        hashes::{sha256, Hash},
+    },
+    BlockId,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::{
+    error,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use crate::{
+    descriptors::ImportedDescriptor,
+    rpc_interface::RpcInterface,
+    store_crypto::{EncryptionParams, SealedRecord, StoreKey},
+    wallet::{ChangeSet, SettingsChangeSet},
+    NETWORK,
+};
+
+const BACKUP_FORMAT: &str = "bdk_core_backup";
+// Version 2 added the imported descriptors.
+const BACKUP_VERSION: u32 = 2;
+// What the encrypted snapshots are bound to.
+const BACKUP_AAD: &[u8] = b"bdk_core_backup";
+
+/// A snapshot of a wallet, with everything needed to restore it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalletBackup {
+    pub network: bitcoin::Network,
+    /// The name of the wallet it was taken from.
+    pub name: String,
+    /// The descriptor of the wallet, with its checksum.
+    pub descriptor: String,
+    /// The descriptors imported into the wallet.
+    pub imported: Vec<ImportedDescriptor>,
+    /// The tip of the wallet's chain as of the snapshot.
+    pub tip: BlockId,
+    pub settings: SettingsChangeSet,
+    /// The aggregated state of the wallet.
+    pub changeset: ChangeSet,
+}
+
+// A snapshot as of version 1 of the backups, before descriptors could be imported.
+#[derive(Debug, Serialize, Deserialize)]
+struct WalletBackupV1 {
+    network: bitcoin::Network,
+    name: String,
+    descriptor: String,
+    tip: BlockId,
+    settings: SettingsChangeSet,
+    changeset: ChangeSet,
+}
+
+impl From<WalletBackupV1> for WalletBackup {
+    fn from(snapshot: WalletBackupV1) -> Self {
+        Self {
+            network: snapshot.network,
+            name: snapshot.name,
+            descriptor: snapshot.descriptor,
+            imported: Vec::new(),
+            tip: snapshot.tip,
+            settings: snapshot.settings,
+            changeset: snapshot.changeset,
+        }
+    }
+}
+
+// The content of a backup file. The snapshot is either in clear along with its hash, to detect a
+// corrupted file, or sealed if the backup is encrypted.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupFile<S> {
+    format: String,
+    version: u32,
+    encryption: Option<EncryptionParams>,
+    snapshot: Option<S>,
+    checksum: Option<sha256::Hash>,
+    sealed: Option<SealedRecord>,
+}
+
+// Enough of a backup file to tell how to read the rest.
+#[derive(Deserialize)]
+struct BackupProbe {
+    format: String,
+    version: u32,
+}
+
+fn checksum<S: Serialize>(snapshot: &S) -> Result<sha256::Hash, Box<dyn error::Error>> {
+    Ok(sha256::Hash::hash(&serde_json::to_vec(snapshot)?))
+}
+
+/// Write this snapshot to a file at this path, only readable by the current user. It's encrypted
+/// with the key if there's one, like the wallet stores. An existing file is replaced.
+pub fn write_backup(
+    path: &Path,
+    snapshot: &WalletBackup,
+    key: Option<&StoreKey>,
+) -> Result<(), Box<dyn error::Error>> {
+    let file = match key {
+        Some(key) => {
+            let (cipher, params) = key.new_cipher(BACKUP_AAD)?;
+            BackupFile {
+                format: BACKUP_FORMAT.to_string(),
+                version: BACKUP_VERSION,
+                encryption: Some(params),
+                snapshot: None,
+                checksum: None,
+                sealed: Some(cipher.seal(snapshot)?),
+            }
+        }
+        None => BackupFile {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            encryption: None,
+            snapshot: Some(snapshot.clone()),
+            checksum: Some(checksum(snapshot)?),
+            sealed: None,
+        },
+    };
+    // Don't leave a partially written backup at the path.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec_pretty(&file)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the snapshot from the backup file at this path, decrypting it with the key if it's
+/// encrypted. Fails if the file is corrupted, or if the snapshot isn't for our network.
+pub fn read_backup(
+    path: &Path,
+    key: Option<&StoreKey>,
+) -> Result<WalletBackup, Box<dyn error::Error>> {
+    let content = fs::read(path)?;
+    let not_a_backup =
+        |e: serde_json::Error| format!("{} is not a wallet backup: '{}'", path.display(), e);
+    let probe: BackupProbe = serde_json::from_slice(&content).map_err(not_a_backup)?;
+    if probe.format != BACKUP_FORMAT {
+        return Err(format!("{} is not a wallet backup.", path.display()).into());
+    }
+    let snapshot = match probe.version {
+        1 => {
+            let file = serde_json::from_slice(&content).map_err(not_a_backup)?;
+            open_snapshot::<WalletBackupV1>(file, path, key)?.into()
+        }
+        BACKUP_VERSION => {
+            let file = serde_json::from_slice(&content).map_err(not_a_backup)?;
+            open_snapshot::<WalletBackup>(file, path, key)?
+        }
+        version => {
+            return Err(format!(
+                "The backup {} is at version {}, which is more recent than this program's {}.",
+                path.display(),
+                version,
+                BACKUP_VERSION
+            )
+            .into())
+        }
+    };
+    if snapshot.network != NETWORK {
+        return Err(format!(
+            "The backup {} is for {}, not {}.",
+            path.display(),
+            snapshot.network,
+            NETWORK
+        )
+        .into());
+    }
+    Ok(snapshot)
+}
+
+// The snapshot of this backup file, checked against its hash or decrypted with the key.
+fn open_snapshot<S: Serialize + DeserializeOwned>(
+    file: BackupFile<S>,
+    path: &Path,
+    key: Option<&StoreKey>,
+) -> Result<S, Box<dyn error::Error>> {
+    match (file.encryption, file.sealed, file.snapshot, file.checksum) {
+        (Some(params), Some(sealed), None, None) => {
+            let key = key.ok_or("The backup is encrypted, a key is needed to restore it.")?;
+            key.open_cipher(&params, BACKUP_AAD)?.open(&sealed)
+        }
+        (None, None, Some(snapshot), Some(hash)) if checksum(&snapshot)? == hash => Ok(snapshot),
+        _ => Err(format!("The backup {} is corrupted.", path.display()).into()),
+    }
+}
+
+/// Check the tip of this snapshot is known to the node. If it was since reorged out, the restored
+/// wallet handles it when catching up, like any wallet which was offline during a reorg. If the
+/// node doesn't know it at all, the snapshot is from another chain and isn't restored.
+pub async fn check_backup_tip(
+    rpc: &RpcInterface,
+    snapshot: &WalletBackup,
+) -> Result<(), Box<dyn error::Error>> {
+    let node_tip = rpc.get_tip().await?;
+    if rpc
+        .is_in_best_chain(&node_tip.hash, &snapshot.tip.hash)
+        .await?
+    {
+        return Ok(());
+    }
+    match rpc
+        .common_ancestor(&node_tip.hash, &snapshot.tip.hash)
+        .await?
+    {
+        Some(ancestor) => {
+            println!(
+                "The tip of the backup at height {} is not in the best chain anymore. The wallet will be synced from height {}.",
+                snapshot.tip.height, ancestor.height
+            );
+            Ok(())
+        }
+        None => Err(format!(
+            "The tip of the backup, block {} at height {}, is unknown to the node.",
+            snapshot.tip.hash, snapshot.tip.height
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,115 @@
+//! Serve the wallet RPC commands through Bitcoin Core's JSON-RPC server.
+
+use capnp_rpc::pry;
+use serde_json::Value;
+
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    chain_capnp::actor_callback::{CallParams, CallResults},
+    notifications::{Subscription, TipSync},
+    rpc_commands::{
+        wallet_name_from_uri, RpcError, WalletCommand, RPC_INVALID_PARAMETER, RPC_MISC_ERROR,
+        WALLET_COMMANDS,
+    },
+    rpc_interface::{NodeChain, RpcInterface},
+    wallet_manager::WalletManager,
+};
+
+/// Prefix of the commands we register, so they don't clash with those of Core's own wallet.
+const COMMAND_PREFIX: &str = "bdk_";
+const COMMAND_CATEGORY: &str = "bdk";
+
+/// Called by Core whenever one of our commands is invoked by a JSON-RPC client.
+struct CommandActor {
+    chain: NodeChain,
+    manager: Arc<Mutex<WalletManager>>,
+    tip_sync: TipSync,
+    command: &'static WalletCommand,
+}
+
+impl crate::chain_capnp::actor_callback::Server for CommandActor {
+    fn call(
+        &mut self,
+        params: CallParams,
+        mut results: CallResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        let request = pry!(pry!(params.get()).get_request());
+        let uri = pry!(pry!(request.get_uri()).to_str());
+        let params = pry!(pry!(request.get_params()).to_str());
+        let wallet_name = wallet_name_from_uri(uri);
+        let params = parse_params(params);
+
+        let (chain, manager, command) = (self.chain.clone(), self.manager.clone(), self.command);
+        let tip_sync = self.tip_sync.clone();
+        ::capnp::capability::Promise::from_future(async move {
+            let res = match params {
+                Ok(params) => {
+                    // Like Core's wallet, answer as of the node's current tip.
+                    match tip_sync.sync_to_current_tip(&chain).await {
+                        Ok(_) => {
+                            command
+                                .call(&chain, &manager, wallet_name.as_deref(), &params)
+                                .await
+                        }
+                        Err(e) => Err(RpcError::new(RPC_MISC_ERROR, e.to_string())),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let mut results = results.get();
+            match res {
+                Ok(response) => {
+                    results.set_response(response.to_string().as_str());
+                    results.set_result(true);
+                }
+                Err(e) => results.set_rpc_error(e.to_json().to_string().as_str()),
+            }
+            Ok(())
+        })
+    }
+}
+
+// Core passes the parameters as serialized JSON, or an empty string if there are none.
+fn parse_params(params: &str) -> Result<Value, RpcError> {
+    if params.is_empty() {
+        return Ok(Value::Null);
+    }
+    serde_json::from_str(params)
+        .map_err(|e| RpcError::new(RPC_INVALID_PARAMETER, format!("Invalid params: {}", e)))
+}
+
+/// Register all the wallet commands with Core's JSON-RPC server, as `bdk_<command>`. They are
+/// served as long as the returned handlers are kept alive, once the notifications of this
+/// subscription up to the node's tip were processed.
+pub async fn register_commands(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    subscription: &Subscription,
+) -> Result<Vec<crate::handler_capnp::handler::Client>, Box<dyn std::error::Error>> {
+    let mut handlers = Vec::with_capacity(WALLET_COMMANDS.len());
+    for (unique_id, command) in WALLET_COMMANDS.iter().enumerate() {
+        let actor = capnp_rpc::new_client(CommandActor {
+            chain: rpc.chain.clone(),
+            manager: manager.clone(),
+            tip_sync: subscription.tip_sync(),
+            command,
+        });
+        let name = format!("{}{}", COMMAND_PREFIX, command.name);
+        let handler = rpc
+            .handle_rpc(
+                COMMAND_CATEGORY,
+                &name,
+                actor,
+                command.args,
+                unique_id.try_into()?,
+            )
+            .await?;
+        handlers.push(handler);
+    }
+    println!(
+        "Registered {} wallet RPC commands with bitcoin-node.",
+        handlers.len()
+    );
+    Ok(handlers)
+}
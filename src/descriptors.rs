@@ -0,0 +1,118 @@
+//! Descriptors imported into a wallet next to the one it was created with, and the export of all
+//! of them in the format of Bitcoin Core's `importdescriptors`.
+//!
+//! Each imported descriptor is tracked as its own keychain, after the wallet's own descriptor.
+//! They're listed in a JSON file in the wallet's directory in the order of their keychains, along
+//! with the height from which the chain must be rescanned for them. The file is sealed if the
+//! wallet's store is encrypted. Descriptors are imported while the wallet isn't running, and
+//! rescanned once it's started.
+
+use bdk_chain::miniscript::{descriptor::checksum::desc_checksum, Descriptor, DescriptorPublicKey};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    error,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::store_crypto::{self, FileSealer, StoreKey};
+
+// The descriptors imported into the wallet, in the wallet's directory.
+const IMPORTED_FILE: &str = "imported_descriptors.json";
+
+/// A descriptor imported into a wallet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportedDescriptor {
+    /// The descriptor, with its checksum.
+    pub descriptor: String,
+    /// The height of the first block which may pay to it.
+    pub birthday: u32,
+    /// The last derivation index to watch at least, for a ranged descriptor.
+    pub range_end: Option<u32>,
+    /// Whether the blocks from its birthday were rescanned since it was imported.
+    pub rescanned: bool,
+}
+
+/// A descriptor as expected by Bitcoin Core's `importdescriptors`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoreDescriptor {
+    pub desc: String,
+    /// The time from which to scan the chain for it. 0 to scan the whole chain.
+    pub timestamp: u32,
+    /// Whether new addresses are derived from it.
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal: Option<bool>,
+    /// The derivation indexes to watch, for a ranged descriptor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<[u32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_index: Option<u32>,
+}
+
+/// Parse a descriptor to import. Like Bitcoin Core, it must come with its checksum so that typos
+/// aren't silently turned into another descriptor.
+pub fn parse_descriptor(
+    descriptor: &str,
+) -> Result<Descriptor<DescriptorPublicKey>, Box<dyn error::Error>> {
+    let (body, checksum) = descriptor
+        .trim()
+        .rsplit_once('#')
+        .ok_or("The descriptor must end with its checksum.")?;
+    let expected = desc_checksum(body)?;
+    if checksum != expected {
+        return Err(format!(
+            "Invalid descriptor checksum {}, expected {}.",
+            checksum, expected
+        )
+        .into());
+    }
+    Ok(Descriptor::from_str(body)?)
+}
+
+/// The descriptors imported into the wallet in this directory, if any. A sealed list needs the key
+/// of the wallet's store.
+pub fn read_imported(
+    dir: &Path,
+    key: Option<&StoreKey>,
+) -> Result<Vec<ImportedDescriptor>, Box<dyn error::Error>> {
+    let path = dir.join(IMPORTED_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    if store_crypto::is_sealed(&path)? {
+        let (_, imported) = FileSealer::open(&path, key, IMPORTED_FILE.as_bytes())?;
+        return Ok(imported);
+    }
+    Ok(serde_json::from_reader(File::open(&path)?)
+        .map_err(|e| format!("Error reading {}: '{}'", path.display(), e))?)
+}
+
+/// Whether descriptors were imported into the wallet in this directory, and listed in clear.
+pub fn imported_in_clear(dir: &Path) -> Result<bool, Box<dyn error::Error>> {
+    let path = dir.join(IMPORTED_FILE);
+    Ok(path.exists() && !store_crypto::is_sealed(&path)?)
+}
+
+/// Replace the list of descriptors imported into the wallet in this directory. It's sealed with
+/// the key of the wallet's store if there's one.
+pub fn write_imported(
+    dir: &Path,
+    imported: &[ImportedDescriptor],
+    key: Option<&StoreKey>,
+) -> Result<(), Box<dyn error::Error>> {
+    // Never leave a partially written list behind, the wallet couldn't be opened anymore.
+    let path = dir.join(IMPORTED_FILE);
+    if let Some(key) = key {
+        return FileSealer::new(key, IMPORTED_FILE.as_bytes())?.write(&path, &imported);
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&serde_json::to_vec_pretty(imported)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
@@ -0,0 +1,415 @@
+//! A local HTTP JSON-RPC server for the wallets, independent of Bitcoin Core's.
+//!
+//! Clients authenticate like they would with Core: using HTTP basic auth with the credentials in
+//! a cookie file, regenerated every time the server starts.
+
+use bdk_chain::bitcoin::{
+    self,
+    base64::{engine::general_purpose::STANDARD as BASE64, Engine},
+    hex::DisplayHex,
+    secp256k1::rand,
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+
+use std::{
+    error, fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    backup,
+    notifications::Subscription,
+    rpc_commands::{
+        finalized, parse_psbt, requested_wallet, wallet_name_from_uri, RpcError,
+        RPC_DESERIALIZATION_ERROR, RPC_INVALID_ADDRESS_OR_KEY, RPC_INVALID_PARAMETER,
+        RPC_MISC_ERROR, RPC_VERIFY_ERROR, RPC_WALLET_ERROR, WALLET_COMMANDS,
+    },
+    rpc_interface::RpcInterface,
+    signer,
+    wallet_manager::WalletManager,
+};
+
+const COOKIE_FILE: &str = "bdk_core_rpc.cookie";
+const COOKIE_USER: &str = "__cookie__";
+// Limits on the requests we accept, to not let a client exhaust our memory or block the server.
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Same as Core's default -maxtxfee.
+const MAX_TX_FEE: bitcoin::Amount = bitcoin::Amount::from_sat(10_000_000);
+
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_INVALID_REQUEST: i64 = -32600;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+/// A parsed HTTP request.
+struct HttpRequest {
+    method: String,
+    uri: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Serve JSON-RPC requests on this local port until `cancel` is triggered. Requests are
+/// processed one at a time. The wallet commands are available along with `sendrawtransaction`
+/// and `sendpsbt` to broadcast transactions through the node, `getsyncstatus` to compare the
+/// wallets' tip with the node's and monitor the queue of notifications, and `backupwallet` and
+/// `restorewallet` to back up a loaded wallet to a file and restore and load one from it.
+/// `signpsbt` signs a PSBT with the private descriptors in `signing_keyfile`, if one is given.
+pub async fn serve(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    subscription: &Subscription,
+    port: u16,
+    signing_keyfile: Option<&Path>,
+    cancel: &CancellationToken,
+) -> Result<(), Box<dyn error::Error>> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let cookie_path = manager.lock().unwrap().data_dir().join(COOKIE_FILE);
+    let expected_auth = write_cookie(&cookie_path)?;
+    println!("Serving JSON-RPC requests on 127.0.0.1:{}.", port);
+
+    loop {
+        let stream = tokio::select! {
+            _ = cancel.cancelled() => break,
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("Error when accepting a JSON-RPC connection: '{}'", e);
+                    continue;
+                }
+            },
+        };
+        let handling = handle_connection(
+            rpc,
+            manager,
+            subscription,
+            stream,
+            &expected_auth,
+            signing_keyfile,
+        );
+        match tokio::time::timeout(REQUEST_TIMEOUT, handling).await {
+            Ok(Err(e)) => eprintln!("Error when handling a JSON-RPC request: '{}'", e),
+            Err(_) => eprintln!("JSON-RPC request timed out."),
+            Ok(Ok(())) => {}
+        }
+    }
+
+    fs::remove_file(cookie_path)?;
+    Ok(())
+}
+
+/// Write a new cookie file, only readable by the user. Returns the expected content of the
+/// Authorization header.
+fn write_cookie(path: &Path) -> Result<String, Box<dyn error::Error>> {
+    let credentials = format!(
+        "{}:{}",
+        COOKIE_USER,
+        rand::random::<[u8; 32]>().to_lower_hex_string()
+    );
+    let _ = fs::remove_file(path);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(credentials.as_bytes())?;
+    Ok(format!("Basic {}", BASE64.encode(credentials)))
+}
+
+// Compare the credentials without leaking how much of them matched through timing.
+fn auth_matches(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn handle_connection(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    subscription: &Subscription,
+    mut stream: TcpStream,
+    expected_auth: &str,
+    signing_keyfile: Option<&Path>,
+) -> Result<(), Box<dyn error::Error>> {
+    let request = match read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => return write_response(&mut stream, 400, &e.to_string()).await,
+    };
+    if !request
+        .authorization
+        .as_deref()
+        .is_some_and(|auth| auth_matches(auth, expected_auth))
+    {
+        return write_response(&mut stream, 401, "").await;
+    }
+    if request.method != "POST" {
+        return write_response(
+            &mut stream,
+            405,
+            "JSON-RPC server handles only POST requests",
+        )
+        .await;
+    }
+
+    let (status, body) = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(Value::Object(req)) => {
+            let id = req.get("id").cloned().unwrap_or(Value::Null);
+            let method = req
+                .get("method")
+                .and_then(|m| m.as_str())
+                .unwrap_or_default();
+            let params = req.get("params").cloned().unwrap_or(Value::Null);
+            let wallet_name = wallet_name_from_uri(&request.uri);
+            match dispatch(
+                rpc,
+                manager,
+                subscription,
+                method,
+                wallet_name.as_deref(),
+                &params,
+                signing_keyfile,
+            )
+            .await
+            {
+                Ok(result) => (200, json!({"result": result, "error": null, "id": id})),
+                Err(e) => {
+                    let status = if e.code == RPC_METHOD_NOT_FOUND {
+                        404
+                    } else {
+                        500
+                    };
+                    (
+                        status,
+                        json!({"result": null, "error": e.to_json(), "id": id}),
+                    )
+                }
+            }
+        }
+        Ok(_) => {
+            let e = RpcError::new(RPC_INVALID_REQUEST, "Invalid Request object");
+            (
+                400,
+                json!({"result": null, "error": e.to_json(), "id": null}),
+            )
+        }
+        Err(e) => {
+            let e = RpcError::new(RPC_PARSE_ERROR, format!("Parse error: {}", e));
+            (
+                500,
+                json!({"result": null, "error": e.to_json(), "id": null}),
+            )
+        }
+    };
+    write_response(&mut stream, status, &body.to_string()).await
+}
+
+async fn dispatch(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    subscription: &Subscription,
+    method: &str,
+    wallet_name: Option<&str>,
+    params: &Value,
+    signing_keyfile: Option<&Path>,
+) -> Result<Value, RpcError> {
+    let tx = match method {
+        "sendrawtransaction" => {
+            let hex = str_param(params, 0, "hexstring")?;
+            bitcoin::consensus::encode::deserialize_hex::<bitcoin::Transaction>(hex).map_err(
+                |e| RpcError::new(RPC_DESERIALIZATION_ERROR, format!("TX decode failed {}", e)),
+            )?
+        }
+        "sendpsbt" => {
+            let mut psbt = parse_psbt(str_param(params, 0, "psbt")?)?;
+            if !signer::finalize_psbt(&mut psbt) {
+                return Err(RpcError::new(RPC_VERIFY_ERROR, "PSBT is not fully signed."));
+            }
+            psbt.extract_tx()
+                .map_err(|e| RpcError::new(RPC_VERIFY_ERROR, e.to_string()))?
+        }
+        "getsyncstatus" => {
+            let metrics = subscription.queue_metrics();
+            let manager = manager.lock().unwrap();
+            let block_id =
+                |b: bdk_chain::BlockId| json!({"height": b.height, "hash": b.hash.to_string()});
+            return Ok(json!({
+                "wallets_tip": block_id(manager.tip()),
+                "node_tip": block_id(manager.node_tip()),
+                "initialblockdownload": manager.is_initial_block_download(),
+                "notification_queue": {
+                    "depth": metrics.depth,
+                    "max_depth": metrics.max_depth,
+                    "processed": metrics.processed,
+                    "backpressure_waits": metrics.backpressure_waits,
+                },
+            }));
+        }
+        "backupwallet" => {
+            let destination = str_param(params, 0, "destination")?;
+            let manager = manager.lock().unwrap();
+            let name = requested_wallet(&manager, wallet_name)?;
+            manager
+                .backup_wallet(&name, Path::new(destination))
+                .map_err(|e| RpcError::new(RPC_WALLET_ERROR, e.to_string()))?;
+            return Ok(Value::Null);
+        }
+        "signpsbt" => {
+            // Never served through the node's RPC server: the keys stay in a local file.
+            let keyfile = signing_keyfile.ok_or_else(|| {
+                RpcError::new(
+                    RPC_WALLET_ERROR,
+                    "No signing key file, see --signing-keyfile.",
+                )
+            })?;
+            let mut psbt = parse_psbt(str_param(params, 0, "psbt")?)?;
+            let descriptors = signer::read_key_file(keyfile)
+                .map_err(|e| RpcError::new(RPC_WALLET_ERROR, e.to_string()))?;
+            for descriptor in descriptors {
+                signer::sign_psbt(&mut psbt, &descriptor)
+                    .map_err(|e| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, e.to_string()))?;
+            }
+            return finalized(psbt);
+        }
+        "restorewallet" => {
+            let name = str_param(params, 0, "wallet_name")?;
+            let backup_file = str_param(params, 1, "backup_file")?;
+            restore_wallet(rpc, manager, name, Path::new(backup_file))
+                .await
+                .map_err(|e| RpcError::new(RPC_WALLET_ERROR, e.to_string()))?;
+            return Ok(json!({"name": name}));
+        }
+        _ => {
+            let command = WALLET_COMMANDS
+                .iter()
+                .find(|c| c.name == method)
+                .ok_or_else(|| RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found"))?;
+            // Like Core's wallet, answer as of the node's current tip.
+            subscription
+                .sync_to_current_tip(rpc)
+                .await
+                .map_err(|e| RpcError::new(RPC_MISC_ERROR, e.to_string()))?;
+            return command.call(rpc, manager, wallet_name, params).await;
+        }
+    };
+
+    match rpc.broadcast_transaction(&tx, MAX_TX_FEE).await {
+        Ok(Ok(())) => Ok(json!(tx.compute_txid().to_string())),
+        Ok(Err(reason)) => Err(RpcError::new(RPC_VERIFY_ERROR, reason)),
+        Err(e) => Err(RpcError::new(RPC_VERIFY_ERROR, e.to_string())),
+    }
+}
+
+// Restore a wallet from this backup file, sync it with the node and load it.
+async fn restore_wallet(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    name: &str,
+    backup_file: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    let key = manager.lock().unwrap().store_key().cloned();
+    let snapshot = backup::read_backup(backup_file, key.as_ref())?;
+    backup::check_backup_tip(rpc, &snapshot).await?;
+    let wallet = manager.lock().unwrap().restore_wallet(name, &snapshot)?;
+    crate::load_wallets(rpc, manager, vec![wallet]).await
+}
+
+// A string parameter of the commands which aren't wallet commands, passed by position or by name.
+fn str_param<'a>(params: &'a Value, pos: usize, name: &str) -> Result<&'a str, RpcError> {
+    let param = match params {
+        Value::Array(params) => params.get(pos),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    };
+    param.and_then(|p| p.as_str()).ok_or_else(|| {
+        RpcError::new(
+            RPC_INVALID_PARAMETER,
+            format!("Missing string parameter {}.", name),
+        )
+    })
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, Box<dyn error::Error>> {
+    let mut reader = BufReader::new(stream);
+    let mut headers_size = 0;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        headers_size += reader.read_line(&mut line).await?;
+        if headers_size > MAX_HEADERS_SIZE {
+            return Err("Headers too large.".into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut request_line = lines.first().ok_or("Empty request.")?.split_whitespace();
+    let method = request_line.next().ok_or("Missing method.")?.to_string();
+    let uri = request_line.next().ok_or("Missing URI.")?.to_string();
+    let mut content_length = 0;
+    let mut authorization = None;
+    for line in &lines[1..] {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(format!("Invalid header '{}'.", line).into());
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse()?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.trim().to_string());
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err("Request body too large.".into());
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(HttpRequest {
+        method,
+        uri,
+        authorization,
+        body,
+    })
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: u16,
+    body: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    if status == 401 {
+        response.push_str("WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n");
+    }
+    response.push_str(&format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
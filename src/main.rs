@@ -6,50 +6,54 @@
 //! its state up to date. This PoC handles confirmed / unconfirmed transaction tracking. Catchup
 //! at startup. Reorgs happening either at runtime or detected at startup. Etc..
 
-use bdk_chain::{
-    self,
-    bitcoin::{self, consensus::Decodable, hashes::Hash},
-    keychain_txout::KeychainTxOutIndex,
-    local_chain::LocalChain,
-    miniscript::{Descriptor, DescriptorPublicKey},
-    BlockId, CheckPoint, ConfirmationBlockTime, IndexedTxGraph, Merge,
-};
-use bdk_file_store::Store as BdkStore;
-use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
-use tokio::task::{self, JoinHandle};
-use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use bdk_chain::{bitcoin, BlockId};
+use tokio_util::sync::CancellationToken;
 
 use std::{
-    env, error,
-    str::FromStr,
+    env,
+    path::PathBuf,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
-#[allow(dead_code)]
+mod backup;
+#[allow(dead_code, unused_parens, clippy::all)]
 mod chain_capnp;
+#[allow(unused_parens, clippy::all)]
 mod common_capnp;
+mod core_rpc;
+mod descriptors;
+#[allow(unused_parens, clippy::all)]
 mod echo_capnp;
+#[allow(unused_parens, clippy::all)]
 mod handler_capnp;
-#[allow(dead_code)]
+mod http_rpc;
+#[allow(dead_code, unused_parens, clippy::all)]
 mod init_capnp;
+#[allow(unused_parens, clippy::all)]
 mod mining_capnp;
-#[allow(dead_code)]
+mod notifications;
+mod persist;
+mod policy;
+#[allow(dead_code, unused_parens, clippy::all)]
 mod proxy_capnp;
+mod rpc_commands;
 mod rpc_interface;
-use chain_capnp::{
-    chain::Client as ChainClient,
-    chain_notifications::{
-        BlockConnectedParams, BlockConnectedResults, BlockDisconnectedParams,
-        BlockDisconnectedResults, ChainStateFlushedParams, ChainStateFlushedResults, DestroyParams,
-        DestroyResults, TransactionAddedToMempoolParams, TransactionAddedToMempoolResults,
-        TransactionRemovedFromMempoolParams, TransactionRemovedFromMempoolResults,
-        UpdatedBlockTipParams, UpdatedBlockTipResults,
-    },
+mod signer;
+mod sqlite_store;
+mod store_crypto;
+mod store_dump;
+mod wallet;
+mod wallet_manager;
+use crate::{
+    notifications::{Subscription, RESUBSCRIBE_INTERVAL},
+    persist::{StoreConfig, PERSIST_INTERVAL},
+    store_crypto::StoreKey,
+    store_dump::DumpFilter,
+    rpc_interface::{NodeChain, RescanStatus, RpcInterface},
+    wallet::BdkWallet,
+    wallet_manager::WalletManager,
 };
-use init_capnp::init::Client as InitClient;
-use proxy_capnp::thread::Client as ThreadClient;
-use crate::rpc_interface::RpcInterface;
 
 // When connected to Bitcoin Core we will sleep for that many seconds before disconnecting
 // and exiting the program. Feel free to change.
@@ -61,665 +65,676 @@ const NETWORK: bitcoin::Network = bitcoin::Network::Regtest;
 // xprv9zLMbgyqu9kLGJEpgsZhMZKYsAk4NUmwX7mnGdj3HFD5WYoNbMrmfefhveVB5ts12SyEuZHTHMTy9qHCMiuMF4fx1vDExza3Nocrctcm48s
 const DESCRIPTOR: &str = "tr(xpub6DKi1CWjjXJdUnKHnu6hihGHRCaYmwVntLhP528eqak4PM8X8uB2DSzBmuTx6kJcUu2dVFLnkpoFudCYNVFGVoa2G5JLwVD4gSDZtncGjpK/*)";
 
-// Persistence for the BDK wallet state
-const BDK_STORE_PATH: &str = "bdk_core_store.dat";
-const BDK_STORE_MAGIC: &[u8] = b"bdk_core_store";
-
-#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
-struct ChangeSet {
-    chain_cs: bdk_chain::local_chain::ChangeSet,
-    graph_cs: bdk_chain::indexed_tx_graph::ChangeSet<
-        ConfirmationBlockTime,
-        bdk_chain::indexer::keychain_txout::ChangeSet,
-    >,
-}
-
-impl Merge for ChangeSet {
-    fn merge(&mut self, other: Self) {
-        Merge::merge(&mut self.chain_cs, other.chain_cs);
-        Merge::merge(&mut self.graph_cs, other.graph_cs);
+// BDK wallets are up and synced with Core. Inform users and register for notifs.
+async fn wallet_startup_complete(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+) -> Result<Subscription, capnp::Error> {
+    println!("BDK Core is synced with bitcoin-node.");
+    let names = manager.lock().unwrap().names();
+    for name in names {
+        let outpoints = manager.lock().unwrap().wallet(&name).unwrap().list_unspent();
+
+        if !outpoints.is_empty() {
+            println!("Scanning {} wallet outpoints in mempool", outpoints.len());
+            let coins = rpc.find_coins_request(outpoints).await?;
+
+            // Process found coins
+            if !coins.is_empty() {
+                println!("Found {} coins in mempool/UTXO set", coins.len());
+                for coin in &coins {
+                    let coinbase = if coin.is_coinbase { " (coinbase)" } else { "" };
+                    println!("  {}:{} - {} satoshis at height {}{}",
+                            coin.outpoint.txid, coin.outpoint.vout, coin.txout.value.to_sat(),
+                            coin.height, coinbase);
+                }
+            }
+        }
     }
+    rpc.show_progress("BDK Core startup", 100, true).await;
 
-    fn is_empty(&self) -> bool {
-        self.chain_cs.is_empty() && self.graph_cs.is_empty()
-    }
+    let subscription = Subscription::subscribe(rpc, manager).await?;
+    
+    // Scan the mempool for wallet-related coins
+    // println!("Scanning mempool for wallet-related coins...");
+    // rpc.find_coins_request().await;
+    Ok(subscription)
 }
 
-/// The wallet state. Maintains the BDK transaction graph and chain state.
-struct BdkWallet {
-    chain: LocalChain,
-    tx_graph: IndexedTxGraph<ConfirmationBlockTime, KeychainTxOutIndex<()>>,
-    store: BdkStore<ChangeSet>,
+// If a reorg happened while we were not listening to notifications we need to process it
+// "manually". Note that BDK does not maintain a state for the tip, only a monotone graph
+// of transaction from which it resolves confirmed one against a linked list of headers
+// and a set of anchors at query time. So here "processing the reorg" barely means we need
+// to disconnect the old tip from the headers linked list (the "local chain"). The blocks from
+// the new chain are then processed by the catch-up to make sure we didn't miss any transaction.
+async fn wallet_handle_startup_reorg(
+    chain: &NodeChain,
+    wallet: &mut BdkWallet,
+    node_tip: &BlockId,
+    wallet_tip: &BlockId,
+) -> Result<(), capnp::Error> {
+    // Try to find the common ancestor between the node and the wallet and disconnect the blocks
+    // above it. The chain is then re-processed from the block following it. If the common
+    // ancestor couldn't be found, use the genesis block.
+    let genesis = BlockId {
+        height: 0,
+        hash: wallet.genesis_hash(),
+    };
+    let common_ancestor = chain
+        .common_ancestor(&node_tip.hash, &wallet_tip.hash)
+        .await?
+        .unwrap_or(genesis);
+
+    println!("Disconnecting the chain above {:?}", common_ancestor);
+    if let Err(e) = wallet.disconnect_above(common_ancestor) {
+        eprintln!(
+            "Error when disconnecting the chain above the common ancestor: '{}'. Restarting from genesis.",
+            e
+        );
+        wallet
+            .disconnect_above(genesis)
+            .expect("The genesis block is always part of the wallet's chain");
+    }
+    Ok(())
 }
 
-impl BdkWallet {
-    /// Create a fresh wallet or open it if a store is available.
-    pub fn new() -> Result<Self, Box<dyn error::Error>> {
-        let (mut chain, _) =
-            LocalChain::from_genesis_hash(bitcoin::constants::genesis_block(NETWORK).block_hash());
-        let mut index = KeychainTxOutIndex::default();
-        let desc: Descriptor<DescriptorPublicKey> = Descriptor::from_str(DESCRIPTOR)
-            .expect("DESCRIPTOR constant must be a valid descriptor.");
-        index
-            .insert_descriptor((), desc)
-            .expect("First to be inserted");
-        let mut tx_graph = IndexedTxGraph::new(index);
-        let mut store: BdkStore<ChangeSet> =
-            BdkStore::open_or_create_new(BDK_STORE_MAGIC, BDK_STORE_PATH)?;
-        for cs in store.iter_changesets() {
-            let cs = cs?;
-            chain.apply_changeset(&cs.chain_cs)?;
-            tx_graph.apply_changeset(cs.graph_cs);
+// Sync these wallets with Core's chain up to `node_tip`. Wallets may be at different tips: each
+// block is only fetched once and applied to all the wallets which don't have it yet.
+async fn wallets_catch_up(
+    chain: &NodeChain,
+    wallets: &mut [BdkWallet],
+    node_tip: &BlockId,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for wallet in wallets.iter_mut() {
+        let wallet_tip = wallet.tip();
+        if wallet_tip == *node_tip {
+            continue;
         }
-        Ok(Self {
-            chain,
-            tx_graph,
-            store,
-        })
-    }
 
-    pub fn genesis_hash(&self) -> bitcoin::BlockHash {
-        self.chain.genesis_hash()
-    }
-
-    pub fn tip(&self) -> BlockId {
-        self.chain.tip().block_id()
-    }
-    pub fn list_unspent(&self) -> Vec<bitcoin::OutPoint> {
-        // self.tx_graph.index.outpoints().into_iter().map(|(_, op)| *op).collect()
-        let outpoints = self
-            .tx_graph
-            .index
-            .outpoints()
-            .into_iter()
-            .map(|((_, _), op)| ((), *op));
-        println!("Found {} outpoints in wallet", outpoints.clone().count());
-        outpoints.map(|(_, op)| op).collect()
-        // let graph = self.tx_graph.graph();
-        // let balance = graph.balance(&self.chain, self.tip(), outpoints.clone(), |_, _| true);
-        // let utxos = graph.filter_chain_unspents(&self.chain, self.tip(), outpoints);
-        
-    }
-    /// Apply the effects of a block on the wallet. Persist the changes to disk.
-    pub fn apply_block(
-        &mut self,
-        block: &bitcoin::Block,
-        height: i32,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let h: u32 = height.try_into().expect("Must never be negative");
-        let graph_cs = self.tx_graph.apply_block_relevant(block, h);
-        let chain_cs = self
-            .chain
-            .apply_update(CheckPoint::from_header(&block.header, h))?;
-        let cs = ChangeSet { graph_cs, chain_cs };
-        self.store.append_changeset(&cs)?;
-        if !cs.graph_cs.is_empty() {
-            println!("Graph change set not empty. Here is the new state of the wallet.");
-            self.print_info()?;
+        if wallet_tip.height >= node_tip.height {
+            println!("The tip on bitcoin-node was reorged or moved backward.");
+            wallet_handle_startup_reorg(chain, wallet, node_tip, &wallet_tip).await?;
+            continue;
         }
-        Ok(())
-    }
 
-    /// Apply the effects of a transaction on the wallet. Persist the changes to disk.
-    pub fn apply_tx(&mut self, tx: bitcoin::Transaction) -> Result<(), Box<dyn error::Error>> {
-        let graph_cs = self
-            .tx_graph
-            .batch_insert_relevant_unconfirmed([(tx, /*TODO*/ 0)]);
-        let cs = ChangeSet {
-            graph_cs,
-            ..Default::default()
-        };
-        self.store.append_changeset(&cs)?;
-        if !cs.graph_cs.is_empty() {
-            println!("Graph change set not empty. Here is the new state of the wallet.");
-            self.print_info()?;
+        println!(
+            "Height on bitcoin-node moved forward. Making sure wallet tip is still in best chain."
+        );
+        if !chain
+            .is_in_best_chain(&node_tip.hash, &wallet_tip.hash)
+            .await?
+        {
+            println!("Wallet tip is not in best chain anymore. Proceeding to process reorg.");
+            wallet_handle_startup_reorg(chain, wallet, node_tip, &wallet_tip).await?;
         }
-        Ok(())
     }
 
-    /// Mark a block as disconnected. Persist to disk.
-    pub fn disconnect(&mut self, block_id: BlockId) -> Result<(), Box<dyn error::Error>> {
-        // FIXME: it should never be necessary.
-        let chain_cs = if block_id.height > 0 {
-            self.chain
-                .disconnect_from(block_id)
-                .expect("Just checked it's not the genesis")
-        } else {
-            let mut cs = bdk_chain::local_chain::ChangeSet::default();
-            for cp in self.chain.iter_checkpoints() {
-                if cp.height() != 0 {
-                    cs.blocks.insert(cp.height(), None);
-                }
-            }
-            self.chain = LocalChain::from_genesis_hash(
-                bitcoin::constants::genesis_block(NETWORK).block_hash(),
-            )
-            .0;
-            cs
-        };
-        self.store.append_changeset(&ChangeSet {
-            chain_cs,
-            ..Default::default()
-        })?;
-        Ok(())
+    let Some(start_height) = wallets.iter().map(|w| w.tip().height + 1).min() else {
+        return Ok(());
+    };
+    if start_height > node_tip.height {
+        return Ok(());
     }
 
-    /// The first address that we don't know has been used onchain.
-    pub fn next_unused_address(&mut self) -> Result<bitcoin::Address, Box<dyn error::Error>> {
-        let ((_, script), cs) = self
-            .tx_graph
-            .index
-            .next_unused_spk(())
-            .expect("We assume a ranged descriptor is in use");
-        let graph_cs = bdk_chain::indexed_tx_graph::ChangeSet {
-            indexer: cs,
-            ..Default::default()
-        };
-        self.store.append_changeset(&ChangeSet {
-            graph_cs,
-            ..Default::default()
-        })?;
-        Ok(bitcoin::Address::from_script(&script, &NETWORK)
-            .expect("We assume the descriptor type used has defined addresses"))
+    println!("All good. Now making sure it has all the blocks for us to sync.");
+    if !chain.has_blocks(&node_tip.hash, start_height.try_into()?).await? {
+        return Err("bitcoin-node is missing blocks to sync the BDK wallet.".into());
     }
 
-    /// The first address to have never been revealed by this wallet.
-    pub fn next_address(&mut self) -> Result<bitcoin::Address, Box<dyn error::Error>> {
-        let ((_, script), cs) = self
-            .tx_graph
-            .index
-            .reveal_next_spk(())
-            .expect("We assume a ranged descriptor is in use");
-        let graph_cs = bdk_chain::indexed_tx_graph::ChangeSet {
-            indexer: cs,
-            ..Default::default()
-        };
-        self.store.append_changeset(&ChangeSet {
-            graph_cs,
-            ..Default::default()
-        })?;
-        Ok(bitcoin::Address::from_script(&script, &NETWORK)
-            .expect("We assume the descriptor type used has defined addresses"))
+    println!("It does. Now proceeding to sync the BDK wallets.");
+    for h in start_height..=node_tip.height {
+        let block = chain.get_block(&node_tip.hash, h.try_into()?).await?;
+        for wallet in wallets.iter_mut().filter(|w| w.tip().height < h) {
+            wallet.apply_block(&block, h.try_into()?)?;
+        }
+    }
+    println!("Done syncing missing blocks.");
+    for wallet in wallets.iter_mut() {
+        wallet.persist()?;
     }
 
-    /// Print the wallet state (addresses, coins, transactions, balance, ..).
-    pub fn print_info(&mut self) -> Result<(), Box<dyn error::Error>> {
-        let next_unused_addr = self.next_unused_address()?;
-        let next_addr = self.next_address()?;
-        let outpoints = self
-            .tx_graph
-            .index
-            .outpoints()
-            .into_iter()
-            .map(|((_, _), op)| ((), *op));
-
-        let graph = self.tx_graph.graph();
-        let balance = graph.balance(&self.chain, self.tip(), outpoints.clone(), |_, _| true);
-        let utxos = graph.filter_chain_unspents(&self.chain, self.tip(), outpoints);
-        let txs = graph.full_txs().map(|tx| {
-            (
-                tx.txid,
-                graph.get_chain_position(&self.chain, self.tip(), tx.txid),
-            )
-        });
-
-        println!("Wallet info:");
-        println!("      Next unused address: {}.", next_unused_addr);
-        println!("      Next unrevealed address: {}.", next_addr);
-        println!(
-            "      Balance (confirmed + unconfirmed): {}.",
-            balance.trusted_spendable()
-        );
-        print!("      Utxos: ");
-        for (_, utxo) in utxos {
-            print!("{} ({}), ", utxo.outpoint, utxo.txout.value);
-        }
-        print!("\n      Transactions: ");
-        for (txid, pos) in txs {
-            print!("{} (chain pos: {:?}), ", txid, pos);
-        }
-        println!();
+    Ok(())
+}
 
-        Ok(())
+// Sync these wallets with Core and start serving them notifications. Blocks may be connected while
+// we are catching up, so keep going until the wallets are synced with the latest tip notified to
+// the manager. No notification can be processed between the last check and adding the wallets to
+// the manager as we don't yield in between.
+async fn load_wallets(
+    chain: &NodeChain,
+    manager: &Arc<Mutex<WalletManager>>,
+    mut wallets: Vec<BdkWallet>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let target_tip = manager.lock().unwrap().tip();
+        wallets_catch_up(chain, &mut wallets, &target_tip).await?;
+
+        let mut manager = manager.lock().unwrap();
+        if manager.tip() == target_tip {
+            for wallet in wallets {
+                manager.insert(wallet)?;
+            }
+            return Ok(());
+        }
     }
 }
 
-/// IPC interface to Bitcoin Core.
-// struct RpcInterface {
-//     rpc_handle: JoinHandle<Result<(), capnp::Error>>,
-//     disconnector: capnp_rpc::Disconnector<twoparty::VatId>,
-//     thread: ThreadClient,
-//     chain_interface: ChainClient,
-// }
-
-// // In the implementation of methods of the IPC interface i used `unwraps()` for
-// // brievety but if reused you should probably have proper error handling.
-// impl RpcInterface {
-//     /// Create an IPC interface by performing the handshake with Bitcoin Core on the provided stream.
-//     pub async fn new(stream: tokio@::net::UnixStream) -> Result<Self, Box<dyn std::error::Error>> {
-//         let (reader, writer) = stream.into_split();
-//         let network = Box::new(twoparty::VatNetwork::new(
-//             reader.compat(),
-//             writer.compat_write(),
-//             rpc_twoparty_capnp::Side::Client,
-//             Default::default(),
-//         ));
-
-//         let mut rpc = RpcSystem::new(network, None);
-//         let init_interface: InitClient = rpc.bootstrap(rpc_twoparty_capnp::Side::Server);
-//         let disconnector = rpc.get_disconnector();
-//         let rpc_handle = task::spawn_local(rpc);
-
-//         let mk_init_req = init_interface.construct_request();
-//         let response = mk_init_req.send().promise.await?;
-//         let thread_map = response.get()?.get_thread_map()?;
-
-//         let mk_thread_req = thread_map.make_thread_request();
-//         let response = mk_thread_req.send().promise.await?;
-//         let thread = response.get()?.get_result()?;
-
-//         let mut mk_chain_req = init_interface.make_chain_request();
-//         mk_chain_req.get().get_context()?.set_thread(thread.clone());
-//         let response = mk_chain_req.send().promise.await?;
-//         let chain_interface = response.get()?.get_result()?;
-
-//         // Send an init message to Core to exercise the interface.
-//         let mut mk_mess_req = chain_interface.init_message_request();
-//         mk_mess_req.get().get_context()?.set_thread(thread.clone());
-//         mk_mess_req
-//             .get()
-//             .set_message("Oxydation of the Bitcoin Core wallet in progress..");
-//         let _ = mk_mess_req.send().promise.await?;
-
-//         Ok(Self {
-//             rpc_handle,
-//             thread,
-//             chain_interface,
-//             disconnector,
-//         })
-//     }
-
-//     pub async fn get_tip(&self) -> BlockId {
-//         let mut height_req = self.chain_interface.get_height_request();
-//         height_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         let response = height_req.send().promise.await.unwrap();
-//         let height_i32 = response.get().unwrap().get_result();
-//         let height = height_i32.try_into().expect("Height is never negative.");
-
-//         let mut hash_req = self.chain_interface.get_block_hash_request();
-//         hash_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         hash_req.get().set_height(height_i32);
-//         let response = hash_req.send().promise.await.unwrap();
-//         let hash = bitcoin::BlockHash::from_slice(response.get().unwrap().get_result().unwrap())
-//             .expect("Core must be serving valid hashes.");
-
-//         BlockId { height, hash }
-//     }
-
-//     // NOTE: not entirely correct, but good enough for the purpose of this PoC
-//     pub async fn is_in_best_chain(
-//         &self,
-//         node_tip_hash: &bitcoin::BlockHash,
-//         ancestor: &bitcoin::BlockHash,
-//     ) -> bool {
-//         let mut find_req = self.chain_interface.find_ancestor_by_hash_request();
-//         find_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         find_req.get().set_block_hash(node_tip_hash.as_ref());
-//         find_req.get().set_ancestor_hash(ancestor.as_ref());
-//         let response = find_req.send().promise.await.unwrap();
-//         response.get().unwrap().get_result()
-//     }
-
-//     pub async fn has_blocks(&self, node_tip_hash: &bitcoin::BlockHash, start_height: i32) -> bool {
-//         let mut has_blocks_req = self.chain_interface.has_blocks_request();
-//         has_blocks_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         has_blocks_req.get().set_block_hash(node_tip_hash.as_ref());
-//         has_blocks_req.get().set_min_height(start_height);
-//         let response = has_blocks_req.send().promise.await.unwrap();
-//         response.get().unwrap().get_result()
-//     }
-
-//     pub async fn get_block(
-//         &self,
-//         node_tip_hash: &bitcoin::BlockHash,
-//         height: i32,
-//     ) -> bitcoin::Block {
-//         let mut find_req = self.chain_interface.find_ancestor_by_height_request();
-//         find_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         find_req.get().set_block_hash(node_tip_hash.as_ref());
-//         find_req.get().set_ancestor_height(height);
-//         find_req.get().get_ancestor().unwrap().set_want_data(true);
-//         let response = find_req.send().promise.await.unwrap();
-//         bitcoin::Block::consensus_decode(
-//             &mut response
-//                 .get()
-//                 .unwrap()
-//                 .get_ancestor()
-//                 .unwrap()
-//                 .get_data()
-//                 .unwrap(),
-//         )
-//         .expect("Core must provide valid blocks")
-//     }
-
-//     pub async fn common_ancestor(
-//         &self,
-//         node_tip_hash: &bitcoin::BlockHash,
-//         wallet_tip_hash: &bitcoin::BlockHash,
-//     ) -> Option<BlockId> {
-//         let mut find_req = self.chain_interface.find_common_ancestor_request();
-//         find_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         find_req.get().set_block_hash1(node_tip_hash.as_ref());
-//         find_req.get().set_block_hash1(wallet_tip_hash.as_ref());
-//         find_req.get().get_ancestor().unwrap().set_want_height(true);
-//         find_req.get().get_ancestor().unwrap().set_want_hash(true);
-//         let response = find_req.send().promise.await.unwrap();
-//         let response = response.get().unwrap();
-//         let ancestor = response.get_ancestor().unwrap();
-//         if !ancestor.get_found() {
-//             return None;
-//         }
-//         let height = ancestor
-//             .get_height()
-//             .try_into()
-//             .expect("Can't be negative.");
-//         let hash = bitcoin::BlockHash::from_slice(ancestor.get_hash().unwrap())
-//             .expect("Core must provide valid blocks");
-//         Some(BlockId { height, hash })
-//     }
-
-//     pub async fn show_progress(&self, title: &str, progress: i32, resume_possible: bool) {
-//         let mut mk_mess_req = self.chain_interface.show_progress_request();
-//         mk_mess_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         mk_mess_req.get().set_title(title);
-//         mk_mess_req.get().set_progress(progress);
-//         mk_mess_req.get().set_resume_possible(resume_possible);
-//         let _ = mk_mess_req.send().promise.await.unwrap();
-//     }
-
-//     pub async fn register_notifications(&self, wallet: Arc<Mutex<BdkWallet>>) {
-//         let notif_handler = capnp_rpc::new_client(wallet);
-//         let mut register_req = self.chain_interface.handle_notifications_request();
-//         register_req
-//             .get()
-//             .get_context()
-//             .unwrap()
-//             .set_thread(self.thread.clone());
-//         register_req.get().set_notifications(notif_handler);
-//         let _ = register_req.send().promise.await.unwrap();
-//     }
-
-//     pub async fn disconnect(self) -> Result<(), capnp::Error> {
-//         self.disconnector.await.unwrap();
-//         self.rpc_handle.await.unwrap()
-//     }
-// }
-
-// Implementation of the subscription to validation events from Bitcoin Core. Main logic post startup.
-impl chain_capnp::chain_notifications::Server for Arc<Mutex<BdkWallet>> {
-    fn destroy(
-        &mut self,
-        _: DestroyParams,
-        _: DestroyResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        unimplemented!("Destroy notification") // TODO: do we ever receive this? Looks like not.
-    }
+// Start the BDK wallets, sync their state with Core's and subscribe to its notifications.
+async fn wallet_startup(
+    rpc: &RpcInterface,
+    options: &Options,
+) -> Result<(Arc<Mutex<WalletManager>>, Subscription), Box<dyn std::error::Error>> {
+    rpc.show_progress("BDK Core startup", 1, false).await;
 
-    fn transaction_added_to_mempool(
-        &mut self,
-        params: TransactionAddedToMempoolParams,
-        _: TransactionAddedToMempoolResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        let tx = bitcoin::Transaction::consensus_decode(&mut pry!(pry!(params.get()).get_tx()))
-            .expect("Core must provide valid transactions.");
-        let txid = tx.compute_txid();
-        println!("New mempool transaction {}.", txid);
-        if let Err(e) = self.lock().unwrap().apply_tx(tx) {
-            eprintln!("Error applying tx {} to wallet: {}", txid, e);
+    let manager = WalletManager::new(
+        rpc.get_tip().await?,
+        options.data_dir.clone(),
+        options.store.clone(),
+    );
+    let mut wallets = Vec::new();
+    for (name, descriptor) in &options.create_wallets {
+        println!("Creating wallet '{}'.", name);
+        wallets.push(manager.create_wallet(name, descriptor)?);
+    }
+    for (name, path) in &options.restore_wallets {
+        println!("Restoring wallet '{}' from {}.", name, path.display());
+        let snapshot = backup::read_backup(path, options.store.key.as_ref())?;
+        backup::check_backup_tip(rpc, &snapshot).await?;
+        wallets.push(manager.restore_wallet(name, &snapshot)?);
+    }
+    for name in &options.wallets {
+        wallets.push(manager.open_wallet(name)?);
+    }
+    // Without any wallet specified, use the default one.
+    if options.create_wallets.is_empty()
+        && options.restore_wallets.is_empty()
+        && options.wallets.is_empty()
+    {
+        wallets.push(manager.open_wallet("")?);
+    }
+    if let Some(lookahead) = options.lookahead {
+        for wallet in wallets.iter_mut() {
+            for keychain in wallet.keychains() {
+                wallet.set_lookahead(keychain, lookahead)?;
+            }
         }
-        ::capnp::capability::Promise::ok(())
     }
 
-    fn transaction_removed_from_mempool(
-        &mut self,
-        _: TransactionRemovedFromMempoolParams,
-        _: TransactionRemovedFromMempoolResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        // BDK's transaction graph is monotone so we can't remove the tx here (see https://docs.rs/bdk_chain/latest/bdk_chain/tx_graph/index.html).
-        ::capnp::capability::Promise::ok(())
-    }
+    let manager = Arc::new(Mutex::new(manager));
+    load_wallets(rpc, &manager, wallets).await?;
+    let subscription = wallet_startup_complete(rpc, &manager).await?;
+
+    Ok((manager, subscription))
+}
 
-    fn block_connected(
-        &mut self,
-        params: BlockConnectedParams,
-        _: BlockConnectedResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        // Assume no background chainstate for the purpose of this PoC.
-        let info = pry!(pry!(params.get()).get_block());
-        let height = info.get_height();
-        let block = bitcoin::Block::consensus_decode(&mut pry!(info.get_data()))
-            .expect("Core must provide valid transactions.");
-        println!("New connected block {}.", block.block_hash());
-        if let Err(e) = self.lock().unwrap().apply_block(&block, height) {
-            eprintln!(
-                "Error when applying connected block {}: '{}'",
-                block.block_hash(),
-                e
+// Rescan the wallets' chain whenever the set of watched script pubkeys was extended past what was
+// used to process the blocks, or descriptors were imported, until cancelled.
+async fn pending_rescans(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    cancel: &CancellationToken,
+) {
+    let notifier = manager.lock().unwrap().rescan_notifier();
+    loop {
+        let pending: Vec<_> = {
+            let mut manager = manager.lock().unwrap();
+            manager
+                .names()
+                .into_iter()
+                .filter_map(|name| {
+                    let range = manager.wallet_mut(&name)?.take_pending_rescan()?;
+                    Some((name, range))
+                })
+                .collect()
+        };
+        if pending.is_empty() {
+            tokio::select! {
+                _ = notifier.notified() => continue,
+                _ = cancel.cancelled() => return,
+            }
+        }
+        for (name, (from_height, to_height)) in pending {
+            println!(
+                "Rescanning blocks {} to {} of wallet '{}' for its new script pubkeys.",
+                from_height, to_height, name
             );
+            match rpc
+                .rescan(manager, &name, from_height, to_height, cancel)
+                .await
+            {
+                Ok(RescanStatus::Cancelled { .. }) => return,
+                Ok(RescanStatus::Completed) => {
+                    println!("Rescan completed.");
+                    let mut manager = manager.lock().unwrap();
+                    if let Some(wallet) = manager.wallet_mut(&name) {
+                        if let Err(e) = wallet.rescan_completed(from_height) {
+                            eprintln!("Error when recording the completed rescan: '{}'", e);
+                        }
+                    }
+                }
+                Ok(status) => println!("Rescan stopped: {:?}.", status),
+                Err(e) => eprintln!("Error when rescanning: '{}'", e),
+            }
         }
-        ::capnp::capability::Promise::ok(())
     }
+}
 
-    fn block_disconnected(
-        &mut self,
-        params: BlockDisconnectedParams,
-        _: BlockDisconnectedResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        // Here again, BDK's tx graph is monotone so we don't actually have to remove transactions.
-        let info = pry!(pry!(params.get()).get_block());
-        let height: u32 = info.get_height().try_into().expect("Can't be negative.");
-        let hash = bitcoin::BlockHash::from_slice(pry!(info.get_hash()))
-            .expect("Core must provide valid block hashes");
-        self.lock()
-            .unwrap()
-            .disconnect(BlockId { height, hash })
-            .expect("Core will never disconnect the genesis block.");
-        println!("Disconnected block {}", hash);
-        ::capnp::capability::Promise::ok(())
+async fn rpc_main(
+    stream: tokio::net::UnixStream,
+    options: Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rpc = RpcInterface::new(stream).await?;
+    let (manager, subscription) = wallet_startup(&rpc, &options).await?;
+
+    let names = manager.lock().unwrap().names();
+    for name in names {
+        manager.lock().unwrap().wallet(&name).unwrap().print_info()?;
     }
+    // Our commands are served by bitcoind until the handlers are dropped.
+    let rpc_handlers = core_rpc::register_commands(&rpc, &manager, &subscription).await?;
+
+    println!(
+        "\nWaiting {} seconds before disconnecting.",
+        SLEEP_BEFORE_DISCONNECT_SECS
+    );
+    // Rescans and the JSON-RPC server run alongside notifications. They are stopped if still
+    // running by the time we disconnect.
+    let shutdown = CancellationToken::new();
+    let rescan = async {
+        if let Some((from_height, to_height)) = options.rescan_range {
+            let names = manager.lock().unwrap().names();
+            for name in names {
+                println!("Rescanning blocks {} to {} of wallet '{}'.", from_height, to_height, name);
+                match rpc
+                    .rescan(&manager, &name, from_height, to_height, &shutdown)
+                    .await
+                {
+                    Ok(status) => println!("Rescan stopped: {:?}.", status),
+                    Err(e) => eprintln!("Error when rescanning: '{}'", e),
+                }
+            }
+        }
+    };
+    let pending_rescans = pending_rescans(&rpc, &manager, &shutdown);
+    let json_rpc = async {
+        if let Some(port) = options.rpc_port {
+            let keyfile = options.signing_keyfile.as_deref();
+            if let Err(e) =
+                http_rpc::serve(&rpc, &manager, &subscription, port, keyfile, &shutdown).await
+            {
+                eprintln!("Error when serving JSON-RPC requests: '{}'", e);
+            }
+        }
+    };
+    // Changes are batched in memory, write them periodically while following the tip.
+    let persist = async {
+        let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => manager.lock().unwrap().persist(),
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    };
+    // Core releases our registration when it stops notifying us, register again until we
+    // disconnect. The blocks missed in the meantime are caught up with.
+    let resubscribe = async {
+        let mut interval = tokio::time::interval(RESUBSCRIBE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => return,
+            }
+            if subscription.is_active() {
+                continue;
+            }
+            println!("Our registration for notifications was released, registering again.");
+            if let Err(e) = subscription.resume(&rpc).await {
+                eprintln!("Error when registering again for notifications: '{}'", e);
+            }
+        }
+    };
+    let sleep = async {
+        tokio::time::sleep(Duration::from_secs(SLEEP_BEFORE_DISCONNECT_SECS)).await;
+        shutdown.cancel();
+    };
+    tokio::join!(
+        rescan,
+        pending_rescans,
+        json_rpc,
+        persist,
+        resubscribe,
+        sleep
+    );
+    println!("Disconnecting.");
+    subscription.disconnect(&rpc).await?;
+    drop(rpc_handlers);
+    rpc.disconnect().await?;
 
-    fn updated_block_tip(
-        &mut self,
-        _: UpdatedBlockTipParams,
-        _: UpdatedBlockTipResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        println!("Block tip updated, but i don't know to what!");
-        ::capnp::capability::Promise::ok(())
+    let names = manager.lock().unwrap().names();
+    for name in names {
+        let wallet = manager.lock().unwrap().unload(&name).expect("Just listed.");
+        wallet.print_info()?;
     }
 
-    fn chain_state_flushed(
-        &mut self,
-        _: ChainStateFlushedParams,
-        _: ChainStateFlushedResults,
-    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
-        println!("Chainstate flushed.");
-        ::capnp::capability::Promise::ok(())
+    Ok(())
+}
+
+/// Optional command line parameters.
+#[derive(Debug, Default)]
+struct Options {
+    /// Rescan these heights (inclusive) once synced.
+    rescan_range: Option<(u32, u32)>,
+    /// Set the lookahead of the wallets' keychains.
+    lookahead: Option<u32>,
+    /// Names of the existing wallets to load.
+    wallets: Vec<String>,
+    /// Names and descriptors of the wallets to create and load.
+    create_wallets: Vec<(String, String)>,
+    /// Names and backup files of the wallets to restore and load.
+    restore_wallets: Vec<(String, PathBuf)>,
+    /// Serve JSON-RPC requests on this local port.
+    rpc_port: Option<u16>,
+    /// Sign PSBTs served over JSON-RPC with the private descriptors in this file.
+    signing_keyfile: Option<PathBuf>,
+    /// Where to store the wallets. The working directory by default.
+    data_dir: PathBuf,
+    /// How to persist the wallets.
+    store: StoreConfig,
+    /// The key to encrypt the file stores with instead, when rotating it.
+    new_store_key: Option<StoreKey>,
+    /// Decrypt the file stores when rotating their key.
+    decrypt: bool,
+    /// What to dump from the stores.
+    dump_filter: DumpFilter,
+    /// The descriptor to import, with its checksum.
+    descriptor: Option<String>,
+    /// The height from which to scan for the imported descriptor.
+    birthday: Option<u32>,
+    /// The last derivation index to watch for the imported descriptor.
+    range_end: Option<u32>,
+    /// The policy to compile into a descriptor.
+    policy: Option<String>,
+    /// Compile the policy into a taproot descriptor rather than a P2WSH one.
+    taproot: bool,
+    /// Where to write the backup of a wallet.
+    destination: Option<PathBuf>,
+}
+
+// The store key derived from the password in this environment variable, which unlike the command
+// line isn't visible to other users.
+fn password_from_env(var: &str) -> Result<StoreKey, Box<dyn std::error::Error>> {
+    match env::var(var) {
+        Ok(password) if !password.is_empty() => Ok(StoreKey::Password(password)),
+        Ok(_) => Err(format!("The password in ${} is empty.", var).into()),
+        Err(e) => Err(format!("Error reading the password from ${}: '{}'", var, e).into()),
     }
 }
 
-// BDK wallet is up and synced with Core. Inform users and register for notifs.
-async fn wallet_startup_complete(rpc: &RpcInterface, wallet: BdkWallet) -> Arc<Mutex<BdkWallet>> {
-    println!("BDK Core is synced with bitcoin-node.");
-    let outpoints = wallet.list_unspent();
-
-    if !outpoints.is_empty() {
-    println!("Scanning {} wallet outpoints in mempool", outpoints.len());
-    let coins = rpc.find_coins_request(outpoints).await;
-
-    // Process found coins
-    if !coins.is_empty() {
-        println!("Found {} coins in mempool/UTXO set", coins.len());
-        for (outpoint, txout) in &coins {
-            println!("  {}:{} - {} satoshis", 
-                    outpoint.txid, outpoint.vout, txout.value);
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut options = Self::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--rescan" => options.rescan_range = Some((value()?.parse()?, value()?.parse()?)),
+                "--lookahead" => options.lookahead = Some(value()?.parse()?),
+                "--wallet" => options.wallets.push(value()?),
+                "--create-wallet" => options.create_wallets.push((value()?, value()?)),
+                "--restore-wallet" => options.restore_wallets.push((value()?, value()?.into())),
+                "--rpcport" => options.rpc_port = Some(value()?.parse()?),
+                "--signing-keyfile" => options.signing_keyfile = Some(value()?.into()),
+                "--datadir" => options.data_dir = value()?.into(),
+                "--store" => options.store.backend = value()?.parse()?,
+                "--store-keyfile" => options.store.key = Some(StoreKey::KeyFile(value()?.into())),
+                "--store-password-env" => options.store.key = Some(password_from_env(&value()?)?),
+                "--new-store-keyfile" => {
+                    options.new_store_key = Some(StoreKey::KeyFile(value()?.into()))
+                }
+                "--new-store-password-env" => {
+                    options.new_store_key = Some(password_from_env(&value()?)?)
+                }
+                "--decrypt" => options.decrypt = true,
+                "--txid" => options.dump_filter.txid = Some(value()?.parse()?),
+                "--height" => options.dump_filter.height = Some(value()?.parse()?),
+                "--descriptor" => options.descriptor = Some(value()?),
+                "--birthday" => options.birthday = Some(value()?.parse()?),
+                "--range" => options.range_end = Some(value()?.parse()?),
+                "--policy" => options.policy = Some(value()?),
+                "--taproot" => options.taproot = true,
+                "--destination" => options.destination = Some(value()?.into()),
+                _ => return Err(format!("Unknown parameter {}", arg).into()),
+            }
         }
+        Ok(options)
     }
-    }
-    rpc.show_progress("BDK Core startup", 100, true).await;
-
-    let wallet = Arc::new(Mutex::new(wallet));
-    rpc.register_notifications(wallet.clone()).await;
-    
-    // Scan the mempool for wallet-related coins
-    // println!("Scanning mempool for wallet-related coins...");
-    // rpc.find_coins_request().await;
-    
-    wallet
 }
 
-// If a reorg happened while we were not listening to notifications we need to process it
-// "manually". Note that BDK does not maintain a state for the tip, only a monotone graph
-// of transaction from which it resolves confirmed one against a linked list of headers
-// and a set of anchors at query time. So here "processing the reorg" barely means we need
-// to disconnect the old tip from the headers linked list (the "local chain") and process
-// the blocks from the new chain to make sure we didn't miss any transaction.
-async fn wallet_handle_startup_reorg(
-    rpc: &RpcInterface,
-    mut wallet: BdkWallet,
-    node_tip: &BlockId,
-    wallet_tip: &BlockId,
-) -> Arc<Mutex<BdkWallet>> {
-    // Try to find the common ancestor between the node and the wallet, disconnect and
-    // re-process the chain from there. If the common ancestor couldn't be found, use the
-    // genesis block.
-    // FIXME: we should not disconnect the common ancestor but start from the next one. Doesn't
-    // matter for this PoC.
-    let common_ancestor = rpc
-        .common_ancestor(&node_tip.hash, &wallet_tip.hash)
-        .await
-        .unwrap_or_else(|| BlockId {
-            height: 0,
-            hash: wallet.genesis_hash(),
-        });
-
-    println!("Disconnecting the chain from {:?}", common_ancestor);
-    wallet.disconnect(common_ancestor).unwrap();
-
-    // FIXME: of course the tip height might have changed in the meanwhile. Doesn't matter
-    // for this PoC.
-    println!("Now processing blocks all the way to the tip.");
-    let start_height: i32 = common_ancestor.height.try_into().expect("Never negative.");
-    for h in start_height..=node_tip.height.try_into().expect("Never negative.") {
-        let block = rpc.get_block(&node_tip.hash, h).await;
-        wallet.apply_block(&block, h).unwrap();
+// Compact the file stores of the wallets, or of the default one if none is specified, while the
+// program isn't running.
+fn compact_stores(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let default = [String::new()];
+    let names = if options.wallets.is_empty() {
+        &default[..]
+    } else {
+        &options.wallets[..]
+    };
+    for name in names {
+        let (before, after) =
+            BdkWallet::compact_store(&options.data_dir, name, options.store.key.as_ref())?;
+        println!(
+            "Compacted the store of wallet '{}' from {} to {} bytes.",
+            name, before, after
+        );
     }
-
-    wallet_startup_complete(rpc, wallet).await
+    Ok(())
 }
 
-// Start the BDK wallet and sync its state with Core's.
-async fn wallet_startup(
-    rpc: &RpcInterface,
-) -> Result<Arc<Mutex<BdkWallet>>, Box<dyn std::error::Error>> {
-    let mut wallet = BdkWallet::new()?;
-
-    rpc.show_progress("BDK Core startup", 1, false).await;
-
-    let node_tip = rpc.get_tip().await;
-    let wallet_tip = wallet.tip();
-    if wallet_tip == node_tip {
-        return Ok(wallet_startup_complete(rpc, wallet).await);
+// Print the content of the file stores of the wallets, or of the default one if none is specified,
+// as JSON.
+fn dump_stores(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let default = [String::new()];
+    let names = if options.wallets.is_empty() {
+        &default[..]
+    } else {
+        &options.wallets[..]
+    };
+    for name in names {
+        let dump = store_dump::dump_store(
+            &options.data_dir,
+            name,
+            options.store.key.as_ref(),
+            &options.dump_filter,
+        )?;
+        println!("{}", serde_json::to_string_pretty(&dump)?);
     }
+    Ok(())
+}
 
-    if wallet_tip.height >= node_tip.height {
-        println!("The tip on bitcoin-node was reorged or moved backward.");
-        return Ok(wallet_handle_startup_reorg(rpc, wallet, &node_tip, &wallet_tip).await);
+// Encrypt the file stores of the wallets, or of the default one if none is specified, with a new
+// key while the program isn't running. Decrypting them must be asked for explicitly.
+fn rotate_store_keys(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    if options.new_store_key.is_some() == options.decrypt {
+        return Err("Specify either a new key or --decrypt.".into());
     }
+    let default = [String::new()];
+    let names = if options.wallets.is_empty() {
+        &default[..]
+    } else {
+        &options.wallets[..]
+    };
+    for name in names {
+        BdkWallet::rotate_store_key(
+            &options.data_dir,
+            name,
+            options.store.key.as_ref(),
+            options.new_store_key.as_ref(),
+        )?;
+        if options.decrypt {
+            println!("Decrypted the store of wallet '{}'.", name);
+        } else {
+            println!("Encrypted the store of wallet '{}' with the new key.", name);
+        }
+    }
+    Ok(())
+}
 
+// Import a descriptor into a wallet, or into the default one if none is specified, while the
+// program isn't running. It's rescanned for the next time the wallet is started.
+fn import_descriptor(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let descriptor = options
+        .descriptor
+        .as_ref()
+        .ok_or("Specify the descriptor to import.")?;
+    let name = match &options.wallets[..] {
+        [] => "",
+        [name] => name,
+        _ => return Err("Descriptors are imported into a single wallet.".into()),
+    };
+    BdkWallet::import_descriptor(
+        &options.data_dir,
+        name,
+        descriptor,
+        options.birthday.unwrap_or(0),
+        options.range_end,
+        &options.store,
+    )?;
     println!(
-        "Height on bitcoin-node moved forward. Making sure wallet tip is still in best chain."
+        "Imported the descriptor into wallet '{}'. It will be rescanned from height {} once started.",
+        name,
+        options.birthday.unwrap_or(0)
     );
-    if !rpc.is_in_best_chain(&node_tip.hash, &wallet_tip.hash).await {
-        println!("Wallet tip is not in best chain anymore. Proceeding to process reorg.");
-        return Ok(wallet_handle_startup_reorg(rpc, wallet, &node_tip, &wallet_tip).await);
-    }
-
-    println!("All good. Now making sure it has all the blocks for us to sync.");
-    let start_height: i32 = (wallet_tip.height + 1).try_into().expect("Must fit");
-    if !rpc.has_blocks(&node_tip.hash, start_height).await {
-        return Err("bitcoin-node is missing blocks to sync the BDK wallet.".into());
-    }
+    Ok(())
+}
 
-    println!("It does. Now proceeding to sync the BDK wallet.");
-    for h in start_height..=node_tip.height.try_into().expect("Never negative.") {
-        let block = rpc.get_block(&node_tip.hash, h).await;
-        wallet.apply_block(&block, h)?;
+// Print the descriptors of the wallets, or of the default one if none is specified, in the format
+// of Bitcoin Core's importdescriptors while the program isn't running.
+fn export_descriptors(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let default = [String::new()];
+    let names = if options.wallets.is_empty() {
+        &default[..]
+    } else {
+        &options.wallets[..]
+    };
+    for name in names {
+        let wallet = BdkWallet::load(
+            &options.data_dir,
+            name,
+            &options.store,
+            Arc::new(tokio::sync::Notify::new()),
+        )?;
+        let descriptors = wallet.export_descriptors();
+        println!("{}", serde_json::to_string_pretty(&descriptors)?);
     }
-    // Get wallet outpoints to scan
-    
-    println!("Done syncing missing blocks.");
-    return Ok(wallet_startup_complete(rpc, wallet).await);
+    Ok(())
 }
 
-async fn rpc_main(stream: tokio::net::UnixStream) -> Result<(), Box<dyn std::error::Error>> {
-    let rpc = RpcInterface::new(stream).await?;
-    let wallet = wallet_startup(&rpc).await?;
-
-    wallet.lock().unwrap().print_info()?;
-
+// Back up a wallet, or the default one if none is specified, reading its store directly while the
+// program isn't running. The backup is encrypted with the store key if one is given.
+fn backup_wallet(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let destination = options
+        .destination
+        .as_ref()
+        .ok_or("Specify the destination of the backup.")?;
+    let name = match &options.wallets[..] {
+        [] => "",
+        [name] => name,
+        _ => return Err("A single wallet is backed up at a time.".into()),
+    };
+    let wallet = BdkWallet::load(
+        &options.data_dir,
+        name,
+        &options.store,
+        Arc::new(tokio::sync::Notify::new()),
+    )?;
+    backup::write_backup(destination, &wallet.backup(), options.store.key.as_ref())?;
     println!(
-        "\nWaiting {} seconds before disconnecting.",
-        SLEEP_BEFORE_DISCONNECT_SECS
+        "Backed up wallet '{}' at height {} to {}.",
+        name,
+        wallet.tip().height,
+        destination.display()
     );
-    tokio::time::sleep(Duration::from_secs(SLEEP_BEFORE_DISCONNECT_SECS)).await;
-    println!("Disconnecting.");
-    rpc.disconnect().await?;
-
-    wallet.lock().unwrap().print_info()?;
+    Ok(())
+}
 
+// Print the descriptor compiled from a policy, to create a wallet with or import into one.
+fn compile_policy(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let policy = options
+        .policy
+        .as_ref()
+        .ok_or("Specify the policy to compile.")?;
+    println!("{}", policy::compile_policy(policy, options.taproot)?);
     Ok(())
 }
 
+// Print how to invoke the program and its commands.
+fn print_usage(program: &str) {
+    eprintln!(
+        "usage: {} /path/to/bitcoin-node/unix/socket [--wallet <name>]... [--create-wallet <name> <descriptor>]... [--restore-wallet <name> <backup file>]... [--rescan <from height> <to height>] [--lookahead <n>] [--rpcport <port> [--signing-keyfile <path>]] [--datadir <path>] [--store <file|sqlite>] [--store-keyfile <path> | --store-password-env <variable>]",
+        program
+    );
+    eprintln!(
+        "       {} compact [--wallet <name>]... [--datadir <path>] [<store key>]",
+        program
+    );
+    eprintln!(
+        "       {} dump-store [--wallet <name>]... [--datadir <path>] [<store key>] [--txid <txid>] [--height <height>]",
+        program
+    );
+    eprintln!(
+        "       {} import-descriptor --descriptor <descriptor#checksum> [--wallet <name>] [--datadir <path>] [--store <file|sqlite>] [<store key>] [--birthday <height>] [--range <last index>]",
+        program
+    );
+    eprintln!(
+        "       {} export-descriptors [--wallet <name>]... [--datadir <path>] [--store <file|sqlite>] [<store key>]",
+        program
+    );
+    eprintln!(
+        "       {} backup --destination <path> [--wallet <name>] [--datadir <path>] [--store <file|sqlite>] [<store key>]",
+        program
+    );
+    eprintln!(
+        "       {} compile-policy --policy <policy> [--taproot]",
+        program
+    );
+    eprintln!(
+        "       {} rotate-key [--wallet <name>]... [--datadir <path>] [<store key>] (--new-store-keyfile <path> | --new-store-password-env <variable> | --decrypt)",
+        program
+    );
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("usage: {} /path/to/bitcoin-node/unix/socket", args[0]);
-        return Ok(());
+    let mut args = env::args();
+    let program = args.next().unwrap_or_default();
+    let Some(socket_path) = args.next() else {
+        print_usage(&program);
+        return Err("Missing the socket path or command.".into());
+    };
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            print_usage(&program);
+            return Err(e);
+        }
+    };
+    match socket_path.as_str() {
+        "compact" => return compact_stores(&options),
+        "dump-store" => return dump_stores(&options),
+        "rotate-key" => return rotate_store_keys(&options),
+        "import-descriptor" => return import_descriptor(&options),
+        "export-descriptors" => return export_descriptors(&options),
+        "backup" => return backup_wallet(&options),
+        "compile-policy" => return compile_policy(&options),
+        _ => {}
     }
 
-    let socket_path = args.last().expect("Just checked.");
     let stream = tokio::net::UnixStream::connect(&socket_path).await?;
 
     tokio::task::LocalSet::new()
-        .run_until(rpc_main(stream))
+        .run_until(rpc_main(stream, options))
         .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,626 @@
+//! Our subscription to Bitcoin Core's validation events.
+//!
+//! The capnp callbacks only queue the raw events, which a worker task decodes and applies to the
+//! loaded wallets in order. Core's notification queue is never stalled by us processing blocks
+//! and writing them to disk, unless we fall too far behind: once the bounded queue is full the
+//! callbacks only return when there is room again.
+
+use bdk_chain::{
+    bitcoin::{self, consensus::Decodable, hashes::Hash},
+    BlockId,
+};
+use capnp_rpc::pry;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::{self, JoinHandle},
+};
+
+use std::{
+    cell::{Cell, RefCell},
+    error,
+    ops::Deref,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    chain_capnp::chain_notifications::{
+        self, BlockConnectedParams, BlockConnectedResults, BlockDisconnectedParams,
+        BlockDisconnectedResults, ChainStateFlushedParams, ChainStateFlushedResults, DestroyParams,
+        DestroyResults, TransactionAddedToMempoolParams, TransactionAddedToMempoolResults,
+        TransactionRemovedFromMempoolParams, TransactionRemovedFromMempoolResults,
+        UpdatedBlockTipParams, UpdatedBlockTipResults,
+    },
+    handler_capnp::handler::Client as HandlerClient,
+    rpc_interface::{NodeChain, RpcInterface},
+    wallet::MempoolRemovalReason,
+    wallet_manager::WalletManager,
+};
+
+// How many events may be waiting for the worker. Connected blocks are the largest events, so this
+// bounds the memory used by the queue to a few hundred MBs at most.
+const EVENT_QUEUE_CAPACITY: usize = 64;
+
+/// How often to check whether Core released our registration, to register again.
+pub const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(10);
+
+// A notification from Core, queued as received, or a request from us to the worker.
+enum ChainEvent {
+    TransactionAdded {
+        tx: Vec<u8>,
+    },
+    TransactionRemoved {
+        tx: Vec<u8>,
+        reason: i32,
+    },
+    BlockConnected {
+        height: i32,
+        prev_hash: Vec<u8>,
+        block: Vec<u8>,
+    },
+    BlockDisconnected {
+        height: i32,
+        hash: Vec<u8>,
+        prev_hash: Vec<u8>,
+    },
+    UpdatedTip,
+    // Sync the wallets with the node's tip, after notifications were missed.
+    CatchUp(oneshot::Sender<Result<(), String>>),
+    // Answered once all the events queued before it were processed.
+    Flush(oneshot::Sender<()>),
+    // Stop the worker once all the events queued before it were processed.
+    Stop,
+}
+
+/// Statistics about the queue of notifications waiting to be processed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueueMetrics {
+    /// The number of events waiting to be processed.
+    pub depth: usize,
+    /// The highest depth reached.
+    pub max_depth: usize,
+    /// The number of events processed.
+    pub processed: u64,
+    /// How many times a notification had to wait for room in the queue.
+    pub backpressure_waits: u64,
+}
+
+fn update_metrics(metrics: &Cell<QueueMetrics>, update: impl FnOnce(&mut QueueMetrics)) {
+    let mut m = metrics.get();
+    update(&mut m);
+    metrics.set(m);
+}
+
+fn record_enqueued(metrics: &Cell<QueueMetrics>) {
+    update_metrics(metrics, |m| {
+        m.depth += 1;
+        m.max_depth = m.max_depth.max(m.depth);
+    });
+}
+
+// The interface we register to Core's notifications.
+#[derive(Clone)]
+struct ChainNotifications {
+    events: mpsc::Sender<ChainEvent>,
+    metrics: Rc<Cell<QueueMetrics>>,
+    // How many of our registrations Core still holds. It destroys its reference to our interface
+    // when shutting down or once the registration's handler was disconnected.
+    registrations: Rc<Cell<u32>>,
+}
+
+impl ChainNotifications {
+    // Queue an event for the worker. Returns immediately unless the queue is full, in which case
+    // the returned promise only resolves once there is room. Core sends its notifications one
+    // after the other, so they are queued in order.
+    fn enqueue(&self, event: ChainEvent) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        let event = match self.events.try_send(event) {
+            Ok(()) => {
+                record_enqueued(&self.metrics);
+                return ::capnp::capability::Promise::ok(());
+            }
+            Err(TrySendError::Closed(_)) => {
+                return ::capnp::capability::Promise::err(::capnp::Error::disconnected(
+                    "The wallet worker stopped.".to_string(),
+                ))
+            }
+            Err(TrySendError::Full(event)) => event,
+        };
+        update_metrics(&self.metrics, |m| m.backpressure_waits += 1);
+        eprintln!(
+            "Notification queue is full ({} events), waiting for the wallets to catch up.",
+            EVENT_QUEUE_CAPACITY
+        );
+        let (events, metrics) = (self.events.clone(), self.metrics.clone());
+        ::capnp::capability::Promise::from_future(async move {
+            events.send(event).await.map_err(|_| {
+                ::capnp::Error::disconnected("The wallet worker stopped.".to_string())
+            })?;
+            record_enqueued(&metrics);
+            Ok(())
+        })
+    }
+}
+
+// Implementation of the subscription to validation events from Bitcoin Core. Main logic post startup.
+// Events are queued for the worker, which decodes them once and applies them to every loaded wallet.
+impl chain_notifications::Server for ChainNotifications {
+    fn destroy(
+        &mut self,
+        _: DestroyParams,
+        _: DestroyResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        let registrations = self.registrations.get().saturating_sub(1);
+        self.registrations.set(registrations);
+        if registrations == 0 {
+            println!("bitcoin-node released our notifications subscription.");
+        }
+        ::capnp::capability::Promise::ok(())
+    }
+
+    fn transaction_added_to_mempool(
+        &mut self,
+        params: TransactionAddedToMempoolParams,
+        _: TransactionAddedToMempoolResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        let tx = pry!(pry!(params.get()).get_tx()).to_vec();
+        self.enqueue(ChainEvent::TransactionAdded { tx })
+    }
+
+    fn transaction_removed_from_mempool(
+        &mut self,
+        params: TransactionRemovedFromMempoolParams,
+        _: TransactionRemovedFromMempoolResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        // BDK's transaction graph is monotone so we can't remove the tx from it (see https://docs.rs/bdk_chain/latest/bdk_chain/tx_graph/index.html),
+        // but the wallets release the coins they reserved for it.
+        let params = pry!(params.get());
+        let tx = pry!(params.get_tx()).to_vec();
+        let reason = params.get_reason();
+        self.enqueue(ChainEvent::TransactionRemoved { tx, reason })
+    }
+
+    fn block_connected(
+        &mut self,
+        params: BlockConnectedParams,
+        _: BlockConnectedResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        // Assume no background chainstate for the purpose of this PoC.
+        let info = pry!(pry!(params.get()).get_block());
+        self.enqueue(ChainEvent::BlockConnected {
+            height: info.get_height(),
+            prev_hash: pry!(info.get_prev_hash()).to_vec(),
+            block: pry!(info.get_data()).to_vec(),
+        })
+    }
+
+    fn block_disconnected(
+        &mut self,
+        params: BlockDisconnectedParams,
+        _: BlockDisconnectedResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        let info = pry!(pry!(params.get()).get_block());
+        self.enqueue(ChainEvent::BlockDisconnected {
+            height: info.get_height(),
+            hash: pry!(info.get_hash()).to_vec(),
+            prev_hash: pry!(info.get_prev_hash()).to_vec(),
+        })
+    }
+
+    fn updated_block_tip(
+        &mut self,
+        _: UpdatedBlockTipParams,
+        _: UpdatedBlockTipResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        self.enqueue(ChainEvent::UpdatedTip)
+    }
+
+    fn chain_state_flushed(
+        &mut self,
+        _: ChainStateFlushedParams,
+        _: ChainStateFlushedResults,
+    ) -> ::capnp::capability::Promise<(), ::capnp::Error> {
+        println!("Chainstate flushed.");
+        ::capnp::capability::Promise::ok(())
+    }
+}
+
+// Decode a transaction sent by Core. An invalid one is logged and skipped rather than taking down
+// the worker.
+fn decode_tx(tx: &[u8]) -> Option<bitcoin::Transaction> {
+    bitcoin::Transaction::consensus_decode(&mut &tx[..])
+        .map_err(|e| eprintln!("Skipping an invalid transaction: '{}'", e))
+        .ok()
+}
+
+fn decode_hash(hash: &[u8]) -> Option<bitcoin::BlockHash> {
+    bitcoin::BlockHash::from_slice(hash)
+        .map_err(|e| eprintln!("Invalid block hash: '{}'", e))
+        .ok()
+}
+
+// Process the queued events in order until stopped.
+async fn worker(
+    mut events: mpsc::Receiver<ChainEvent>,
+    manager: Arc<Mutex<WalletManager>>,
+    chain: NodeChain,
+    metrics: Rc<Cell<QueueMetrics>>,
+) {
+    while let Some(event) = events.recv().await {
+        update_metrics(&metrics, |m| m.depth -= 1);
+        match event {
+            ChainEvent::TransactionAdded { tx } => {
+                let Some(tx) = decode_tx(&tx) else {
+                    continue;
+                };
+                println!("New mempool transaction {}.", tx.compute_txid());
+                manager.lock().unwrap().apply_tx(&tx);
+            }
+            ChainEvent::TransactionRemoved { tx, reason } => {
+                let Some(tx) = decode_tx(&tx) else {
+                    continue;
+                };
+                let Some(reason) = MempoolRemovalReason::from_core(reason) else {
+                    eprintln!(
+                        "Transaction {} removed from the mempool for an unknown reason {}.",
+                        tx.compute_txid(),
+                        reason
+                    );
+                    continue;
+                };
+                println!(
+                    "Transaction {} removed from the mempool ({:?}).",
+                    tx.compute_txid(),
+                    reason
+                );
+                manager.lock().unwrap().remove_mempool_tx(&tx, reason);
+            }
+            ChainEvent::BlockConnected {
+                height,
+                prev_hash,
+                block,
+            } => {
+                let block = match bitcoin::Block::consensus_decode(&mut block.as_slice()) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        eprintln!("Skipping an invalid connected block: '{}'", e);
+                        continue;
+                    }
+                };
+                let (Ok(parent_height), Some(parent_hash)) =
+                    ((height - 1).try_into(), decode_hash(&prev_hash))
+                else {
+                    eprintln!(
+                        "Skipping connected block {} with an invalid parent.",
+                        block.block_hash()
+                    );
+                    continue;
+                };
+                let parent = BlockId {
+                    height: parent_height,
+                    hash: parent_hash,
+                };
+                println!("New connected block {}.", block.block_hash());
+                // If we missed notifications, the block doesn't build on top of the wallets' tip.
+                // Fetch the blocks we missed first so we don't leave a gap in their local chain.
+                let names = manager.lock().unwrap().names();
+                for name in names {
+                    let wallet_tip = manager.lock().unwrap().wallet(&name).map(|w| w.tip());
+                    let Some(wallet_tip) = wallet_tip.filter(|tip| *tip != parent) else {
+                        continue;
+                    };
+                    eprintln!(
+                        "Block {} doesn't build on the tip {:?} of wallet '{}'. Fetching the missing blocks.",
+                        block.block_hash(),
+                        wallet_tip,
+                        name
+                    );
+                    if let Err(e) = wallet_fill_gap(&chain, &manager, &name, &parent).await {
+                        eprintln!(
+                            "Error when fetching missing blocks for wallet '{}': '{}'",
+                            name, e
+                        );
+                    }
+                }
+                manager.lock().unwrap().apply_block(&block, height);
+            }
+            ChainEvent::BlockDisconnected {
+                height,
+                hash,
+                prev_hash,
+            } => {
+                // Here again, BDK's tx graph is monotone so we don't actually have to remove transactions.
+                let (Ok(height), Some(hash), Some(prev_hash)) = (
+                    height.try_into(),
+                    decode_hash(&hash),
+                    decode_hash(&prev_hash),
+                ) else {
+                    eprintln!(
+                        "Skipping an invalid disconnected block at height {}.",
+                        height
+                    );
+                    continue;
+                };
+                manager
+                    .lock()
+                    .unwrap()
+                    .disconnect(BlockId { height, hash }, prev_hash);
+                println!("Disconnected block {}", hash);
+            }
+            ChainEvent::UpdatedTip => {
+                // The notification doesn't tell us the new tip, ask for it.
+                // On failure, the next tip update is relied upon to bring us up to date.
+                let (node_tip, ibd) = match node_status(&chain).await {
+                    Ok(status) => status,
+                    Err(e) => {
+                        eprintln!("Error when fetching the node's tip: '{}'", e);
+                        continue;
+                    }
+                };
+                println!(
+                    "Block tip updated to {:?}{}.",
+                    node_tip,
+                    if ibd { " (initial block download)" } else { "" }
+                );
+                manager.lock().unwrap().set_node_tip(node_tip, ibd);
+            }
+            ChainEvent::CatchUp(done) => {
+                let res = match chain.get_tip().await {
+                    Ok(node_tip) => catch_up(&chain, &manager, node_tip).await,
+                    Err(e) => Err(format!("Error when fetching the node's tip: '{}'", e)),
+                };
+                let _ = done.send(res);
+            }
+            ChainEvent::Flush(done) => {
+                let _ = done.send(());
+            }
+            ChainEvent::Stop => break,
+        }
+        update_metrics(&metrics, |m| m.processed += 1);
+    }
+}
+
+/// Our registration for Core's notifications, along with the worker processing them. It must be
+/// kept for as long as we want to receive them, as dropping the handler unregisters us.
+pub struct Subscription {
+    tip_sync: TipSync,
+    handler: RefCell<Option<HandlerClient>>,
+    worker: JoinHandle<()>,
+}
+
+impl Deref for Subscription {
+    type Target = TipSync;
+
+    fn deref(&self) -> &TipSync {
+        &self.tip_sync
+    }
+}
+
+impl Subscription {
+    /// Start the worker and register for notifications. The loaded wallets must be synced with
+    /// the node's tip.
+    pub async fn subscribe(
+        rpc: &RpcInterface,
+        manager: &Arc<Mutex<WalletManager>>,
+    ) -> Result<Self, capnp::Error> {
+        let (events, receiver) = mpsc::channel(EVENT_QUEUE_CAPACITY);
+        let metrics = Rc::new(Cell::new(QueueMetrics::default()));
+        let worker = task::spawn_local(worker(
+            receiver,
+            manager.clone(),
+            rpc.chain.clone(),
+            metrics.clone(),
+        ));
+        let subscription = Self {
+            tip_sync: TipSync {
+                notifications: ChainNotifications {
+                    events,
+                    metrics,
+                    registrations: Rc::new(Cell::new(0)),
+                },
+                manager: manager.clone(),
+            },
+            handler: RefCell::new(None),
+            worker,
+        };
+        subscription.register(rpc).await?;
+        Ok(subscription)
+    }
+
+    async fn register(&self, rpc: &RpcInterface) -> Result<(), capnp::Error> {
+        let client = capnp_rpc::new_client(self.notifications.clone());
+        let handler = rpc.register_notifications(client).await?;
+        *self.handler.borrow_mut() = Some(handler);
+        let registrations = &self.notifications.registrations;
+        registrations.set(registrations.get() + 1);
+        Ok(())
+    }
+
+    /// Whether we are registered and Core didn't release our subscription (for instance because
+    /// it's shutting down).
+    pub fn is_active(&self) -> bool {
+        self.handler.borrow().is_some() && self.notifications.registrations.get() > 0
+    }
+
+    /// The state of the queue of notifications waiting to be processed.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.notifications.metrics.get()
+    }
+
+    /// Stop receiving notifications until resumed. The wallets won't be updated in the meantime.
+    pub async fn pause(&self, rpc: &RpcInterface) -> Result<(), capnp::Error> {
+        let handler = self.handler.borrow_mut().take();
+        if let Some(handler) = handler {
+            if self.notifications.registrations.get() > 0 {
+                rpc.disconnect_handler(&handler).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register again for notifications, and sync the wallets with the blocks we missed in the
+    /// meantime. Notifications received while doing so are processed after.
+    pub async fn resume(&self, rpc: &RpcInterface) -> Result<(), Box<dyn error::Error>> {
+        if self.is_active() {
+            return Ok(());
+        }
+        *self.handler.borrow_mut() = None;
+        self.register(rpc).await?;
+        let (done, caught_up) = oneshot::channel();
+        self.send(ChainEvent::CatchUp(done)).await?;
+        caught_up.await??;
+        Ok(())
+    }
+
+    /// A handle to wait for the notifications to be processed, which can be held by the handlers
+    /// of our commands.
+    pub fn tip_sync(&self) -> TipSync {
+        self.tip_sync.clone()
+    }
+
+    /// Unregister for good, once the notifications received so far were processed.
+    pub async fn disconnect(self, rpc: &RpcInterface) -> Result<(), capnp::Error> {
+        let res = self.pause(rpc).await;
+        if self.send(ChainEvent::Stop).await.is_ok() {
+            let _ = self.worker.await;
+        }
+        // Write what the notifications processed so far, now that no more are coming.
+        self.tip_sync.manager.lock().unwrap().persist();
+        res
+    }
+}
+
+/// Wait for the notifications queued by a [`Subscription`] to be processed, as long as its worker
+/// is running.
+#[derive(Clone)]
+pub struct TipSync {
+    notifications: ChainNotifications,
+    manager: Arc<Mutex<WalletManager>>,
+}
+
+impl TipSync {
+    /// Wait until all the notifications received so far were processed.
+    pub async fn flush(&self) -> Result<(), capnp::Error> {
+        let (done, flushed) = oneshot::channel();
+        self.send(ChainEvent::Flush(done)).await?;
+        flushed.await.map_err(|_| worker_stopped())
+    }
+
+    /// Wait until the loaded wallets reflect at least the node's tip as of this call. Returns the
+    /// tip they are synced to.
+    ///
+    /// The tip is that of the node's validated chain. The `Chain` interface doesn't expose the
+    /// headers the node has ahead of it, so there is no headers-only progress to report: while in
+    /// initial block download the node's tip, and so the wallets', lags behind the network's.
+    pub async fn sync_to_current_tip(&self, chain: &NodeChain) -> Result<BlockId, capnp::Error> {
+        let old_tip = self.manager.lock().unwrap().tip();
+        chain
+            .wait_for_notifications_if_tip_changed(&old_tip.hash)
+            .await?;
+        // Core delivered its notifications up to its current tip, they may still be queued.
+        self.flush().await?;
+        let tip = self.manager.lock().unwrap().tip();
+        Ok(tip)
+    }
+
+    async fn send(&self, event: ChainEvent) -> Result<(), capnp::Error> {
+        self.notifications
+            .events
+            .send(event)
+            .await
+            .map_err(|_| worker_stopped())?;
+        record_enqueued(&self.notifications.metrics);
+        Ok(())
+    }
+}
+
+// The node's tip and whether it's in initial block download.
+async fn node_status(chain: &NodeChain) -> Result<(BlockId, bool), capnp::Error> {
+    Ok((
+        chain.get_tip().await?,
+        chain.is_initial_block_download().await?,
+    ))
+}
+
+// Sync all the loaded wallets up to the node's tip, after notifications were missed.
+async fn catch_up(
+    chain: &NodeChain,
+    manager: &Arc<Mutex<WalletManager>>,
+    node_tip: BlockId,
+) -> Result<(), String> {
+    let names = manager.lock().unwrap().names();
+    for name in names {
+        if let Err(e) = wallet_fill_gap(chain, manager, &name, &node_tip).await {
+            return Err(format!("Error when syncing wallet '{}': '{}'", name, e));
+        }
+    }
+    manager.lock().unwrap().set_tip(node_tip);
+    Ok(())
+}
+
+fn worker_stopped() -> capnp::Error {
+    capnp::Error::disconnected("The wallet worker stopped.".to_string())
+}
+
+// Sync a loaded wallet up to `target`, a block of the node's chain, when notifications were missed.
+// The wallet is only locked while applying changes, never across requests to the node.
+async fn wallet_fill_gap(
+    chain: &NodeChain,
+    manager: &Arc<Mutex<WalletManager>>,
+    name: &str,
+    target: &BlockId,
+) -> Result<(), Box<dyn error::Error>> {
+    let Some(wallet_tip) = manager.lock().unwrap().wallet(name).map(|w| w.tip()) else {
+        return Ok(());
+    };
+
+    // We may have missed disconnections too, in which case the wallet's tip isn't part of the
+    // chain anymore. Disconnect its blocks above the fork point first.
+    if !chain
+        .is_in_best_chain(&target.hash, &wallet_tip.hash)
+        .await?
+    {
+        let genesis_hash = manager
+            .lock()
+            .unwrap()
+            .wallet(name)
+            .map(|w| w.genesis_hash());
+        let common_ancestor = match chain
+            .common_ancestor(&target.hash, &wallet_tip.hash)
+            .await?
+        {
+            Some(ancestor) => ancestor,
+            None => BlockId {
+                height: 0,
+                hash: genesis_hash.ok_or("Wallet was unloaded.")?,
+            },
+        };
+        println!("Disconnecting the chain above {:?}", common_ancestor);
+        let mut manager = manager.lock().unwrap();
+        let Some(wallet) = manager.wallet_mut(name) else {
+            return Ok(());
+        };
+        wallet.disconnect_above(common_ancestor)?;
+    }
+
+    let Some(start_height) = manager
+        .lock()
+        .unwrap()
+        .wallet(name)
+        .map(|w| w.tip().height + 1)
+    else {
+        return Ok(());
+    };
+    for h in start_height..=target.height {
+        let block = chain.get_block(&target.hash, h.try_into()?).await?;
+        let mut manager = manager.lock().unwrap();
+        let Some(wallet) = manager.wallet_mut(name) else {
+            return Ok(());
+        };
+        wallet.apply_block(&block, h.try_into()?)?;
+    }
+    Ok(())
+}
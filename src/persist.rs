@@ -0,0 +1,847 @@
+//! Batched persistence of the wallets' changes.
+//!
+//! Changes are merged in memory and written to the store as a single changeset per batch, which
+//! is then synced to disk. A batch is written once enough blocks were processed, when the wallet
+//! hands out something it must not forget (such as an address), or when asked to by the periodic
+//! flush. Since each batch holds both the chain and the transaction graph changes, the store
+//! always reflects the wallet as of the end of a batch. After a crash the wallet restarts from
+//! there, and catches up with the blocks it lost from the node.
+//!
+//! Where the batches are written is up to a [`Backend`]: either an append-only file of
+//! changesets, or an SQLite database of the aggregated state (see [`crate::sqlite_store`]). The
+//! records of a file store may be encrypted (see [`crate::store_crypto`]). The small stores next
+//! to it, such as the wallet's settings, are then sealed with the same key (see [`SideStore`]).
+
+use bdk_chain::{
+    bitcoin::{self, Txid},
+    BlockId, Merge,
+};
+use bdk_file_store::{IterError, Store as BdkStore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::{
+    error,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::store_crypto::{
+    self, EncryptionParams, FileSealer, SealedRecord, StoreCipher, StoreKey,
+};
+
+/// How many processed blocks are merged into a single write to the store.
+pub const PERSIST_BATCH_BLOCKS: usize = 100;
+/// How often the staged changes are written when not enough blocks were processed to fill a
+/// batch, for instance while following the tip.
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+/// A file store is compacted once larger than this, and at least twice its size after it was last
+/// compacted.
+pub const COMPACT_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The kind of store the wallets are persisted in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StoreBackend {
+    /// An append-only file of changesets.
+    #[default]
+    File,
+    /// An SQLite database.
+    Sqlite,
+}
+
+impl FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Self::File),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!(
+                "Unknown store '{}', expected 'file' or 'sqlite'.",
+                s
+            )),
+        }
+    }
+}
+
+/// How the wallets are persisted.
+#[derive(Debug, Default, Clone)]
+pub struct StoreConfig {
+    pub backend: StoreBackend,
+    /// Encrypt the file stores with this key.
+    pub key: Option<StoreKey>,
+}
+
+/// Where the changes of type `C` are persisted.
+pub trait Backend<C>: Send {
+    /// Read back the aggregate of all the changes written so far.
+    fn load(&mut self) -> Result<C, Box<dyn error::Error>>;
+
+    /// Write these changes, and make sure they are on disk before returning.
+    fn write(&mut self, changeset: &C) -> Result<(), Box<dyn error::Error>>;
+
+    /// Queries on the persisted state, if the backend supports them.
+    fn queries(&self) -> Option<&dyn Queries> {
+        None
+    }
+}
+
+/// A transaction of the wallet, as persisted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTx {
+    pub tx: bitcoin::Transaction,
+    /// The block of the persisted chain it's confirmed in, if any.
+    pub confirmation: Option<BlockId>,
+    /// When it was last seen unconfirmed, if ever.
+    pub last_seen: Option<u64>,
+}
+
+/// Look up the persisted state without loading it.
+pub trait Queries {
+    /// The transaction with this txid, if it's part of the store.
+    fn transaction(&self, txid: &Txid) -> Result<Option<StoredTx>, Box<dyn error::Error>>;
+
+    /// The transactions confirmed in the block at this height of the persisted chain.
+    fn transactions_at_height(&self, height: u32) -> Result<Vec<Txid>, Box<dyn error::Error>>;
+}
+
+/// The magic bytes starting a versioned file store.
+const STORE_MAGIC: &[u8] = b"bdk_core_vstore";
+/// The magic bytes starting a file store from before it was versioned, which only contains
+/// changesets. It is version 0.
+const LEGACY_STORE_MAGIC: &[u8] = b"bdk_core_store";
+/// The current version of the file store format. Bump it along with a reader for the previous
+/// format in [`FileBackend::migrate`] whenever the format of the persisted changesets changes. The
+/// version must remain the first field of the header, which must remain the first field of the
+/// entries.
+pub const STORE_VERSION: u32 = 2;
+
+/// The header of a file store, identifying what it contains.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreHeader {
+    /// The version of the store format.
+    pub version: u32,
+    pub network: bitcoin::Network,
+    /// The checksum of the descriptor of the wallet.
+    pub descriptor_checksum: String,
+    /// How the records are encrypted, if they are.
+    pub encryption: Option<EncryptionParams>,
+}
+
+impl StoreHeader {
+    /// The header of an unencrypted store in the current format.
+    pub fn new(network: bitcoin::Network, descriptor_checksum: &str) -> Self {
+        Self {
+            version: STORE_VERSION,
+            network,
+            descriptor_checksum: descriptor_checksum.to_string(),
+            encryption: None,
+        }
+    }
+
+    /// Check the store at this path, with this header, is for the same network and descriptor as
+    /// this one.
+    pub fn check(&self, stored: &StoreHeader, path: &Path) -> Result<(), Box<dyn error::Error>> {
+        if stored.network != self.network {
+            return Err(format!(
+                "The store at {} is for {}, not {}.",
+                path.display(),
+                stored.network,
+                self.network
+            )
+            .into());
+        }
+        if stored.descriptor_checksum != self.descriptor_checksum {
+            return Err(format!(
+                "The store at {} is for the descriptor with checksum {}, not {}.",
+                path.display(),
+                stored.descriptor_checksum,
+                self.descriptor_checksum
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // What the encrypted records are bound to, so they can't be moved to another wallet's store.
+    fn aad(&self) -> Vec<u8> {
+        format!("{}:{}", self.network, self.descriptor_checksum).into_bytes()
+    }
+}
+
+// An entry of a versioned file store. The first one holds the header. The changes of an encrypted
+// store are sealed records, which are opened and merged in order.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Envelope<C> {
+    header: Option<StoreHeader>,
+    changeset: C,
+    sealed: Vec<SealedRecord>,
+}
+
+impl<C: Merge> Envelope<C> {
+    // An entry with these changes, sealed if there's a cipher.
+    fn new(
+        header: Option<StoreHeader>,
+        changeset: &C,
+        cipher: Option<&StoreCipher>,
+    ) -> Result<Self, Box<dyn error::Error>>
+    where
+        C: Default + Clone + Serialize,
+    {
+        Ok(match cipher {
+            Some(cipher) => Self {
+                header,
+                changeset: C::default(),
+                sealed: vec![cipher.seal(changeset)?],
+            },
+            None => Self {
+                header,
+                changeset: changeset.clone(),
+                sealed: Vec::new(),
+            },
+        })
+    }
+
+    // The changes of the entry, opening the sealed records with the cipher.
+    fn open(self, cipher: Option<&StoreCipher>) -> Result<C, Box<dyn error::Error>>
+    where
+        C: DeserializeOwned,
+    {
+        let mut changeset = self.changeset;
+        for record in &self.sealed {
+            let cipher = cipher.ok_or("The store contains encrypted records, but no key.")?;
+            changeset.merge(cipher.open(record)?);
+        }
+        Ok(changeset)
+    }
+}
+
+impl<C: Merge> Merge for Envelope<C> {
+    fn merge(&mut self, other: Self) {
+        if other.header.is_some() {
+            self.header = other.header;
+        }
+        self.changeset.merge(other.changeset);
+        self.sealed.extend(other.sealed);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.header.is_none() && self.changeset.is_empty() && self.sealed.is_empty()
+    }
+}
+
+// The header of version 1 stores, which couldn't be encrypted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoreHeaderV1 {
+    version: u32,
+    network: bitcoin::Network,
+    descriptor_checksum: String,
+}
+
+// An entry of a version 1 store.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct EnvelopeV1<C> {
+    header: Option<StoreHeaderV1>,
+    changeset: C,
+}
+
+impl<C: Merge> Merge for EnvelopeV1<C> {
+    fn merge(&mut self, other: Self) {
+        if other.header.is_some() {
+            self.header = other.header;
+        }
+        self.changeset.merge(other.changeset);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.header.is_none() && self.changeset.is_empty()
+    }
+}
+
+// The start of the first entry of a versioned store, whatever its version: the version of its
+// header.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct VersionProbe {
+    version: Option<u32>,
+}
+
+impl Merge for VersionProbe {
+    fn merge(&mut self, other: Self) {
+        self.version = self.version.or(other.version);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.version.is_none()
+    }
+}
+
+/// A [`Backend`] appending each changeset to a file. As the file grows it is compacted: all its
+/// changesets are replaced by their aggregate.
+pub struct FileBackend<C>
+where
+    C: Send + Sync,
+{
+    store: BdkStore<Envelope<C>>,
+    // Another handle to the store's file, to sync it to disk.
+    file: File,
+    header: StoreHeader,
+    // Set if the store is encrypted.
+    cipher: Option<StoreCipher>,
+    path: PathBuf,
+    // The size of the file after it was last compacted.
+    compacted_size: u64,
+}
+
+impl<C> FileBackend<C>
+where
+    C: Merge + Default + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    /// Open the store at this path, creating it with this header if it doesn't exist. A store in
+    /// an older format is migrated first. Changes must be loaded before new ones are written,
+    /// which fails if the store doesn't have this header.
+    ///
+    /// A new store is encrypted if there's a key. An existing store must be opened with its key
+    /// if it's encrypted, and without one if it isn't (see [`Self::rotate_key`]).
+    pub fn open(
+        path: &Path,
+        mut header: StoreHeader,
+        key: Option<&StoreKey>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        if path.exists() {
+            Self::migrate(path, &header)?;
+        } else {
+            let cipher = match key {
+                Some(key) => {
+                    let (cipher, params) = key.new_cipher(&header.aad())?;
+                    header.encryption = Some(params);
+                    Some(cipher)
+                }
+                None => None,
+            };
+            write_store(path, &header, &C::default(), cipher.as_ref())?;
+        }
+        let mut store = BdkStore::<Envelope<C>>::open(STORE_MAGIC, path)?;
+        let stored = store
+            .iter_changesets()
+            .next()
+            .transpose()?
+            .and_then(|entry| entry.header)
+            .ok_or_else(|| format!("The store at {} has no header.", path.display()))?;
+        let cipher = match (&stored.encryption, key) {
+            (Some(params), Some(key)) => Some(
+                key.open_cipher(params, &stored.aad())
+                    .map_err(|e| format!("Error opening {}: '{}'", path.display(), e))?,
+            ),
+            (Some(_), None) => {
+                return Err(format!(
+                    "The store at {} is encrypted, a key is needed to open it.",
+                    path.display()
+                )
+                .into())
+            }
+            (None, Some(_)) => {
+                return Err(format!(
+                    "The store at {} isn't encrypted. Encrypt it with the rotate-key command.",
+                    path.display()
+                )
+                .into())
+            }
+            (None, None) => None,
+        };
+        header.encryption = stored.encryption;
+        let file = File::open(path)?;
+        Ok(Self {
+            store,
+            file,
+            header,
+            cipher,
+            path: path.to_path_buf(),
+            compacted_size: 0,
+        })
+    }
+
+    /// Replace all the changesets in the file by their aggregate. Returns the size of the file
+    /// before and after.
+    pub fn compact(&mut self) -> Result<(u64, u64), Box<dyn error::Error>> {
+        let size = self.file.metadata()?.len();
+        let aggregate = self.read_checked()?;
+        self.rewrite(&aggregate)?;
+        Ok((size, self.compacted_size))
+    }
+
+    /// Encrypt the store with this key instead of its current one, or decrypt it if there's none.
+    /// The store is rewritten at once with its changesets compacted, so it's never left with
+    /// records under both keys. Once encrypted, the copies kept in clear by migrations are removed.
+    pub fn rotate_key(&mut self, key: Option<&StoreKey>) -> Result<(), Box<dyn error::Error>> {
+        let aggregate = self.read_checked()?;
+        let (cipher, params) = match key {
+            Some(key) => {
+                let (cipher, params) = key.new_cipher(&self.header.aad())?;
+                (Some(cipher), Some(params))
+            }
+            None => (None, None),
+        };
+        self.cipher = cipher;
+        self.header.encryption = params;
+        self.rewrite(&aggregate)?;
+        if key.is_some() {
+            for version in 0..STORE_VERSION {
+                let backup = path_with_suffix(&self.path, &format!(".v{}.bak", version));
+                if backup.exists() {
+                    fs::remove_file(&backup)?;
+                    println!("Removed the unencrypted backup {}.", backup.display());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Upgrade the store at this path to the current version, reading it in its format and writing
+    // it back in the current one. A copy of the store is kept as a backup before it's modified.
+    // Stores are written unencrypted, as they were.
+    fn migrate(path: &Path, header: &StoreHeader) -> Result<(), Box<dyn error::Error>> {
+        let version = store_version(path)?;
+        if version > STORE_VERSION {
+            return Err(format!(
+                "The store at {} is at version {}, which is more recent than this program's {}.",
+                path.display(),
+                version,
+                STORE_VERSION
+            )
+            .into());
+        }
+        if version == STORE_VERSION {
+            return Ok(());
+        }
+        let backup = path_with_suffix(path, &format!(".v{}.bak", version));
+        println!(
+            "Migrating the store at {} from version {} to {}. A backup is kept at {}.",
+            path.display(),
+            version,
+            STORE_VERSION,
+            backup.display()
+        );
+        fs::copy(path, &backup)?;
+        File::open(&backup)?.sync_all()?;
+        let (stored, aggregate) = match version {
+            0 => Self::read_v0(path)?,
+            1 => Self::read_v1(path)?,
+            _ => unreachable!("Every version below the current one has a reader."),
+        };
+        write_store(
+            path,
+            &stored.unwrap_or_else(|| header.clone()),
+            &aggregate,
+            None,
+        )
+    }
+
+    // Version 0 stores are made of bare changesets, without a header. Their content is assumed to
+    // be the wallet's.
+    fn read_v0(path: &Path) -> Result<(Option<StoreHeader>, C), Box<dyn error::Error>> {
+        let mut store = BdkStore::<C>::open(LEGACY_STORE_MAGIC, path)?;
+        Ok((None, aggregate_entries(&mut store, path)?))
+    }
+
+    // Version 1 stores have a header, without encryption parameters.
+    fn read_v1(path: &Path) -> Result<(Option<StoreHeader>, C), Box<dyn error::Error>> {
+        let mut store = BdkStore::<EnvelopeV1<C>>::open(STORE_MAGIC, path)?;
+        let aggregate = aggregate_entries(&mut store, path)?;
+        let header = aggregate
+            .header
+            .map(|h| StoreHeader::new(h.network, &h.descriptor_checksum));
+        Ok((header, aggregate.changeset))
+    }
+
+    // Read the aggregate of the changesets, leaving the file positioned after the last one.
+    fn read_changesets(&mut self) -> Result<Envelope<C>, Box<dyn error::Error>> {
+        aggregate_entries(&mut self.store, &self.path)
+    }
+
+    // Read the aggregate of the changesets, checking the store is the one expected.
+    fn read_checked(&mut self) -> Result<C, Box<dyn error::Error>> {
+        let aggregate = self.read_changesets()?;
+        let header = aggregate
+            .header
+            .as_ref()
+            .ok_or_else(|| format!("The store at {} has no header.", self.path.display()))?;
+        self.header.check(header, &self.path)?;
+        aggregate
+            .open(self.cipher.as_ref())
+            .map_err(|e| format!("Error reading {}: '{}'", self.path.display(), e).into())
+    }
+
+    fn needs_compaction(&self) -> Result<bool, Box<dyn error::Error>> {
+        let size = self.file.metadata()?.len();
+        Ok(size > COMPACT_MIN_SIZE.max(2 * self.compacted_size))
+    }
+
+    // Atomically replace the file with one containing only this changeset.
+    fn rewrite(&mut self, aggregate: &C) -> Result<(), Box<dyn error::Error>> {
+        write_store(&self.path, &self.header, aggregate, self.cipher.as_ref())?;
+        // Appends happen after the last changeset.
+        let mut store = BdkStore::open(STORE_MAGIC, &self.path)?;
+        for cs in store.iter_changesets() {
+            cs?;
+        }
+        self.store = store;
+        self.file = File::open(&self.path)?;
+        self.compacted_size = self.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+impl<C> Backend<C> for FileBackend<C>
+where
+    C: Merge + Default + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn load(&mut self) -> Result<C, Box<dyn error::Error>> {
+        let aggregate = self.read_checked()?;
+        if self.needs_compaction()? {
+            println!("Compacting {}.", self.path.display());
+            self.rewrite(&aggregate)?;
+        }
+        Ok(aggregate)
+    }
+
+    fn write(&mut self, changeset: &C) -> Result<(), Box<dyn error::Error>> {
+        self.store
+            .append_changeset(&Envelope::new(None, changeset, self.cipher.as_ref())?)?;
+        self.file.sync_data()?;
+        // The changes are on disk already, failing to compact only leaves the file as it is.
+        let compacted = match self.needs_compaction() {
+            Ok(true) => self.compact().map(Some),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match compacted {
+            Ok(Some((before, after))) => println!(
+                "Compacted {} from {} to {} bytes.",
+                self.path.display(),
+                before,
+                after
+            ),
+            Ok(None) => {}
+            Err(e) => eprintln!("Error compacting {}: '{}'", self.path.display(), e),
+        }
+        Ok(())
+    }
+}
+
+/// The header of a file store and its changesets, up to the first one which can't be read.
+pub type StoreContent<C> = (Option<StoreHeader>, Vec<Result<C, String>>);
+
+/// The header and the changesets of the file store at this path, read without modifying it.
+/// Reading stops at the first changeset which can't be read, returned as an error. Stores from
+/// before the header was introduced don't have one. An encrypted store is read with this key.
+pub fn read_store<C>(
+    path: &Path,
+    key: Option<&StoreKey>,
+) -> Result<StoreContent<C>, Box<dyn error::Error>>
+where
+    C: Merge + Default + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    match store_version(path)? {
+        0 => {
+            let store = BdkStore::<C>::open(LEGACY_STORE_MAGIC, path)?;
+            Ok((None, read_entries(store, Ok)))
+        }
+        1 => {
+            let mut header = None;
+            let store = BdkStore::<EnvelopeV1<C>>::open(STORE_MAGIC, path)?;
+            let changesets = read_entries(store, |entry| {
+                if let Some(h) = entry.header {
+                    header = Some(StoreHeader {
+                        version: h.version,
+                        network: h.network,
+                        descriptor_checksum: h.descriptor_checksum,
+                        encryption: None,
+                    });
+                }
+                Ok(entry.changeset)
+            });
+            Ok((header, changesets))
+        }
+        _ => {
+            let mut header: Option<StoreHeader> = None;
+            let mut cipher = None;
+            let store = BdkStore::<Envelope<C>>::open(STORE_MAGIC, path)?;
+            let changesets = read_entries(store, |mut entry| {
+                if let Some(h) = entry.header.take() {
+                    if let Some(params) = &h.encryption {
+                        let key =
+                            key.ok_or("The store is encrypted, a key is needed to read it.")?;
+                        cipher = Some(key.open_cipher(params, &h.aad())?);
+                    }
+                    header = Some(h);
+                }
+                entry.open(cipher.as_ref())
+            });
+            Ok((header, changesets))
+        }
+    }
+}
+
+// Read the entries of this store and convert them to changesets, until one can't be.
+fn read_entries<E, C>(
+    mut store: BdkStore<E>,
+    mut convert: impl FnMut(E) -> Result<C, Box<dyn error::Error>>,
+) -> Vec<Result<C, String>>
+where
+    E: Merge + Serialize + DeserializeOwned + Send + Sync,
+{
+    let mut changesets = Vec::new();
+    for entry in store.iter_changesets() {
+        let cs = match entry {
+            Ok(entry) => convert(entry).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let failed = cs.is_err();
+        changesets.push(cs);
+        if failed {
+            break;
+        }
+    }
+    changesets
+}
+
+// The aggregate of the entries of this store at this path. A batch is appended at once, so only
+// the last entry may have been partially written by a crash: an entry cut short by the end of the
+// file is discarded, and overwritten by the next append. Any other entry which can't be read is an
+// error, as the entries after it would be overwritten.
+fn aggregate_entries<E>(store: &mut BdkStore<E>, path: &Path) -> Result<E, Box<dyn error::Error>>
+where
+    E: Merge + Default + Serialize + DeserializeOwned + Send + Sync,
+{
+    let mut aggregate = E::default();
+    for entry in store.iter_changesets() {
+        match entry {
+            Ok(entry) => aggregate.merge(entry),
+            Err(IterError::Bincode(bincode::ErrorKind::Io(e)))
+                if e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                eprintln!(
+                    "Discarding a partially written changeset at the end of {}: '{}'",
+                    path.display(),
+                    e
+                );
+            }
+            Err(e) => return Err(format!("Error reading {}: '{}'", path.display(), e).into()),
+        }
+    }
+    Ok(aggregate)
+}
+
+// The version of the file store at this path.
+fn store_version(path: &Path) -> Result<u32, Box<dyn error::Error>> {
+    let mut magic = Vec::new();
+    File::open(path)?
+        .take(STORE_MAGIC.len().max(LEGACY_STORE_MAGIC.len()) as u64)
+        .read_to_end(&mut magic)?;
+    if magic.starts_with(STORE_MAGIC) {
+        let mut store = BdkStore::<VersionProbe>::open(STORE_MAGIC, path)?;
+        let version = store
+            .iter_changesets()
+            .next()
+            .transpose()?
+            .and_then(|probe| probe.version)
+            .ok_or_else(|| format!("The store at {} has no header.", path.display()))?;
+        Ok(version)
+    } else if magic.starts_with(LEGACY_STORE_MAGIC) {
+        Ok(0)
+    } else {
+        Err(format!("{} is not a wallet store.", path.display()).into())
+    }
+}
+
+// Atomically write a store at this path with only this header and changeset, sealed if there's a
+// cipher: it's written to a temporary file which is then renamed over the path.
+fn write_store<C>(
+    path: &Path,
+    header: &StoreHeader,
+    changeset: &C,
+    cipher: Option<&StoreCipher>,
+) -> Result<(), Box<dyn error::Error>>
+where
+    C: Merge + Default + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    let tmp_path = path_with_suffix(path, ".tmp");
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    BdkStore::create_new(STORE_MAGIC, &tmp_path)?.append_changeset(&Envelope::new(
+        Some(header.clone()),
+        changeset,
+        cipher,
+    )?)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // Make sure the rename itself is on disk.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// A small store next to the wallet's store, such as its settings. Its changesets are appended to
+/// a file in clear, or if the wallet's store is encrypted, their aggregate is sealed with the same
+/// key and the file rewritten on every change.
+pub enum SideStore<C>
+where
+    C: Send + Sync,
+{
+    Clear(BdkStore<C>),
+    Sealed {
+        path: PathBuf,
+        sealer: FileSealer,
+        aggregate: C,
+    },
+}
+
+impl<C> SideStore<C>
+where
+    C: Merge + Default + Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    /// Open the store at this path, creating it if it doesn't exist. Returns the aggregate of its
+    /// changesets along with it. Its magic bytes also identify its sealed content.
+    ///
+    /// A store written in clear is sealed if there's a key. A sealed store needs its key.
+    pub fn open(
+        path: &Path,
+        magic: &[u8],
+        key: Option<&StoreKey>,
+    ) -> Result<(Self, C), Box<dyn error::Error>> {
+        if path.exists() && store_crypto::is_sealed(path)? {
+            let (sealer, aggregate) = FileSealer::open::<C>(path, key, magic)?;
+            let store = Self::Sealed {
+                path: path.to_path_buf(),
+                sealer,
+                aggregate: aggregate.clone(),
+            };
+            return Ok((store, aggregate));
+        }
+        let mut store = BdkStore::<C>::open_or_create_new(magic, path)?;
+        let aggregate = aggregate_entries(&mut store, path)?;
+        let Some(key) = key else {
+            return Ok((Self::Clear(store), aggregate));
+        };
+        let sealer = FileSealer::new(key, magic)?;
+        sealer.write(path, &aggregate)?;
+        let store = Self::Sealed {
+            path: path.to_path_buf(),
+            sealer,
+            aggregate: aggregate.clone(),
+        };
+        Ok((store, aggregate))
+    }
+
+    /// Persist this changeset.
+    pub fn append(&mut self, changeset: &C) -> Result<(), Box<dyn error::Error>> {
+        match self {
+            Self::Clear(store) => store.append_changeset(changeset)?,
+            Self::Sealed {
+                path,
+                sealer,
+                aggregate,
+            } => {
+                let mut merged = aggregate.clone();
+                merged.merge(changeset.clone());
+                sealer.write(path, &merged)?;
+                *aggregate = merged;
+            }
+        }
+        Ok(())
+    }
+
+    /// Seal the store at this path with `new_key` instead of `key`, or write it in clear if
+    /// there's none.
+    pub fn rotate_key(
+        path: &Path,
+        magic: &[u8],
+        key: Option<&StoreKey>,
+        new_key: Option<&StoreKey>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let (_, aggregate) = Self::open(path, magic, key)?;
+        if let Some(new_key) = new_key {
+            return FileSealer::new(new_key, magic)?.write(path, &aggregate);
+        }
+        let tmp_path = path_with_suffix(path, ".tmp");
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        BdkStore::create_new(magic, &tmp_path)?.append_changeset(&aggregate)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// A store which stages changes in memory and writes them in batches.
+pub struct BatchedStore<C> {
+    backend: Box<dyn Backend<C>>,
+    staged: C,
+    staged_blocks: usize,
+}
+
+impl<C: Merge + Default> BatchedStore<C> {
+    /// Stage changes to be written to this backend. Returns the aggregate of the changes it
+    /// already contains along with it.
+    pub fn new(mut backend: Box<dyn Backend<C>>) -> Result<(Self, C), Box<dyn error::Error>> {
+        let aggregate = backend.load()?;
+        let batched = Self {
+            backend,
+            staged: C::default(),
+            staged_blocks: 0,
+        };
+        Ok((batched, aggregate))
+    }
+
+    /// Stage these changes, to be written with the next batch.
+    pub fn stage(&mut self, changeset: C) {
+        self.staged.merge(changeset);
+    }
+
+    /// Stage the changes from processing a block. Write the batch if it's full.
+    pub fn stage_block(&mut self, changeset: C) -> Result<(), Box<dyn error::Error>> {
+        self.stage(changeset);
+        self.staged_blocks += 1;
+        if self.staged_blocks >= PERSIST_BATCH_BLOCKS {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    /// Stage these changes and write them right away, along with the rest of the batch.
+    pub fn commit(&mut self, changeset: C) -> Result<(), Box<dyn error::Error>> {
+        self.stage(changeset);
+        self.write()
+    }
+
+    /// Write the staged changes to the store and sync it to disk. On failure they stay staged.
+    pub fn write(&mut self) -> Result<(), Box<dyn error::Error>> {
+        if !self.staged.is_empty() {
+            self.backend.write(&self.staged)?;
+            self.staged = C::default();
+        }
+        self.staged_blocks = 0;
+        Ok(())
+    }
+
+    /// Queries on the written changes, if the backend supports them.
+    pub fn queries(&self) -> Option<&dyn Queries> {
+        self.backend.queries()
+    }
+}
@@ -0,0 +1,229 @@
+//! The spending paths of the wallet's descriptors, as extracted from their miniscript policy, and
+//! the compilation of policies into descriptors.
+//!
+//! A spending path is one way of satisfying a descriptor: a set of keys which must all sign, the
+//! preimages which must be revealed and the time locks which must have expired. A vault
+//! descriptor for instance has a path for its hot key alone, and another path for its recovery key
+//! once the coin is old enough.
+
+use bdk_chain::{
+    bitcoin::{absolute, relative},
+    miniscript::{
+        policy::{Concrete, Liftable, Semantic},
+        DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, MiniscriptKey, Segwitv0,
+    },
+    ConfirmationBlockTime,
+};
+
+use std::{error, str::FromStr};
+
+// The x-only key from BIP 341 which nobody knows the private key of, used as the internal key of
+// taproot descriptors compiled from policies which can't be satisfied by a single key.
+const UNSPENDABLE_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// One way of satisfying a descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendingPath<Pk> {
+    /// The keys which must all sign.
+    pub keys: Vec<Pk>,
+    /// The hashes whose preimage must be revealed, such as `sha256(<hash>)`.
+    pub hashes: Vec<String>,
+    /// The height or time from which the spending transaction can be included in a block.
+    pub after: Option<absolute::LockTime>,
+    /// The number of blocks or time since the coin was confirmed before it can be spent.
+    pub older: Option<relative::LockTime>,
+}
+
+impl<Pk> Default for SpendingPath<Pk> {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            hashes: Vec::new(),
+            after: None,
+            older: None,
+        }
+    }
+}
+
+impl<Pk: Clone + PartialEq> SpendingPath<Pk> {
+    /// The path requiring the conditions of both these paths, unless their time locks can't be
+    /// combined (one being a height and the other a time).
+    fn and(&self, other: &Self) -> Option<Self> {
+        let mut keys = self.keys.clone();
+        keys.extend(
+            other
+                .keys
+                .iter()
+                .filter(|k| !self.keys.contains(k))
+                .cloned(),
+        );
+        let mut hashes = self.hashes.clone();
+        hashes.extend(
+            other
+                .hashes
+                .iter()
+                .filter(|h| !self.hashes.contains(h))
+                .cloned(),
+        );
+        let after = match (self.after, other.after) {
+            (Some(a), Some(b)) if a.is_implied_by(b) => Some(b),
+            (Some(a), Some(b)) if b.is_implied_by(a) => Some(a),
+            (Some(_), Some(_)) => return None,
+            (a, b) => a.or(b),
+        };
+        let older = match (self.older, other.older) {
+            (Some(a), Some(b)) if a.is_implied_by(b) => Some(b),
+            (Some(a), Some(b)) if b.is_implied_by(a) => Some(a),
+            (Some(_), Some(_)) => return None,
+            (a, b) => a.or(b),
+        };
+        Some(Self {
+            keys,
+            hashes,
+            after,
+            older,
+        })
+    }
+}
+
+impl<Pk> SpendingPath<Pk> {
+    /// Whether the time locks of this path are met for a coin with this confirmation (if
+    /// confirmed), by a transaction included in the block after this tip.
+    ///
+    /// Time locks are compared with this time and the time of the block confirming the coin. The
+    /// network uses the median time of the last 11 blocks instead, which lags about an hour behind:
+    /// a transaction spending through a path which just expired may take as long to be accepted.
+    pub fn is_unlocked(
+        &self,
+        tip_height: u32,
+        time: u64,
+        confirmation: Option<&ConfirmationBlockTime>,
+    ) -> bool {
+        let after = match self.after {
+            None => true,
+            Some(absolute::LockTime::Blocks(height)) => tip_height >= height.to_consensus_u32(),
+            Some(absolute::LockTime::Seconds(lock)) => time > u64::from(lock.to_consensus_u32()),
+        };
+        let older = match (self.older, confirmation) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(relative::LockTime::Blocks(blocks)), Some(conf)) => {
+                (tip_height + 1).saturating_sub(conf.block_id.height) >= u32::from(blocks.value())
+            }
+            (Some(relative::LockTime::Time(intervals)), Some(conf)) => {
+                time >= conf.confirmation_time + 512 * u64::from(intervals.value())
+            }
+        };
+        after && older
+    }
+}
+
+impl SpendingPath<DescriptorPublicKey> {
+    /// This path of a ranged descriptor, with its keys derived at this index.
+    pub fn at_derivation_index(
+        &self,
+        index: u32,
+    ) -> Result<SpendingPath<DefiniteDescriptorKey>, Box<dyn error::Error>> {
+        Ok(SpendingPath {
+            keys: self
+                .keys
+                .iter()
+                .map(|key| key.clone().at_derivation_index(index))
+                .collect::<Result<_, _>>()?,
+            hashes: self.hashes.clone(),
+            after: self.after,
+            older: self.older,
+        })
+    }
+}
+
+/// The spending paths of this descriptor, in the order of its lifted (normalized) policy. For a
+/// ranged descriptor the order is the same at every derivation index. Paths through the
+/// unspendable key of BIP 341 are left out.
+pub fn spending_paths(
+    descriptor: &Descriptor<DescriptorPublicKey>,
+) -> Result<Vec<SpendingPath<DescriptorPublicKey>>, Box<dyn error::Error>> {
+    let unspendable = DescriptorPublicKey::from_str(UNSPENDABLE_KEY)?;
+    let mut paths = policy_paths(&descriptor.lift()?);
+    paths.retain(|path| !path.keys.contains(&unspendable));
+    Ok(paths)
+}
+
+// All the combinations of conditions satisfying this policy, without duplicates.
+fn policy_paths<Pk: MiniscriptKey>(policy: &Semantic<Pk>) -> Vec<SpendingPath<Pk>> {
+    let hash = |hash: String| {
+        vec![SpendingPath {
+            hashes: vec![hash],
+            ..Default::default()
+        }]
+    };
+    match policy {
+        Semantic::Unsatisfiable => Vec::new(),
+        Semantic::Trivial => vec![SpendingPath::default()],
+        Semantic::Key(key) => vec![SpendingPath {
+            keys: vec![key.clone()],
+            ..Default::default()
+        }],
+        Semantic::After(lock) => vec![SpendingPath {
+            after: Some((*lock).into()),
+            ..Default::default()
+        }],
+        Semantic::Older(lock) => vec![SpendingPath {
+            older: Some((*lock).into()),
+            ..Default::default()
+        }],
+        Semantic::Sha256(h) => hash(format!("sha256({})", h)),
+        Semantic::Hash256(h) => hash(format!("hash256({})", h)),
+        Semantic::Ripemd160(h) => hash(format!("ripemd160({})", h)),
+        Semantic::Hash160(h) => hash(format!("hash160({})", h)),
+        Semantic::Thresh(thresh) => {
+            let subs: Vec<_> = thresh.iter().map(|sub| policy_paths(sub)).collect();
+            let mut paths: Vec<SpendingPath<Pk>> = Vec::new();
+            for combination in combinations(subs.len(), thresh.k()) {
+                let mut combined = vec![SpendingPath::default()];
+                for i in combination {
+                    combined = combined
+                        .iter()
+                        .flat_map(|path| subs[i].iter().filter_map(|sub| path.and(sub)))
+                        .collect();
+                }
+                for path in combined {
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+            paths
+        }
+    }
+}
+
+// The subsets of k indexes out of n, each in increasing order.
+fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![Vec::new()];
+    }
+    (k - 1..n)
+        .flat_map(|last| {
+            combinations(last, k - 1).into_iter().map(move |mut c| {
+                c.push(last);
+                c
+            })
+        })
+        .collect()
+}
+
+/// Compile a policy (such as `or(99@pk(<hot key>),and(pk(<recovery key>),older(4032)))`) into a
+/// P2WSH descriptor, or a taproot one whose internal key is the most likely key to sign alone.
+pub fn compile_policy(
+    policy: &str,
+    taproot: bool,
+) -> Result<Descriptor<DescriptorPublicKey>, Box<dyn error::Error>> {
+    let policy = Concrete::<DescriptorPublicKey>::from_str(policy)?;
+    if taproot {
+        let unspendable = DescriptorPublicKey::from_str(UNSPENDABLE_KEY)?;
+        Ok(policy.compile_tr(Some(unspendable))?)
+    } else {
+        Ok(Descriptor::new_wsh(policy.compile::<Segwitv0>()?)?)
+    }
+}
@@ -0,0 +1,851 @@
+//! Wallet RPC commands, independent of the JSON-RPC server they are exposed through.
+
+use bdk_chain::{
+    bitcoin::{self, absolute, relative, secp256k1::Secp256k1, Amount, FeeRate, OutPoint, Psbt},
+    miniscript::DefiniteDescriptorKey,
+};
+use serde_json::{json, Value};
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    policy::SpendingPath,
+    rpc_interface::NodeChain,
+    signer::{self, PsbtRole},
+    wallet::{
+        BdkWallet, CoinControl, CreatePsbtError, PathChoice, TxFilter, UtxoLock, PRIMARY_KEYCHAIN,
+    },
+    wallet_manager::WalletManager,
+    NETWORK,
+};
+
+// Error codes, as used by Bitcoin Core's JSON-RPC server.
+pub const RPC_MISC_ERROR: i64 = -1;
+pub const RPC_TYPE_ERROR: i64 = -3;
+pub const RPC_WALLET_ERROR: i64 = -4;
+pub const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+pub const RPC_INVALID_PARAMETER: i64 = -8;
+pub const RPC_WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+pub const RPC_DESERIALIZATION_ERROR: i64 = -22;
+pub const RPC_VERIFY_ERROR: i64 = -25;
+pub const RPC_WALLET_NOT_FOUND: i64 = -18;
+pub const RPC_WALLET_NOT_SPECIFIED: i64 = -19;
+
+/// An error returned to the JSON-RPC client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The error object of a JSON-RPC response.
+    pub fn to_json(&self) -> Value {
+        json!({"code": self.code, "message": self.message})
+    }
+}
+
+/// A command operating on a single wallet, or loading and unloading wallets. Its arguments are
+/// passed by position, in the order of [`WalletCommand::args`].
+pub struct WalletCommand {
+    pub name: &'static str,
+    /// Names of the (optional) arguments.
+    pub args: &'static [&'static str],
+    handler: Handler,
+}
+
+enum Handler {
+    /// Run against the requested wallet.
+    Wallet(fn(&mut BdkWallet, &[Value]) -> Result<Value, RpcError>),
+    /// Run against the manager, given the wallet name of the request if any.
+    Manager(fn(&mut WalletManager, Option<&str>, &[Value]) -> ManagerResponse),
+}
+
+/// The response to a command run against the manager, along with the wallet to load if any. It's
+/// synced with the node before it's loaded.
+type ManagerResponse = Result<(Value, Option<BdkWallet>), RpcError>;
+
+/// All the wallet commands.
+pub const WALLET_COMMANDS: &[WalletCommand] = &[
+    WalletCommand {
+        name: "getbalance",
+        args: &[],
+        handler: Handler::Wallet(getbalance),
+    },
+    WalletCommand {
+        name: "getbalances",
+        args: &[],
+        handler: Handler::Wallet(getbalances),
+    },
+    WalletCommand {
+        name: "getnewaddress",
+        args: &[],
+        handler: Handler::Wallet(getnewaddress),
+    },
+    WalletCommand {
+        name: "listunspent",
+        args: &["minconf", "maxconf"],
+        handler: Handler::Wallet(listunspent),
+    },
+    WalletCommand {
+        name: "listspendingpaths",
+        args: &[],
+        handler: Handler::Wallet(listspendingpaths),
+    },
+    WalletCommand {
+        name: "listtransactions",
+        args: &[
+            "count",
+            "skip",
+            "min_height",
+            "max_height",
+            "min_time",
+            "max_time",
+        ],
+        handler: Handler::Wallet(listtransactions),
+    },
+    WalletCommand {
+        name: "getstoredtransaction",
+        args: &["txid"],
+        handler: Handler::Wallet(getstoredtransaction),
+    },
+    WalletCommand {
+        name: "liststoredtransactions",
+        args: &["height"],
+        handler: Handler::Wallet(liststoredtransactions),
+    },
+    WalletCommand {
+        name: "listaddresses",
+        args: &[],
+        handler: Handler::Wallet(listaddresses),
+    },
+    WalletCommand {
+        name: "createpsbt",
+        args: &[
+            "outputs",
+            "fee_rate",
+            "spending_path",
+            "keychain",
+            "include",
+            "exclude",
+            "lock",
+            "lock_expires",
+        ],
+        handler: Handler::Wallet(createpsbt),
+    },
+    WalletCommand {
+        name: "lockunspent",
+        args: &["unlock", "transactions", "reason", "expires"],
+        handler: Handler::Wallet(lockunspent),
+    },
+    WalletCommand {
+        name: "listlockunspent",
+        args: &[],
+        handler: Handler::Wallet(listlockunspent),
+    },
+    WalletCommand {
+        name: "combinepsbt",
+        args: &["txs"],
+        handler: Handler::Wallet(combinepsbt),
+    },
+    WalletCommand {
+        name: "analyzepsbt",
+        args: &["psbt"],
+        handler: Handler::Wallet(analyzepsbt),
+    },
+    WalletCommand {
+        name: "finalizepsbt",
+        args: &["psbt"],
+        handler: Handler::Wallet(finalizepsbt),
+    },
+    WalletCommand {
+        name: "loadwallet",
+        args: &["filename"],
+        handler: Handler::Manager(loadwallet),
+    },
+    WalletCommand {
+        name: "createwallet",
+        args: &["wallet_name", "descriptor"],
+        handler: Handler::Manager(createwallet),
+    },
+    WalletCommand {
+        name: "unloadwallet",
+        args: &["wallet_name"],
+        handler: Handler::Manager(unloadwallet),
+    },
+];
+
+/// The wallet name requested through the `/wallet/<name>` endpoint, if any.
+pub fn wallet_name_from_uri(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("/wallet/")?;
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The name of the wallet a request is for. If no name is given and a single wallet is loaded, use
+/// it.
+pub fn requested_wallet(
+    manager: &WalletManager,
+    wallet_name: Option<&str>,
+) -> Result<String, RpcError> {
+    match wallet_name {
+        Some(name) => Ok(name.to_string()),
+        None => {
+            let mut names = manager.names();
+            if names.len() != 1 {
+                return Err(RpcError::new(
+                    RPC_WALLET_NOT_SPECIFIED,
+                    "Wallet file not specified (must request wallet RPC through /wallet/<filename> uri-path).",
+                ));
+            }
+            Ok(names.remove(0))
+        }
+    }
+}
+
+impl WalletCommand {
+    /// Run this command against the wallet with this name. If no name is given and a single
+    /// wallet is loaded, use it. The parameters are either a list of positional arguments or an
+    /// object of named arguments.
+    ///
+    /// A wallet loaded by the command is synced with the node, then served the notifications of
+    /// the manager's subscription along with the other loaded wallets.
+    pub async fn call(
+        &self,
+        chain: &NodeChain,
+        manager: &Arc<Mutex<WalletManager>>,
+        wallet_name: Option<&str>,
+        params: &Value,
+    ) -> Result<Value, RpcError> {
+        let params = self.positional_params(params)?;
+        let (response, to_load) = {
+            let mut manager = manager.lock().unwrap();
+            match self.handler {
+                Handler::Wallet(handler) => {
+                    let wallet_name = requested_wallet(&manager, wallet_name)?;
+                    let wallet = manager.wallet_mut(&wallet_name).ok_or_else(|| {
+                        RpcError::new(
+                            RPC_WALLET_NOT_FOUND,
+                            format!("Requested wallet '{}' is not loaded.", wallet_name),
+                        )
+                    })?;
+                    (handler(wallet, &params)?, None)
+                }
+                Handler::Manager(handler) => handler(&mut manager, wallet_name, &params)?,
+            }
+        };
+        if let Some(wallet) = to_load {
+            crate::load_wallets(chain, manager, vec![wallet])
+                .await
+                .map_err(wallet_error)?;
+        }
+        Ok(response)
+    }
+
+    fn positional_params(&self, params: &Value) -> Result<Vec<Value>, RpcError> {
+        let params = match params {
+            Value::Null => Vec::new(),
+            Value::Array(params) => params.clone(),
+            Value::Object(named) => {
+                if let Some(unknown) = named.keys().find(|k| !self.args.contains(&k.as_str())) {
+                    return Err(RpcError::new(
+                        RPC_INVALID_PARAMETER,
+                        format!("Unknown named parameter {}", unknown),
+                    ));
+                }
+                let mut params: Vec<_> = self
+                    .args
+                    .iter()
+                    .map(|arg| named.get(*arg).cloned().unwrap_or(Value::Null))
+                    .collect();
+                while params.last() == Some(&Value::Null) {
+                    params.pop();
+                }
+                params
+            }
+            _ => {
+                return Err(RpcError::new(
+                    RPC_TYPE_ERROR,
+                    "Params must be an array or an object.",
+                ))
+            }
+        };
+        if params.len() > self.args.len() {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                format!("Too many parameters for {}.", self.name),
+            ));
+        }
+        Ok(params)
+    }
+}
+
+// The optional integer parameter at this position, or its default value.
+fn opt_u32(params: &[Value], pos: usize, default: u32) -> Result<u32, RpcError> {
+    match params.get(pos) {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => value
+            .as_u64()
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| {
+                RpcError::new(
+                    RPC_TYPE_ERROR,
+                    format!("Expected a positive integer, got {}.", value),
+                )
+            }),
+    }
+}
+
+// The optional integer parameter at this position, if given.
+fn maybe_u32(params: &[Value], pos: usize) -> Result<Option<u32>, RpcError> {
+    match params.get(pos) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => opt_u32(params, pos, 0).map(Some),
+    }
+}
+
+// The string parameter at this position.
+fn str_param(params: &[Value], pos: usize, name: &str) -> Result<String, RpcError> {
+    match params.get(pos) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        None | Some(Value::Null) => Err(RpcError::new(
+            RPC_INVALID_PARAMETER,
+            format!("Missing parameter {}.", name),
+        )),
+        Some(value) => Err(RpcError::new(
+            RPC_TYPE_ERROR,
+            format!("Expected a string for {}, got {}.", name, value),
+        )),
+    }
+}
+
+// The optional array of `{"txid": .., "vout": ..}` objects at this position, as in Core.
+fn outpoints_param(params: &[Value], pos: usize) -> Result<Option<Vec<OutPoint>>, RpcError> {
+    let outpoints = match params.get(pos) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Array(outpoints)) => outpoints,
+        Some(value) => {
+            return Err(RpcError::new(
+                RPC_TYPE_ERROR,
+                format!("Expected an array of outpoints, got {}.", value),
+            ))
+        }
+    };
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            let txid = outpoint["txid"].as_str().and_then(|t| t.parse().ok());
+            let vout = outpoint["vout"].as_u64().and_then(|v| v.try_into().ok());
+            match (txid, vout) {
+                (Some(txid), Some(vout)) => Ok(OutPoint { txid, vout }),
+                _ => Err(RpcError::new(
+                    RPC_INVALID_PARAMETER,
+                    format!("Invalid outpoint {}, expected a txid and a vout.", outpoint),
+                )),
+            }
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Parse a base64 encoded PSBT.
+pub fn parse_psbt(psbt: &str) -> Result<Psbt, RpcError> {
+    Psbt::from_str(psbt)
+        .map_err(|e| RpcError::new(RPC_DESERIALIZATION_ERROR, format!("TX decode failed {}", e)))
+}
+
+fn wallet_error(e: impl ToString) -> RpcError {
+    RpcError::new(RPC_WALLET_ERROR, e.to_string())
+}
+
+// Load a wallet from the data directory, as created by `createwallet` or `--create-wallet`.
+fn loadwallet(manager: &mut WalletManager, _: Option<&str>, params: &[Value]) -> ManagerResponse {
+    let name = str_param(params, 0, "filename")?;
+    let wallet = manager.open_wallet(&name).map_err(wallet_error)?;
+    Ok((json!({"name": name}), Some(wallet)))
+}
+
+// Create a wallet tracking this descriptor, and load it.
+fn createwallet(manager: &mut WalletManager, _: Option<&str>, params: &[Value]) -> ManagerResponse {
+    let name = str_param(params, 0, "wallet_name")?;
+    let descriptor = str_param(params, 1, "descriptor")?;
+    let wallet = manager
+        .create_wallet(&name, &descriptor)
+        .map_err(wallet_error)?;
+    Ok((json!({"name": name}), Some(wallet)))
+}
+
+// Unload the wallet with this name, or else the one requested, once its changes are written.
+fn unloadwallet(
+    manager: &mut WalletManager,
+    wallet_name: Option<&str>,
+    params: &[Value],
+) -> ManagerResponse {
+    let name = match params.first() {
+        None | Some(Value::Null) => requested_wallet(manager, wallet_name)?,
+        Some(_) => str_param(params, 0, "wallet_name")?,
+    };
+    if wallet_name.is_some_and(|requested| requested != name) {
+        return Err(RpcError::new(
+            RPC_INVALID_PARAMETER,
+            "RPC endpoint wallet and wallet_name parameter specify different wallets",
+        ));
+    }
+    let wallet = manager.wallet_mut(&name).ok_or_else(|| {
+        RpcError::new(
+            RPC_WALLET_NOT_FOUND,
+            format!("Requested wallet '{}' is not loaded.", name),
+        )
+    })?;
+    wallet.persist().map_err(wallet_error)?;
+    manager.unload(&name);
+    Ok((Value::Null, None))
+}
+
+fn getbalance(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    Ok(json!(wallet.balance().trusted_spendable().to_btc()))
+}
+
+// The balance broken down as in Core's getbalances, along with the confirmed and trusted pending
+// parts of the trusted balance.
+fn getbalances(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    let balance = wallet.balance();
+    Ok(json!({
+        "mine": {
+            "trusted": balance.trusted_spendable().to_btc(),
+            "untrusted_pending": balance.untrusted_pending.to_btc(),
+            "immature": balance.immature.to_btc(),
+            "confirmed": balance.confirmed.to_btc(),
+            "trusted_pending": balance.trusted_pending.to_btc(),
+        },
+    }))
+}
+
+fn getnewaddress(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    let address = wallet.next_address().map_err(wallet_error)?;
+    Ok(json!(address.to_string()))
+}
+
+fn listunspent(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let minconf = opt_u32(params, 0, 1)?;
+    let maxconf = opt_u32(params, 1, 9_999_999)?;
+    let tip_height = wallet.tip().height;
+    let utxos = wallet
+        .utxos()
+        .into_iter()
+        .filter_map(|utxo| {
+            let confirmations = match utxo.chain_position {
+                bdk_chain::ChainPosition::Confirmed(anchor) => {
+                    tip_height + 1 - anchor.block_id.height
+                }
+                bdk_chain::ChainPosition::Unconfirmed(_) => 0,
+            };
+            if confirmations < minconf || confirmations > maxconf {
+                return None;
+            }
+            Some(json!({
+                "txid": utxo.outpoint.txid.to_string(),
+                "vout": utxo.outpoint.vout,
+                "address": wallet.address(&utxo.txout.script_pubkey).map(|a| a.to_string()),
+                "scriptPubKey": utxo.txout.script_pubkey.to_hex_string(),
+                "amount": utxo.txout.value.to_btc(),
+                "confirmations": confirmations,
+            }))
+        })
+        .collect();
+    Ok(Value::Array(utxos))
+}
+
+// The spending paths of each unspent coin, and whether their time locks are met now.
+fn listspendingpaths(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    let coins = wallet
+        .utxo_spending_paths(now())
+        .map_err(wallet_error)?
+        .into_iter()
+        .map(|coin| {
+            let paths: Vec<_> = coin
+                .paths
+                .iter()
+                .enumerate()
+                .map(|(index, (path, unlocked))| {
+                    let mut entry = spending_path_json(path);
+                    entry["index"] = json!(index);
+                    entry["unlocked"] = json!(unlocked);
+                    entry
+                })
+                .collect();
+            json!({
+                "txid": coin.utxo.outpoint.txid.to_string(),
+                "vout": coin.utxo.outpoint.vout,
+                "keychain": coin.keychain,
+                "amount": coin.utxo.txout.value.to_btc(),
+                "paths": paths,
+            })
+        })
+        .collect();
+    Ok(Value::Array(coins))
+}
+
+fn spending_path_json(path: &SpendingPath<DefiniteDescriptorKey>) -> Value {
+    let secp = Secp256k1::verification_only();
+    let keys: Vec<_> = path
+        .keys
+        .iter()
+        .map(|key| {
+            json!({
+                "pubkey": key.derive_public_key(&secp).ok().map(|pk| pk.to_string()),
+                "fingerprint": key.master_fingerprint().to_string(),
+                "path": key.full_derivation_path().map(|path| format!("m/{}", path)),
+            })
+        })
+        .collect();
+    let mut entry = json!({"keys": keys, "hashes": path.hashes});
+    match path.after {
+        Some(absolute::LockTime::Blocks(height)) => {
+            entry["after"] = json!({"height": height.to_consensus_u32()})
+        }
+        Some(absolute::LockTime::Seconds(time)) => {
+            entry["after"] = json!({"time": time.to_consensus_u32()})
+        }
+        None => {}
+    }
+    match path.older {
+        Some(relative::LockTime::Blocks(blocks)) => {
+            entry["older"] = json!({"blocks": blocks.value()})
+        }
+        Some(relative::LockTime::Time(intervals)) => {
+            entry["older"] = json!({"seconds": u32::from(intervals.value()) * 512})
+        }
+        None => {}
+    }
+    entry
+}
+
+// The current time, which time locks are checked against.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn listtransactions(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let filter = TxFilter {
+        count: Some(opt_u32(params, 0, 10)? as usize),
+        skip: opt_u32(params, 1, 0)? as usize,
+        min_height: maybe_u32(params, 2)?,
+        max_height: maybe_u32(params, 3)?,
+        min_time: maybe_u32(params, 4)?.map(u64::from),
+        max_time: maybe_u32(params, 5)?.map(u64::from),
+    };
+    let txs = wallet
+        .list_transactions(&filter)
+        .into_iter()
+        .map(|tx| {
+            let mut entry = json!({
+                "txid": tx.txid.to_string(),
+                "amount": tx.net().to_btc(),
+                "sent": tx.sent.to_btc(),
+                "received": tx.received.to_btc(),
+                "confirmations": tx.confirmations,
+                "bip125-replaceable": if tx.rbf { "yes" } else { "no" },
+            });
+            if let Some(fee) = tx.fee {
+                entry["fee"] = json!(fee.to_btc());
+            }
+            // In sat/vB, as the fee rate of createpsbt.
+            if let Some(feerate) = tx.feerate {
+                entry["feerate"] = json!(feerate.to_sat_per_kwu() as f64 / 250.0);
+            }
+            if let Some(conf) = tx.confirmation {
+                entry["blockhash"] = json!(conf.block_id.hash.to_string());
+                entry["blockheight"] = json!(conf.block_id.height);
+                entry["blocktime"] = json!(conf.confirmation_time);
+            }
+            entry
+        })
+        .collect();
+    Ok(Value::Array(txs))
+}
+
+// Look up a transaction in the wallet's store, rather than in its loaded state.
+fn getstoredtransaction(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let txid = bitcoin::Txid::from_str(&str_param(params, 0, "txid")?)
+        .map_err(|e| RpcError::new(RPC_INVALID_PARAMETER, format!("Invalid txid: {}", e)))?;
+    let stored = wallet
+        .stored_transaction(&txid)
+        .map_err(wallet_error)?
+        .ok_or_else(|| {
+            RpcError::new(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "Invalid or non-wallet transaction id",
+            )
+        })?;
+    let mut entry = json!({
+        "txid": txid.to_string(),
+        "hex": bitcoin::consensus::encode::serialize_hex(&stored.tx),
+    });
+    if let Some(block) = stored.confirmation {
+        entry["blockhash"] = json!(block.hash.to_string());
+        entry["blockheight"] = json!(block.height);
+    }
+    if let Some(last_seen) = stored.last_seen {
+        entry["lastseen"] = json!(last_seen);
+    }
+    Ok(entry)
+}
+
+// The txids of the wallet's store confirmed at a height.
+fn liststoredtransactions(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let height = match params.first() {
+        None | Some(Value::Null) => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "Missing parameter height.",
+            ))
+        }
+        Some(_) => opt_u32(params, 0, 0)?,
+    };
+    let txids = wallet
+        .stored_transactions_at_height(height)
+        .map_err(wallet_error)?
+        .into_iter()
+        .map(|txid| json!(txid.to_string()))
+        .collect();
+    Ok(Value::Array(txids))
+}
+
+fn listaddresses(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    let addresses = wallet
+        .revealed_addresses()
+        .into_iter()
+        .map(|(index, address)| json!({"address": address.to_string(), "index": index}))
+        .collect();
+    Ok(Value::Array(addresses))
+}
+
+fn createpsbt(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let outputs = match params.first() {
+        Some(Value::Object(outputs)) if !outputs.is_empty() => outputs,
+        _ => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "Outputs must be a non-empty object of address to amount.",
+            ))
+        }
+    };
+    let mut recipients = Vec::with_capacity(outputs.len());
+    for (address, amount) in outputs {
+        let address = bitcoin::Address::from_str(address)
+            .ok()
+            .and_then(|a| a.require_network(NETWORK).ok())
+            .ok_or_else(|| {
+                RpcError::new(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    format!("Invalid address: {}", address),
+                )
+            })?;
+        let amount = amount
+            .as_f64()
+            .and_then(|a| Amount::from_btc(a).ok())
+            .ok_or_else(|| RpcError::new(RPC_TYPE_ERROR, format!("Invalid amount {}.", amount)))?;
+        if amount > Amount::MAX_MONEY {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                format!("Amount {} is out of range.", amount),
+            ));
+        }
+        recipients.push((address.script_pubkey(), amount));
+    }
+    // In sat/vB, as in Core.
+    let fee_rate = match params.get(1) {
+        None | Some(Value::Null) => FeeRate::from_sat_per_vb_unchecked(1),
+        Some(value) => value
+            .as_f64()
+            .filter(|r| *r >= 0.0)
+            .map(|r| FeeRate::from_sat_per_kwu((r * 250.0).ceil() as u64))
+            .ok_or_else(|| RpcError::new(RPC_TYPE_ERROR, format!("Invalid fee rate {}.", value)))?,
+    };
+    // The index of a spending path among those listed by listspendingpaths.
+    let path = match params.get(2) {
+        None | Some(Value::Null) => None,
+        Some(_) => Some(PathChoice {
+            keychain: opt_u32(params, 3, PRIMARY_KEYCHAIN)?,
+            index: opt_u32(params, 2, 0)? as usize,
+            time: now(),
+        }),
+    };
+    let lock = match params.get(6) {
+        None | Some(Value::Null) => None,
+        Some(_) => Some(UtxoLock {
+            reason: str_param(params, 6, "lock")?,
+            expires: maybe_u32(params, 7)?.map(u64::from),
+        }),
+    };
+    let coins = CoinControl {
+        include: outpoints_param(params, 4)?.unwrap_or_default(),
+        exclude: outpoints_param(params, 5)?.unwrap_or_default(),
+        lock,
+        time: now(),
+    };
+    let psbt = wallet
+        .create_psbt(recipients, fee_rate, path, &coins)
+        .map_err(|e| {
+            let code = match e {
+                CreatePsbtError::InsufficientFunds { .. } => RPC_WALLET_INSUFFICIENT_FUNDS,
+                CreatePsbtError::InvalidParameter(_) => RPC_INVALID_PARAMETER,
+                CreatePsbtError::Wallet(_) => RPC_WALLET_ERROR,
+            };
+            RpcError::new(code, e.to_string())
+        })?;
+    let fee = psbt
+        .fee()
+        .map_err(|e| RpcError::new(RPC_MISC_ERROR, e.to_string()))?;
+    Ok(json!({"psbt": psbt.to_string(), "fee": fee.to_btc()}))
+}
+
+// Lock or unlock coins, like Core's lockunspent. Unlocking without coins unlocks them all. Locks
+// are always persistent, and can be given a reason and an expiry time.
+fn lockunspent(wallet: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let unlock = match params.first() {
+        Some(Value::Bool(unlock)) => *unlock,
+        _ => {
+            return Err(RpcError::new(
+                RPC_TYPE_ERROR,
+                "Expected a boolean for unlock.",
+            ))
+        }
+    };
+    let outpoints = outpoints_param(params, 1)?;
+    if unlock {
+        wallet
+            .unlock_utxos(outpoints.as_deref())
+            .map_err(wallet_error)?;
+    } else {
+        let outpoints = outpoints.ok_or_else(|| {
+            RpcError::new(RPC_INVALID_PARAMETER, "Missing parameter transactions.")
+        })?;
+        let lock = UtxoLock {
+            reason: match params.get(2) {
+                None | Some(Value::Null) => String::new(),
+                Some(_) => str_param(params, 2, "reason")?,
+            },
+            expires: maybe_u32(params, 3)?.map(u64::from),
+        };
+        wallet
+            .lock_utxos(&outpoints, lock)
+            .map_err(|e| RpcError::new(RPC_INVALID_PARAMETER, e.to_string()))?;
+    }
+    Ok(json!(true))
+}
+
+fn listlockunspent(wallet: &mut BdkWallet, _: &[Value]) -> Result<Value, RpcError> {
+    let locks = wallet
+        .locked_utxos(now())
+        .into_iter()
+        .map(|(outpoint, lock)| {
+            json!({
+                "txid": outpoint.txid.to_string(),
+                "vout": outpoint.vout,
+                "reason": lock.reason,
+                "expires": lock.expires,
+            })
+        })
+        .collect();
+    Ok(Value::Array(locks))
+}
+
+// The PSBTs signed by each cosigner, merged into one.
+fn combinepsbt(_: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let psbts = match params.first() {
+        Some(Value::Array(psbts)) if !psbts.is_empty() => psbts,
+        _ => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "Txs must be a non-empty array of base64 PSBTs.",
+            ))
+        }
+    };
+    let psbts = psbts
+        .iter()
+        .map(|psbt| {
+            psbt.as_str()
+                .ok_or_else(|| RpcError::new(RPC_TYPE_ERROR, format!("Invalid PSBT {}.", psbt)))
+                .and_then(parse_psbt)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let combined = signer::combine_psbts(psbts)
+        .map_err(|e| RpcError::new(RPC_INVALID_PARAMETER, e.to_string()))?;
+    Ok(json!(combined.to_string()))
+}
+
+// Which keys signed each input, and what's needed next.
+fn analyzepsbt(_: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    let psbt = parse_psbt(&str_param(params, 0, "psbt")?)?;
+    let inputs = signer::analyze_psbt(&psbt);
+    let next = inputs
+        .iter()
+        .map(|input| input.next)
+        .min()
+        .unwrap_or(PsbtRole::Extractor);
+    let inputs: Vec<_> = inputs
+        .into_iter()
+        .map(|input| {
+            let keys: Vec<_> = input
+                .keys
+                .into_iter()
+                .map(|key| {
+                    json!({
+                        "pubkey": key.pubkey,
+                        "fingerprint": key.source.0.to_string(),
+                        "path": format!("m/{}", key.source.1),
+                        "signed": key.signed,
+                    })
+                })
+                .collect();
+            json!({"next": input.next.to_string(), "keys": keys})
+        })
+        .collect();
+    Ok(json!({"inputs": inputs, "next": next.to_string()}))
+}
+
+fn finalizepsbt(_: &mut BdkWallet, params: &[Value]) -> Result<Value, RpcError> {
+    finalized(parse_psbt(&str_param(params, 0, "psbt")?)?)
+}
+
+/// Finalize the PSBT if its inputs have enough signatures, and extract the transaction if so.
+pub fn finalized(psbt: Psbt) -> Result<Value, RpcError> {
+    let mut finalized = psbt.clone();
+    if signer::finalize_psbt(&mut finalized) {
+        let psbt = finalized.to_string();
+        let tx = finalized
+            .extract_tx()
+            .map_err(|e| RpcError::new(RPC_VERIFY_ERROR, e.to_string()))?;
+        return Ok(json!({
+            "psbt": psbt,
+            "hex": bitcoin::consensus::encode::serialize_hex(&tx),
+            "complete": true,
+        }));
+    }
+    Ok(json!({"psbt": psbt.to_string(), "complete": false}))
+}
@@ -1,29 +1,78 @@
-use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
 use tokio::task::{self, JoinHandle};
-use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
-use std::{sync::{Arc, Mutex}, io::{Error, ErrorKind}};
-// use bdk_chain::{bitcoin, BlockId};
+use tokio_util::{
+    compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt},
+    sync::CancellationToken,
+};
+use std::{
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
 use bdk_chain::{
-    self,
-    bitcoin::{self, consensus::Decodable, hashes::Hash, address::{Address, NetworkUnchecked, AddressType}},
-    keychain_txout::KeychainTxOutIndex,
-    local_chain::LocalChain,
-    miniscript::{Descriptor, DescriptorPublicKey},
-    BlockId, CheckPoint, ConfirmationBlockTime, IndexedTxGraph, Merge,
+    bitcoin::{self, consensus::Decodable, hashes::Hash, address::{Address, AddressType}},
+    BlockId,
+};
+use crate::chain_capnp::{
+    actor_callback::Client as ActorCallbackClient, chain::Client as ChainClient,
+    chain_notifications::Client as ChainNotificationsClient,
 };
-use crate::chain_capnp::{chain::Client as ChainClient};
+use crate::handler_capnp::handler::Client as HandlerClient;
 use crate::init_capnp::init::Client as InitClient;
 use crate::proxy_capnp::thread::Client as ThreadClient;
-use crate::BdkWallet;
+use crate::wallet_manager::WalletManager;
 use std::str::FromStr;
 
+/// How a [`RpcInterface::rescan`] ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescanStatus {
+    /// All the blocks in the range were processed.
+    Completed,
+    /// The rescan was cancelled. Blocks from `next_height` on were not processed.
+    Cancelled { next_height: u32 },
+    /// The block at `height` was reorged out of the wallet's chain during the rescan. Blocks of
+    /// the new best chain are processed through notifications.
+    Reorged { height: u32 },
+}
+
+/// A coin of the node's UTXO set, as returned by [`RpcInterface::find_coins_request`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundCoin {
+    pub outpoint: bitcoin::OutPoint,
+    pub txout: bitcoin::TxOut,
+    /// The height of the block which created it.
+    pub height: u32,
+    /// Whether it was created by a coinbase transaction, in which case it can only be spent once
+    /// it has 100 confirmations.
+    pub is_coinbase: bool,
+}
+
 pub struct RpcInterface {
     pub rpc_handle: JoinHandle<Result<(), capnp::Error>>,
     pub disconnector: capnp_rpc::Disconnector<twoparty::VatId>,
+    pub chain: NodeChain,
+}
+
+/// The node's `Chain` interface, for queries about its block chain. Cheap to clone, so it can be
+/// held by the notification handlers.
+#[derive(Clone)]
+pub struct NodeChain {
     pub thread: ThreadClient,
     pub chain_interface: ChainClient,
 }
 
+// A block hash served by the node.
+fn block_hash(data: &[u8]) -> Result<bitcoin::BlockHash, capnp::Error> {
+    bitcoin::BlockHash::from_slice(data).map_err(|e| capnp::Error::failed(e.to_string()))
+}
+
+impl Deref for RpcInterface {
+    type Target = NodeChain;
+
+    fn deref(&self) -> &NodeChain {
+        &self.chain
+    }
+}
+
 
 impl RpcInterface {
     /// Create an IPC interface by performing the handshake with Bitcoin Core on the provided stream.
@@ -64,20 +113,21 @@ impl RpcInterface {
 
         Ok(Self {
             rpc_handle,
-            thread,
-            chain_interface,
             disconnector,
+            chain: NodeChain {
+                thread,
+                chain_interface,
+            },
         })
     }
-    pub async fn find_coins_request(&self, outpoints: Vec<bitcoin::OutPoint>) -> Vec<(bitcoin::OutPoint, bitcoin::TxOut)> {
+    pub async fn find_coins_request(&self, outpoints: Vec<bitcoin::OutPoint>) -> Result<Vec<FoundCoin>, capnp::Error> {
         println!("DEBUG: Requesting coin information for {} outpoints", outpoints.len());
         let mut find_coins_req = self.chain_interface.find_coins_request();
         
         // Set the thread context
         find_coins_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         
         // Initialize the coins list with the outpoints
@@ -89,21 +139,16 @@ impl RpcInterface {
             let mut outpoint_data = Vec::new();
             outpoint_data.extend_from_slice(&outpoint.txid[..]);
             outpoint_data.extend_from_slice(&outpoint.vout.to_le_bytes());
-            // let data_reader = capnp::data::Reader::FromPointer(*outpoint_data);
-            pair.reborrow().set_key(&outpoint_data[..]);
-            
-            // Set the key (outpoint)
-            // pair.reborrow().set_key(&outpoint_data.as_ref());
-            // pair.reborrow().set_key(data_reader);
+            pair.reborrow().set_key(&outpoint_data[..])?;
             
             // Initialize the value (will be filled by Bitcoin Core)
-            pair.get_value().unwrap();
+            pair.get_value()?;
         }
         
         // Send the request and process the response
-        let response = find_coins_req.send().promise.await.unwrap();
-        let result_data = response.get().unwrap();
-        let coins_result = result_data.get_coins().unwrap();
+        let response = find_coins_req.send().promise.await?;
+        let result_data = response.get()?;
+        let coins_result = result_data.get_coins()?;
         
         let mut result_coins = Vec::with_capacity(coins_result.len() as usize);
         
@@ -111,7 +156,7 @@ impl RpcInterface {
             let pair = coins_result.get(i);
             
             // Get the outpoint from the key
-            let outpoint_bytes = pair.get_key().unwrap();
+            let outpoint_bytes = pair.get_key()?;
             if outpoint_bytes.len() < 36 {
                 println!("WARNING: Invalid outpoint data received");
                 continue;
@@ -128,7 +173,7 @@ impl RpcInterface {
             let outpoint = bitcoin::OutPoint { txid, vout };
             
             // Get the coin data from the value
-            let coin_bytes = pair.get_value().unwrap();
+            let coin_bytes = pair.get_value()?;
             if coin_bytes.is_empty() {
                 println!("DEBUG: Empty coin for outpoint {}:{} (not found/spent)", txid, vout);
                 continue;
@@ -187,37 +232,42 @@ impl RpcInterface {
             println!("Found coin: {}:{} - {} satoshis (height: {}, coinbase: {})",
                      txid, vout, value, height, is_coinbase);
             
-            result_coins.push((outpoint, txout));
+            result_coins.push(FoundCoin {
+                outpoint,
+                txout,
+                height,
+                is_coinbase,
+            });
         }
         
         println!("Found {} coins in mempool/UTXO set", result_coins.len());
-        result_coins
+        Ok(result_coins)
     }
-    // Helper functions for serialization
-  
-    pub async fn get_tip(&self) -> BlockId {
+}
+
+impl NodeChain {
+    pub async fn get_tip(&self) -> Result<BlockId, capnp::Error> {
         println!("DEBUG: Requesting tip");
         let mut height_req = self.chain_interface.get_height_request();
         height_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
-        let response = height_req.send().promise.await.unwrap();
-        let height_i32 = response.get().unwrap().get_result();
-        let height = height_i32.try_into().expect("Height is never negative.");
+        let response = height_req.send().promise.await?;
+        let height_i32 = response.get()?.get_result();
+        let height = height_i32
+            .try_into()
+            .map_err(|_| capnp::Error::failed(format!("Invalid tip height {}", height_i32)))?;
         let mut hash_req = self.chain_interface.get_block_hash_request();
         hash_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         hash_req.get().set_height(height_i32);
-        let response = hash_req.send().promise.await.unwrap();
-        let hash = bitcoin::BlockHash::from_slice(response.get().unwrap().get_result().unwrap())
-            .expect("Core must be serving valid hashes.");
+        let response = hash_req.send().promise.await?;
+        let hash = block_hash(response.get()?.get_result()?)?;
 
-        BlockId { height, hash }
+        Ok(BlockId { height, hash })
     }
 
     // NOTE: not entirely correct, but good enough for the purpose of this PoC
@@ -225,30 +275,28 @@ impl RpcInterface {
         &self,
         node_tip_hash: &bitcoin::BlockHash,
         ancestor: &bitcoin::BlockHash,
-    ) -> bool {
+    ) -> Result<bool, capnp::Error> {
         let mut find_req = self.chain_interface.find_ancestor_by_hash_request();
         find_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         find_req.get().set_block_hash(node_tip_hash.as_ref());
         find_req.get().set_ancestor_hash(ancestor.as_ref());
-        let response = find_req.send().promise.await.unwrap();
-        response.get().unwrap().get_result()
+        let response = find_req.send().promise.await?;
+        Ok(response.get()?.get_result())
     }
 
-    pub async fn has_blocks(&self, node_tip_hash: &bitcoin::BlockHash, start_height: i32) -> bool {
+    pub async fn has_blocks(&self, node_tip_hash: &bitcoin::BlockHash, start_height: i32) -> Result<bool, capnp::Error> {
         let mut has_blocks_req = self.chain_interface.has_blocks_request();
         has_blocks_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         has_blocks_req.get().set_block_hash(node_tip_hash.as_ref());
         has_blocks_req.get().set_min_height(start_height);
-        let response = has_blocks_req.send().promise.await.unwrap();
-        response.get().unwrap().get_result()
+        let response = has_blocks_req.send().promise.await?;
+        Ok(response.get()?.get_result())
     }
 
 
@@ -256,60 +304,81 @@ impl RpcInterface {
         &self,
         node_tip_hash: &bitcoin::BlockHash,
         height: i32,
-    ) -> bitcoin::Block {
+    ) -> Result<bitcoin::Block, capnp::Error> {
         println!("DEBUG: Requesting block at height {}", height);
         let mut find_req = self.chain_interface.find_ancestor_by_height_request();
         find_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         find_req.get().set_block_hash(node_tip_hash.as_ref());
         find_req.get().set_ancestor_height(height);
-        find_req.get().get_ancestor().unwrap().set_want_data(true);
-        let response = find_req.send().promise.await.unwrap();
+        find_req.get().get_ancestor()?.set_want_data(true);
+        let response = find_req.send().promise.await?;
         bitcoin::Block::consensus_decode(
             &mut response
-                .get()
-                .unwrap()
-                .get_ancestor()
-                .unwrap()
-                .get_data()
-                .unwrap(),
+                .get()?
+                .get_ancestor()?
+                .get_data()?,
         )
-        .expect("Core must provide valid blocks")
+        .map_err(|e| capnp::Error::failed(format!("Invalid block at height {}: {}", height, e)))
     }
 
     pub async fn common_ancestor(
         &self,
         node_tip_hash: &bitcoin::BlockHash,
         wallet_tip_hash: &bitcoin::BlockHash,
-    ) -> Option<BlockId> {
+    ) -> Result<Option<BlockId>, capnp::Error> {
         let mut find_req = self.chain_interface.find_common_ancestor_request();
         find_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         find_req.get().set_block_hash1(node_tip_hash.as_ref());
-        find_req.get().set_block_hash1(wallet_tip_hash.as_ref());
-        find_req.get().get_ancestor().unwrap().set_want_height(true);
-        find_req.get().get_ancestor().unwrap().set_want_hash(true);
-        let response = find_req.send().promise.await.unwrap();
-        let response = response.get().unwrap();
-        let ancestor = response.get_ancestor().unwrap();
+        find_req.get().set_block_hash2(wallet_tip_hash.as_ref());
+        find_req.get().get_ancestor()?.set_want_height(true);
+        find_req.get().get_ancestor()?.set_want_hash(true);
+        let response = find_req.send().promise.await?;
+        let response = response.get()?;
+        let ancestor = response.get_ancestor()?;
         if !ancestor.get_found() {
-            return None;
+            return Ok(None);
         }
         let height = ancestor
             .get_height()
             .try_into()
-            .expect("Can't be negative.");
-        let hash = bitcoin::BlockHash::from_slice(ancestor.get_hash().unwrap())
-            .expect("Core must provide valid blocks");
-        Some(BlockId { height, hash })
+            .map_err(|_| capnp::Error::failed("Negative common ancestor height".to_string()))?;
+        let hash = block_hash(ancestor.get_hash()?)?;
+        Ok(Some(BlockId { height, hash }))
     }
 
+    pub async fn is_initial_block_download(&self) -> Result<bool, capnp::Error> {
+        let mut ibd_req = self.chain_interface.is_initial_block_download_request();
+        ibd_req
+            .get()
+            .get_context()?
+            .set_thread(self.thread.clone());
+        let response = ibd_req.send().promise.await?;
+        Ok(response.get()?.get_result())
+    }
+
+    /// If the node's tip isn't `old_tip` anymore, wait until all the notifications it queued for
+    /// the new tip were delivered to us.
+    pub async fn wait_for_notifications_if_tip_changed(
+        &self,
+        old_tip: &bitcoin::BlockHash,
+    ) -> Result<(), capnp::Error> {
+        let mut wait_req = self
+            .chain_interface
+            .wait_for_notifications_if_tip_changed_request();
+        wait_req.get().get_context()?.set_thread(self.thread.clone());
+        wait_req.get().set_old_tip(old_tip.as_ref());
+        wait_req.send().promise.await?;
+        Ok(())
+    }
+}
+
+impl RpcInterface {
     pub async fn show_progress(&self, title: &str, progress: i32, resume_possible: bool) {
         let mut mk_mess_req = self.chain_interface.show_progress_request();
         mk_mess_req
@@ -323,16 +392,161 @@ impl RpcInterface {
         let _ = mk_mess_req.send().promise.await.unwrap();
     }
 
-    pub async fn register_notifications(&self, wallet: Arc<Mutex<BdkWallet>>) {
-        let notif_handler = capnp_rpc::new_client(wallet);
+    /// Re-process the blocks of a loaded wallet's chain from `from_height` to `to_height`
+    /// (inclusive), for instance to pick up transactions missed by a previous scan. `to_height` is
+    /// capped to the wallet's tip.
+    ///
+    /// The wallets are only locked while applying a single block so notifications keep being
+    /// processed during the rescan. Blocks are fetched as ancestors of the wallet's block at
+    /// `to_height` and only ever update the transaction graph, never the local chain: blocks
+    /// connected in the meantime are applied in order by the notification handler, and if part of
+    /// the range gets reorged out of the wallet's chain the rescan stops there. Progress is
+    /// reported to the node and the rescan stops before the next block once `cancel` is triggered.
+    pub async fn rescan(
+        &self,
+        manager: &Arc<Mutex<WalletManager>>,
+        wallet_name: &str,
+        from_height: u32,
+        to_height: u32,
+        cancel: &CancellationToken,
+    ) -> Result<RescanStatus, Box<dyn std::error::Error>> {
+        let unloaded = || format!("Wallet '{}' is not loaded.", wallet_name);
+        let (to_height, ref_hash) = {
+            let manager = manager.lock().unwrap();
+            let wallet = manager.wallet(wallet_name).ok_or_else(unloaded)?;
+            let to_height = to_height.min(wallet.tip().height);
+            let hash = wallet
+                .block_hash(to_height)
+                .ok_or_else(|| format!("No block at height {} in the wallet chain.", to_height))?;
+            (to_height, hash)
+        };
+        if from_height > to_height {
+            return Err(format!("Invalid rescan range {}..={}.", from_height, to_height).into());
+        }
+        if !self.has_blocks(&ref_hash, from_height.try_into()?).await? {
+            return Err("bitcoin-node is missing blocks to rescan the BDK wallet.".into());
+        }
+
+        let title = "BDK Core rescan";
+        let total = to_height - from_height + 1;
+        let mut reported_progress = 0;
+        self.show_progress(title, 0, true).await;
+        for height in from_height..=to_height {
+            if cancel.is_cancelled() {
+                self.show_progress(title, 100, true).await;
+                return Ok(RescanStatus::Cancelled {
+                    next_height: height,
+                });
+            }
+
+            let block = self.get_block(&ref_hash, height.try_into()?).await?;
+            let applied = manager
+                .lock()
+                .unwrap()
+                .wallet_mut(wallet_name)
+                .ok_or_else(unloaded)?
+                .apply_rescanned_block(&block, height)?;
+            if !applied {
+                self.show_progress(title, 100, true).await;
+                return Ok(RescanStatus::Reorged { height });
+            }
+
+            let progress = ((height - from_height + 1) * 100 / total) as i32;
+            if progress > reported_progress {
+                reported_progress = progress;
+                self.show_progress(title, progress, true).await;
+                if progress % 10 == 0 {
+                    println!("Rescan {}% done (height {}).", progress, height);
+                }
+            }
+        }
+
+        Ok(RescanStatus::Completed)
+    }
+
+    /// Subscribe to the node's notifications. They are sent until the returned handler is
+    /// disconnected or dropped.
+    pub async fn register_notifications(
+        &self,
+        notif_handler: ChainNotificationsClient,
+    ) -> Result<HandlerClient, capnp::Error> {
         let mut register_req = self.chain_interface.handle_notifications_request();
         register_req
             .get()
-            .get_context()
-            .unwrap()
+            .get_context()?
             .set_thread(self.thread.clone());
         register_req.get().set_notifications(notif_handler);
-        let _ = register_req.send().promise.await.unwrap();
+        let response = register_req.send().promise.await?;
+        response.get()?.get_result()
+    }
+
+    /// Disconnect a handler returned by the node, for instance to stop receiving notifications.
+    pub async fn disconnect_handler(&self, handler: &HandlerClient) -> Result<(), capnp::Error> {
+        let mut disconnect_req = handler.disconnect_request();
+        disconnect_req
+            .get()
+            .get_context()?
+            .set_thread(self.thread.clone());
+        disconnect_req.send().promise.await?;
+        Ok(())
+    }
+
+    /// Submit a transaction to the node's mempool and relay it. It's rejected if it pays more
+    /// than `max_tx_fee` in fees. Returns the node's error message if it was rejected.
+    pub async fn broadcast_transaction(
+        &self,
+        tx: &bitcoin::Transaction,
+        max_tx_fee: bitcoin::Amount,
+    ) -> Result<Result<(), String>, capnp::Error> {
+        let mut broadcast_req = self.chain_interface.broadcast_transaction_request();
+        broadcast_req
+            .get()
+            .get_context()?
+            .set_thread(self.thread.clone());
+        broadcast_req
+            .get()
+            .set_tx(&bitcoin::consensus::encode::serialize(tx));
+        broadcast_req
+            .get()
+            .set_max_tx_fee(max_tx_fee.to_sat().try_into().expect("Fits in an i64"));
+        broadcast_req.get().set_relay(true);
+        let response = broadcast_req.send().promise.await?;
+        let response = response.get()?;
+        if response.get_result() {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(response.get_error()?.to_string()?))
+        }
+    }
+
+    /// Register a command with Core's JSON-RPC server, served by `actor`. The command is
+    /// unregistered once the returned handler is dropped.
+    pub async fn handle_rpc(
+        &self,
+        category: &str,
+        name: &str,
+        actor: ActorCallbackClient,
+        arg_names: &[&str],
+        unique_id: i64,
+    ) -> Result<HandlerClient, capnp::Error> {
+        let mut handle_req = self.chain_interface.handle_rpc_request();
+        handle_req
+            .get()
+            .get_context()?
+            .set_thread(self.thread.clone());
+        let mut command = handle_req.get().init_command();
+        command.set_category(category);
+        command.set_name(name);
+        command.set_actor(actor);
+        command.set_unique_id(unique_id);
+        let mut args = command.init_arg_names(arg_names.len().try_into().expect("Few args"));
+        for (i, arg_name) in arg_names.iter().enumerate() {
+            let mut arg = args.reborrow().get(i as u32);
+            arg.set_name(*arg_name);
+            arg.set_named_only(false);
+        }
+        let response = handle_req.send().promise.await?;
+        response.get()?.get_result()
     }
 
     pub async fn disconnect(self) -> Result<(), capnp::Error> {
@@ -340,6 +554,7 @@ impl RpcInterface {
         self.rpc_handle.await.unwrap()
     }
 
+    #[allow(dead_code)]
     pub async fn create_and_broadcast_transaction(&self, utxos: Vec<bitcoin::OutPoint>, recipient_address: &str, amount: u64) -> Result<bitcoin::Txid, Box<dyn std::error::Error>> {
         // Create a transaction
         let mut tx = bitcoin::Transaction {
@@ -408,7 +623,7 @@ impl RpcInterface {
             return Err(error.to_str()?.into());
         }
 
-        Ok(tx.txid())
+        Ok(tx.compute_txid())
 
     }
 }
@@ -0,0 +1,237 @@
+//! Sign PSBTs created by the (watch-only) wallet using private keys read from a local key file, and
+//! coordinate the signatures of the cosigners of a multisig or miniscript policy wallet: combine
+//! their PSBTs, report which keys signed each input and finalize it once the policy is satisfied.
+
+use bdk_chain::{
+    bitcoin::{
+        self,
+        bip32::KeySource,
+        psbt::{GetKey, GetKeyError, KeyRequest, SigningKeys},
+        secp256k1::{Secp256k1, Signing},
+    },
+    miniscript::{
+        descriptor::{DescriptorSecretKey, KeyMap, Wildcard},
+        psbt::PsbtExt,
+        Descriptor,
+    },
+};
+
+use std::{error, fmt, fs, path::Path};
+
+/// The private keys of a descriptor, looked up by the origin of the keys recorded in a PSBT.
+struct DescriptorKeys(KeyMap);
+
+impl DescriptorKeys {
+    fn key_for_source<C: Signing>(
+        &self,
+        (fingerprint, path): &KeySource,
+        secp: &Secp256k1<C>,
+    ) -> Result<Option<bitcoin::PrivateKey>, GetKeyError> {
+        for (public, secret) in &self.0 {
+            if public.master_fingerprint() != *fingerprint {
+                continue;
+            }
+            match secret {
+                DescriptorSecretKey::Single(single) => {
+                    if public.full_derivation_path().as_ref() == Some(path) {
+                        return Ok(Some(single.key));
+                    }
+                }
+                DescriptorSecretKey::XPrv(xprv) => {
+                    // The path recorded in the PSBT is the origin of the extended key, then its
+                    // derivation path and the index of the wildcard, if any.
+                    let origin = xprv.origin.as_ref().map_or(&[][..], |(_, p)| p.as_ref());
+                    let Some(path) = path.as_ref().strip_prefix(origin) else {
+                        continue;
+                    };
+                    let Some(child) = path.strip_prefix(xprv.derivation_path.as_ref()) else {
+                        continue;
+                    };
+                    let matches = match (xprv.wildcard, child) {
+                        (Wildcard::None, []) => true,
+                        (Wildcard::Unhardened, [index]) => index.is_normal(),
+                        (Wildcard::Hardened, [index]) => index.is_hardened(),
+                        _ => false,
+                    };
+                    if matches {
+                        let path = bitcoin::bip32::DerivationPath::from(path);
+                        return Ok(Some(xprv.xkey.derive_priv(secp, &path)?.to_priv()));
+                    }
+                }
+                DescriptorSecretKey::MultiXPrv(_) => return Err(GetKeyError::NotSupported),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl GetKey for DescriptorKeys {
+    type Error = GetKeyError;
+
+    fn get_key<C: Signing>(
+        &self,
+        key_request: KeyRequest,
+        secp: &Secp256k1<C>,
+    ) -> Result<Option<bitcoin::PrivateKey>, Self::Error> {
+        match key_request {
+            KeyRequest::Bip32(key_source) => self.key_for_source(&key_source, secp),
+            _ => Err(GetKeyError::NotSupported),
+        }
+    }
+}
+
+/// Sign the inputs of this PSBT with the private keys of this descriptor. Returns the number of
+/// inputs which were signed, or the errors if any input failed to be signed.
+pub fn sign_psbt(
+    psbt: &mut bitcoin::Psbt,
+    private_descriptor: &str,
+) -> Result<usize, Box<dyn error::Error>> {
+    let secp = Secp256k1::new();
+    let (_, keymap) = Descriptor::parse_descriptor(&secp, private_descriptor)?;
+    if keymap.is_empty() {
+        return Err("The descriptor doesn't contain any private key.".into());
+    }
+    let signed = psbt
+        .sign(&DescriptorKeys(keymap), &secp)
+        .map_err(|(_, errors)| {
+            let errors: Vec<_> = errors
+                .iter()
+                .map(|(index, e)| format!("input {}: '{}'", index, e))
+                .collect();
+            format!("Error when signing {}", errors.join(", "))
+        })?;
+    // Every input is reported, even those none of our keys could sign.
+    Ok(signed
+        .values()
+        .filter(|keys| match keys {
+            SigningKeys::Ecdsa(keys) => !keys.is_empty(),
+            SigningKeys::Schnorr(keys) => !keys.is_empty(),
+        })
+        .count())
+}
+
+/// Read the private descriptors to sign with from this key file, one per line. Empty lines and
+/// lines starting with `#` are ignored.
+pub fn read_key_file(path: &Path) -> Result<Vec<String>, Box<dyn error::Error>> {
+    let descriptors: Vec<_> = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading the key file {}: '{}'", path.display(), e))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    if descriptors.is_empty() {
+        return Err(format!(
+            "The key file {} doesn't contain any descriptor.",
+            path.display()
+        )
+        .into());
+    }
+    Ok(descriptors)
+}
+
+/// Try to finalize all the inputs of this PSBT. Returns whether it's ready to be extracted.
+pub fn finalize_psbt(psbt: &mut bitcoin::Psbt) -> bool {
+    psbt.finalize_mut(&Secp256k1::verification_only()).is_ok()
+}
+
+/// Merge the signatures (and any other information) of these PSBTs for the same transaction, for
+/// instance as signed separately by each cosigner.
+pub fn combine_psbts(
+    psbts: impl IntoIterator<Item = bitcoin::Psbt>,
+) -> Result<bitcoin::Psbt, Box<dyn error::Error>> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts.next().ok_or("No PSBT to combine.")?;
+    for psbt in psbts {
+        combined.combine(psbt)?;
+    }
+    Ok(combined)
+}
+
+/// The next step needed by a PSBT, or one of its inputs, as in BIP 174.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PsbtRole {
+    /// Information about the coin being spent is missing.
+    Updater,
+    /// More signatures are needed to satisfy the spending policy.
+    Signer,
+    /// Enough signatures are there, the input can be finalized.
+    Finalizer,
+    /// The input is final.
+    Extractor,
+}
+
+impl fmt::Display for PsbtRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Updater => "updater",
+            Self::Signer => "signer",
+            Self::Finalizer => "finalizer",
+            Self::Extractor => "extractor",
+        })
+    }
+}
+
+/// A key which may sign an input, and whether it did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputKey {
+    /// The public key, x-only for Taproot inputs, hex encoded.
+    pub pubkey: String,
+    pub source: KeySource,
+    pub signed: bool,
+}
+
+/// Where the signing of an input stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputStatus {
+    pub next: PsbtRole,
+    /// The keys recorded for this input. Empty once it's final.
+    pub keys: Vec<InputKey>,
+}
+
+/// Where the signing of each input of this PSBT stands: which of the keys recorded in it signed
+/// already, and whether that's enough to finalize it.
+pub fn analyze_psbt(psbt: &bitcoin::Psbt) -> Vec<InputStatus> {
+    let secp = Secp256k1::verification_only();
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let mut keys: Vec<_> = input
+                .bip32_derivation
+                .iter()
+                .map(|(pubkey, source)| InputKey {
+                    pubkey: pubkey.to_string(),
+                    source: source.clone(),
+                    signed: input
+                        .partial_sigs
+                        .contains_key(&bitcoin::PublicKey::new(*pubkey)),
+                })
+                .collect();
+            keys.extend(
+                input
+                    .tap_key_origins
+                    .iter()
+                    .map(|(pubkey, (_, source))| InputKey {
+                        pubkey: pubkey.to_string(),
+                        source: source.clone(),
+                        signed: if input.tap_internal_key == Some(*pubkey) {
+                            input.tap_key_sig.is_some()
+                        } else {
+                            input.tap_script_sigs.keys().any(|(key, _)| key == pubkey)
+                        },
+                    }),
+            );
+            let next = if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+                PsbtRole::Extractor
+            } else if input.witness_utxo.is_none() && input.non_witness_utxo.is_none() {
+                PsbtRole::Updater
+            } else if psbt.clone().finalize_inp_mut(&secp, index).is_ok() {
+                PsbtRole::Finalizer
+            } else {
+                PsbtRole::Signer
+            };
+            InputStatus { next, keys }
+        })
+        .collect()
+}
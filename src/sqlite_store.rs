@@ -0,0 +1,208 @@
+//! Persist the wallet state in an SQLite database.
+//!
+//! Contrary to the file store, which appends every changeset and grows forever, the database holds
+//! the aggregated state of the chain, the transaction graph and the index, using the tables of
+//! `bdk_chain`. It can be queried without loading the wallet.
+
+use bdk_chain::{
+    bitcoin::{self, Txid},
+    indexed_tx_graph, keychain_txout, local_chain,
+    rusqlite::{named_params, Connection, OptionalExtension},
+    tx_graph, BlockId, ConfirmationBlockTime,
+};
+
+use std::{error, fs, path::Path, str::FromStr};
+
+use crate::{
+    persist::{Backend, FileBackend, Queries, StoreHeader, StoredTx},
+    wallet::ChangeSet,
+};
+
+type TxGraphChangeSet = tx_graph::ChangeSet<ConfirmationBlockTime>;
+
+// The table holding the network and descriptor checksum of the wallet, in a single row, like the
+// header of a file store.
+const HEADER_TABLE_NAME: &str = "bdk_core_store_header";
+
+/// A [`Backend`] writing each batch as a transaction to an SQLite database.
+pub struct SqliteBackend {
+    conn: Connection,
+}
+
+impl SqliteBackend {
+    /// Open the database at this path, creating it if it doesn't exist. Fails if it's for another
+    /// network or descriptor than the header's. A database which doesn't record them yet is
+    /// assumed to be the wallet's.
+    pub fn open(path: &Path, header: &StoreHeader) -> Result<Self, Box<dyn error::Error>> {
+        let mut conn = Connection::open(path)?;
+        // Make sure a committed batch is on disk.
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        let db_tx = conn.transaction()?;
+        db_tx.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY CHECK (id = 0), \
+                network TEXT NOT NULL, descriptor_checksum TEXT NOT NULL)",
+                HEADER_TABLE_NAME
+            ),
+            (),
+        )?;
+        let stored = db_tx
+            .query_row(
+                &format!(
+                    "SELECT network, descriptor_checksum FROM {} WHERE id = 0",
+                    HEADER_TABLE_NAME
+                ),
+                (),
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        match stored {
+            Some((network, descriptor_checksum)) => {
+                let network = bitcoin::Network::from_str(&network)?;
+                header.check(&StoreHeader::new(network, &descriptor_checksum), path)?;
+            }
+            None => {
+                db_tx.execute(
+                    &format!(
+                        "INSERT INTO {} (id, network, descriptor_checksum) \
+                        VALUES (0, :network, :descriptor_checksum)",
+                        HEADER_TABLE_NAME
+                    ),
+                    named_params! {
+                        ":network": header.network.to_string(),
+                        ":descriptor_checksum": header.descriptor_checksum,
+                    },
+                )?;
+            }
+        }
+        local_chain::ChangeSet::init_sqlite_tables(&db_tx)?;
+        TxGraphChangeSet::init_sqlite_tables(&db_tx)?;
+        keychain_txout::ChangeSet::init_sqlite_tables(&db_tx)?;
+        db_tx.commit()?;
+        Ok(Self { conn })
+    }
+
+    /// Open the database at `path`. If it doesn't exist yet but a file store does at `file_path`,
+    /// first migrate the state from the file store. The file store is then renamed with a
+    /// `.migrated` extension, and kept as a backup. An encrypted file store isn't migrated.
+    pub fn open_or_migrate(
+        path: &Path,
+        file_path: &Path,
+        header: StoreHeader,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        if !path.exists() && file_path.exists() {
+            println!(
+                "Migrating the wallet store {} to {}.",
+                file_path.display(),
+                path.display()
+            );
+            let changeset =
+                FileBackend::<ChangeSet>::open(file_path, header.clone(), None)?.load()?;
+            // Only move the database into place once fully written, so an interrupted migration
+            // is started over.
+            let tmp_path = path.with_extension("tmp");
+            if tmp_path.exists() {
+                fs::remove_file(&tmp_path)?;
+            }
+            SqliteBackend::open(&tmp_path, &header)?.write(&changeset)?;
+            fs::rename(&tmp_path, path)?;
+            let mut migrated = file_path.as_os_str().to_owned();
+            migrated.push(".migrated");
+            fs::rename(file_path, migrated)?;
+        }
+        Self::open(path, &header)
+    }
+}
+
+impl Backend<ChangeSet> for SqliteBackend {
+    fn load(&mut self) -> Result<ChangeSet, Box<dyn error::Error>> {
+        let db_tx = self.conn.transaction()?;
+        let changeset = ChangeSet {
+            chain_cs: local_chain::ChangeSet::from_sqlite(&db_tx)?,
+            graph_cs: indexed_tx_graph::ChangeSet {
+                tx_graph: TxGraphChangeSet::from_sqlite(&db_tx)?,
+                indexer: keychain_txout::ChangeSet::from_sqlite(&db_tx)?,
+            },
+        };
+        db_tx.commit()?;
+        Ok(changeset)
+    }
+
+    fn write(&mut self, changeset: &ChangeSet) -> Result<(), Box<dyn error::Error>> {
+        let db_tx = self.conn.transaction()?;
+        changeset.chain_cs.persist_to_sqlite(&db_tx)?;
+        changeset.graph_cs.tx_graph.persist_to_sqlite(&db_tx)?;
+        changeset.graph_cs.indexer.persist_to_sqlite(&db_tx)?;
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    fn queries(&self) -> Option<&dyn Queries> {
+        Some(self)
+    }
+}
+
+impl Queries for SqliteBackend {
+    fn transaction(&self, txid: &Txid) -> Result<Option<StoredTx>, Box<dyn error::Error>> {
+        let row = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT raw_tx, last_seen FROM {} WHERE txid=:txid AND raw_tx IS NOT NULL",
+                    TxGraphChangeSet::TXS_TABLE_NAME
+                ),
+                named_params! {":txid": txid.to_string()},
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<u64>>(1)?)),
+            )
+            .optional()?;
+        let Some((raw_tx, last_seen)) = row else {
+            return Ok(None);
+        };
+        // Anchors to blocks which were since disconnected are kept, only consider the ones in
+        // the chain.
+        let confirmation = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT a.block_height, a.block_hash FROM {} AS a JOIN {} AS b \
+                    ON a.block_height = b.block_height AND a.block_hash = b.block_hash \
+                    WHERE a.txid=:txid",
+                    TxGraphChangeSet::ANCHORS_TABLE_NAME,
+                    local_chain::ChangeSet::BLOCKS_TABLE_NAME
+                ),
+                named_params! {":txid": txid.to_string()},
+                |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?
+            .map(|(height, hash)| -> Result<_, Box<dyn error::Error>> {
+                Ok(BlockId {
+                    height,
+                    hash: bitcoin::BlockHash::from_str(&hash)?,
+                })
+            })
+            .transpose()?;
+        Ok(Some(StoredTx {
+            tx: bitcoin::consensus::deserialize(&raw_tx)?,
+            confirmation,
+            last_seen,
+        }))
+    }
+
+    fn transactions_at_height(&self, height: u32) -> Result<Vec<Txid>, Box<dyn error::Error>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT a.txid FROM {} AS a JOIN {} AS b \
+            ON a.block_height = b.block_height AND a.block_hash = b.block_hash \
+            WHERE a.block_height=:height ORDER BY a.txid",
+            TxGraphChangeSet::ANCHORS_TABLE_NAME,
+            local_chain::ChangeSet::BLOCKS_TABLE_NAME
+        ))?;
+        let rows = statement.query_map(named_params! {":height": height}, |row| {
+            row.get::<_, String>(0)
+        })?;
+        let mut txids = Vec::new();
+        for txid in rows {
+            txids.push(Txid::from_str(&txid?)?);
+        }
+        Ok(txids)
+    }
+}
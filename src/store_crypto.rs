@@ -0,0 +1,308 @@
+//! Encryption of the file stores.
+//!
+//! Even for a watch-only wallet, its store reveals every address and the whole transaction
+//! history to anyone who can read it. An encrypted store seals each of its records with
+//! XChaCha20-Poly1305 under a random nonce. The key is either read from a key file, or derived
+//! from a password with Argon2id. What's needed to derive it again, along with a sealed known value
+//! to check the key is right, are kept in clear in the header of the store.
+//!
+//! The small files kept next to an encrypted store, such as the wallet's descriptor or settings,
+//! are sealed as a whole with the same key.
+
+use argon2::Argon2;
+use bdk_chain::bitcoin::hex::FromHex;
+use bincode::Options;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use std::{
+    error, fmt,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// The value sealed in the header of an encrypted store, to check the key before reading it.
+const KEY_CHECK: &[u8] = b"bdk_core store key check";
+/// The magic bytes starting a file sealed as a whole.
+const SEALED_FILE_MAGIC: &[u8] = b"bdk_core_sealed";
+
+/// How to get the key of an encrypted store.
+#[derive(Clone, PartialEq, Eq)]
+pub enum StoreKey {
+    /// A file containing the 32 bytes of the key, either raw or hex encoded.
+    KeyFile(PathBuf),
+    /// A password the key is derived from.
+    Password(String),
+}
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+            Self::Password(_) => f.write_str("Password(..)"),
+        }
+    }
+}
+
+/// How the key of an encrypted store is obtained.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kdf {
+    /// Read from a key file as is.
+    KeyFile,
+    /// Derived from a password with Argon2id, with these parameters.
+    Argon2id {
+        salt: [u8; 16],
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+/// The encryption parameters of a store, kept in its header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionParams {
+    pub kdf: Kdf,
+    /// The [`KEY_CHECK`] value sealed with the key.
+    pub key_check: SealedRecord,
+}
+
+/// A record of an encrypted store.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedRecord {
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Seals and opens the records of an encrypted store.
+pub struct StoreCipher {
+    aead: XChaCha20Poly1305,
+    // Records are bound to the store they were sealed for.
+    aad: Vec<u8>,
+}
+
+impl StoreKey {
+    /// A new cipher with this key for the store identified by `aad`, along with the parameters to
+    /// open it again. A password is derived with a new random salt.
+    pub fn new_cipher(
+        &self,
+        aad: &[u8],
+    ) -> Result<(StoreCipher, EncryptionParams), Box<dyn error::Error>> {
+        let kdf = match self {
+            Self::KeyFile(_) => Kdf::KeyFile,
+            Self::Password(_) => {
+                let mut salt = [0; 16];
+                OsRng.fill_bytes(&mut salt);
+                Kdf::Argon2id {
+                    salt,
+                    m_cost: argon2::Params::DEFAULT_M_COST,
+                    t_cost: argon2::Params::DEFAULT_T_COST,
+                    p_cost: argon2::Params::DEFAULT_P_COST,
+                }
+            }
+        };
+        let cipher = StoreCipher::new(&self.derive(&kdf)?, aad);
+        let key_check = cipher.seal_bytes(KEY_CHECK)?;
+        Ok((cipher, EncryptionParams { kdf, key_check }))
+    }
+
+    /// The cipher of the store identified by `aad` which was encrypted with these parameters.
+    /// Fails if this isn't its key.
+    pub fn open_cipher(
+        &self,
+        params: &EncryptionParams,
+        aad: &[u8],
+    ) -> Result<StoreCipher, Box<dyn error::Error>> {
+        let cipher = StoreCipher::new(&self.derive(&params.kdf)?, aad);
+        match cipher.open_bytes(&params.key_check) {
+            Ok(check) if check == KEY_CHECK => Ok(cipher),
+            _ => Err("Wrong key for the encrypted store.".into()),
+        }
+    }
+
+    fn derive(&self, kdf: &Kdf) -> Result<[u8; 32], Box<dyn error::Error>> {
+        let mut key = [0; 32];
+        match (self, kdf) {
+            (Self::KeyFile(path), Kdf::KeyFile) => {
+                let content = fs::read(path)
+                    .map_err(|e| format!("Error reading key file {}: '{}'", path.display(), e))?;
+                if content.len() == key.len() {
+                    key.copy_from_slice(&content);
+                } else {
+                    key = std::str::from_utf8(&content)
+                        .ok()
+                        .and_then(|hex| <[u8; 32]>::from_hex(hex.trim()).ok())
+                        .ok_or_else(|| {
+                            format!(
+                                "Key file {} must contain 32 bytes, raw or hex encoded.",
+                                path.display()
+                            )
+                        })?;
+                }
+            }
+            (
+                Self::Password(password),
+                Kdf::Argon2id {
+                    salt,
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                },
+            ) => {
+                let params = argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(key.len()))
+                    .map_err(|e| e.to_string())?;
+                Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|e| e.to_string())?;
+            }
+            (Self::KeyFile(_), Kdf::Argon2id { .. }) => {
+                return Err("The store is encrypted with a password, not a key file.".into())
+            }
+            (Self::Password(_), Kdf::KeyFile) => {
+                return Err("The store is encrypted with a key file, not a password.".into())
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl StoreCipher {
+    fn new(key: &[u8; 32], aad: &[u8]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+            aad: aad.to_vec(),
+        }
+    }
+
+    /// Seal this value under a new random nonce.
+    pub fn seal<T: Serialize>(&self, value: &T) -> Result<SealedRecord, Box<dyn error::Error>> {
+        self.seal_bytes(&bincode_options().serialize(value)?)
+    }
+
+    /// Open a record sealed with [`Self::seal`]. Fails if it was sealed with another key or for
+    /// another store, or if it was tampered with.
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        record: &SealedRecord,
+    ) -> Result<T, Box<dyn error::Error>> {
+        Ok(bincode_options().deserialize(&self.open_bytes(record)?)?)
+    }
+
+    fn seal_bytes(&self, msg: &[u8]) -> Result<SealedRecord, Box<dyn error::Error>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg,
+                    aad: &self.aad,
+                },
+            )
+            .map_err(|_| "Error encrypting a store record.")?;
+        Ok(SealedRecord {
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    fn open_bytes(&self, record: &SealedRecord) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        Ok(self
+            .aead
+            .decrypt(
+                XNonce::from_slice(&record.nonce),
+                Payload {
+                    msg: &record.ciphertext,
+                    aad: &self.aad,
+                },
+            )
+            .map_err(|_| "Error decrypting a store record: wrong key or corrupted record.")?)
+    }
+}
+
+// The content of a sealed file, after its magic bytes.
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    encryption: EncryptionParams,
+    sealed: SealedRecord,
+}
+
+/// Seals a small file as a whole. The key is only derived once, to rewrite the file on every
+/// change.
+pub struct FileSealer {
+    cipher: StoreCipher,
+    params: EncryptionParams,
+}
+
+impl FileSealer {
+    /// A sealer with this key for the files identified by `aad`.
+    pub fn new(key: &StoreKey, aad: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let (cipher, params) = key.new_cipher(aad)?;
+        Ok(Self { cipher, params })
+    }
+
+    /// Open the file at this path, sealed for `aad`. Returns its content along with a sealer to
+    /// rewrite it with the same key.
+    pub fn open<T: DeserializeOwned>(
+        path: &Path,
+        key: Option<&StoreKey>,
+        aad: &[u8],
+    ) -> Result<(Self, T), Box<dyn error::Error>> {
+        let content = fs::read(path)?;
+        let file: SealedFile = content
+            .strip_prefix(SEALED_FILE_MAGIC)
+            .and_then(|content| bincode_options().deserialize(content).ok())
+            .ok_or_else(|| format!("{} is not a sealed file.", path.display()))?;
+        let key = key.ok_or_else(|| {
+            format!(
+                "{} is encrypted, a key is needed to read it.",
+                path.display()
+            )
+        })?;
+        let opened = key
+            .open_cipher(&file.encryption, aad)
+            .and_then(|cipher| Ok((cipher.open(&file.sealed)?, cipher)));
+        let (value, cipher) =
+            opened.map_err(|e| format!("Error opening {}: '{}'", path.display(), e))?;
+        let sealer = Self {
+            cipher,
+            params: file.encryption,
+        };
+        Ok((sealer, value))
+    }
+
+    /// Atomically replace the file at this path with this value, sealed: it's written to a
+    /// temporary file which is then renamed over the path.
+    pub fn write<T: Serialize>(&self, path: &Path, value: &T) -> Result<(), Box<dyn error::Error>> {
+        let file = SealedFile {
+            encryption: self.params.clone(),
+            sealed: self.cipher.seal(value)?,
+        };
+        let mut content = SEALED_FILE_MAGIC.to_vec();
+        bincode_options().serialize_into(&mut content, &file)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&content)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Whether the file at this path was sealed as a whole, see [`FileSealer`].
+pub fn is_sealed(path: &Path) -> Result<bool, Box<dyn error::Error>> {
+    let mut magic = Vec::new();
+    File::open(path)?
+        .take(SEALED_FILE_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic == SEALED_FILE_MAGIC)
+}
+
+// The same encoding as the records of the file store.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_varint_encoding()
+}
@@ -0,0 +1,95 @@
+//! Inspect the content of a wallet's file store, for instance when its state looks wrong.
+//!
+//! The header of the store is dumped as JSON, along with each of its changesets in the order they
+//! were written and their aggregate. Changesets can be restricted to what they contain about a transaction or a height.
+
+use bdk_chain::{bitcoin::Txid, Merge};
+use serde_json::{json, Value};
+
+use std::{error, path::Path};
+
+use crate::{
+    store_crypto::StoreKey,
+    wallet::{BdkWallet, ChangeSet},
+};
+
+/// Only dump what the changesets contain about this transaction and (or) this height.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DumpFilter {
+    pub txid: Option<Txid>,
+    pub height: Option<u32>,
+}
+
+impl DumpFilter {
+    fn is_empty(&self) -> bool {
+        self.txid.is_none() && self.height.is_none()
+    }
+
+    // The part of the changeset matching the filter.
+    fn apply(&self, cs: &ChangeSet) -> ChangeSet {
+        if self.is_empty() {
+            return cs.clone();
+        }
+        let mut cs = cs.clone();
+        let graph = &mut cs.graph_cs.tx_graph;
+        if let Some(txid) = self.txid {
+            graph.txs.retain(|tx| tx.compute_txid() == txid);
+            graph.txouts.retain(|op, _| op.txid == txid);
+            graph.anchors.retain(|(_, t)| *t == txid);
+            graph.last_seen.retain(|t, _| *t == txid);
+        }
+        // Blocks aren't related to a transaction.
+        match (self.txid, self.height) {
+            (None, Some(height)) => cs.chain_cs.blocks.retain(|h, _| *h == height),
+            _ => cs.chain_cs.blocks.clear(),
+        }
+        if let Some(height) = self.height {
+            graph
+                .anchors
+                .retain(|(anchor, _)| anchor.block_id.height == height);
+            // The transactions confirmed at this height.
+            let anchored: Vec<_> = graph.anchors.iter().map(|(_, txid)| *txid).collect();
+            graph.txs.retain(|tx| anchored.contains(&tx.compute_txid()));
+            graph.txouts.retain(|op, _| anchored.contains(&op.txid));
+            graph.last_seen.retain(|txid, _| anchored.contains(txid));
+        }
+        // Revealed indexes aren't related to a transaction or a height.
+        cs.graph_cs.indexer = Default::default();
+        cs
+    }
+}
+
+/// Dump the file store of the wallet with this name in the data directory. The wallet must not be
+/// in use, and its store is decrypted with this key if it's encrypted. A changeset which can't be
+/// read ends the dump, along with the error.
+pub fn dump_store(
+    data_dir: &Path,
+    name: &str,
+    key: Option<&StoreKey>,
+    filter: &DumpFilter,
+) -> Result<Value, Box<dyn error::Error>> {
+    let (header, entries) = BdkWallet::read_store_file(data_dir, name, key)?;
+    let mut changesets = Vec::new();
+    let mut aggregate = ChangeSet::default();
+    for (index, cs) in entries.into_iter().enumerate() {
+        match cs {
+            Ok(cs) => {
+                let filtered = filter.apply(&cs);
+                if filter.is_empty() || !filtered.is_empty() {
+                    changesets.push(json!({
+                        "index": index,
+                        "changeset": serde_json::to_value(&filtered)?,
+                    }));
+                }
+                aggregate.merge(cs);
+            }
+            Err(e) => changesets.push(json!({"index": index, "error": e})),
+        }
+    }
+    Ok(json!({
+        "wallet": name,
+        "header": serde_json::to_value(header)?,
+        "changesets": changesets,
+        "aggregate": serde_json::to_value(filter.apply(&aggregate))?,
+    }))
+}
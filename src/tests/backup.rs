@@ -0,0 +1,273 @@
+//! Tests of the backup and restoration of the wallets.
+
+use bdk_chain::{
+    bitcoin::{
+        hashes::{sha256, Hash},
+        Amount, Network,
+    },
+    BlockId,
+};
+use serde::Serialize;
+
+use std::{fs, path::Path};
+
+use super::{
+    balance, mock_node::payment, mock_node::MockNode, run_local, start_wallet, start_wallet_with,
+    stop_wallet, wallet_spk,
+};
+use crate::{
+    backup::read_backup,
+    backup_wallet,
+    rpc_interface::RpcInterface,
+    store_crypto::StoreKey,
+    wallet::{ChangeSet, SettingsChangeSet},
+    wallet_startup, Options,
+};
+
+// A snapshot as written by version 1 of the backups, before descriptors could be imported.
+#[derive(Serialize)]
+struct SnapshotV1<'a> {
+    network: Network,
+    name: &'a str,
+    descriptor: &'a str,
+    tip: BlockId,
+    settings: &'a SettingsChangeSet,
+    changeset: &'a ChangeSet,
+}
+
+fn restore_options(data_dir: &Path, name: &str, backup: &Path) -> Options {
+    Options {
+        data_dir: data_dir.to_path_buf(),
+        restore_wallets: vec![(name.to_string(), backup.to_path_buf())],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn backup_and_restore() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backup_path = data_dir.path().join("wallet.backup");
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let address = {
+            let mut manager = manager.lock().unwrap();
+            let address = manager.wallet_mut("").unwrap().next_address().unwrap();
+            manager.backup_wallet("", &backup_path).unwrap();
+            address
+        };
+        assert!(manager
+            .lock()
+            .unwrap()
+            .backup_wallet("missing", &backup_path)
+            .is_err());
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+        let snapshot = read_backup(&backup_path, None).unwrap();
+        assert_eq!(snapshot.tip.hash, node.tip());
+
+        // Blocks mined after the backup are caught up with once restored, in another data
+        // directory and under another name.
+        node.mine(vec![payment(wallet_spk(1), Amount::from_sat(20_000))]);
+        let restore_dir = tempfile::tempdir().unwrap();
+        let options = restore_options(restore_dir.path(), "restored", &backup_path);
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        {
+            let manager = manager.lock().unwrap();
+            let wallet = manager.wallet("restored").unwrap();
+            assert_eq!(wallet.tip().hash, node.tip());
+            assert_eq!(wallet.balance().confirmed, Amount::from_sat(30_000));
+            assert!(wallet
+                .revealed_addresses()
+                .into_iter()
+                .any(|(_, a)| a == address));
+        }
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // The restored wallet is persisted like any other, and can't be restored over.
+        let options = Options {
+            data_dir: restore_dir.path().to_path_buf(),
+            wallets: vec!["restored".to_string()],
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        assert_eq!(
+            manager
+                .lock()
+                .unwrap()
+                .wallet("restored")
+                .unwrap()
+                .balance()
+                .confirmed,
+            Amount::from_sat(30_000)
+        );
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+        let rpc = RpcInterface::new(node.connect()).await.unwrap();
+        let options = restore_options(restore_dir.path(), "restored", &backup_path);
+        assert!(wallet_startup(&rpc, &options).await.is_err());
+
+        // The default wallet can be restored too, if it doesn't exist yet.
+        let options = restore_options(data_dir.path(), "", &backup_path);
+        assert!(wallet_startup(&rpc, &options).await.is_err());
+        rpc.disconnect().await.unwrap();
+        let default_dir = tempfile::tempdir().unwrap();
+        let options = restore_options(default_dir.path(), "", &backup_path);
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(30_000));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn offline_backup() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backup_path = data_dir.path().join("wallet.backup");
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // The wallet is backed up from its store while the program isn't running.
+        let options = |destination: Option<&Path>, wallet: &str| Options {
+            data_dir: data_dir.path().to_path_buf(),
+            wallets: vec![wallet.to_string()],
+            destination: destination.map(Path::to_path_buf),
+            ..Default::default()
+        };
+        assert!(backup_wallet(&options(None, "")).is_err());
+        assert!(backup_wallet(&options(Some(&backup_path), "missing")).is_err());
+        backup_wallet(&options(Some(&backup_path), "")).unwrap();
+        assert_eq!(
+            read_backup(&backup_path, None).unwrap().tip.hash,
+            node.tip()
+        );
+
+        let restore_dir = tempfile::tempdir().unwrap();
+        let options = restore_options(restore_dir.path(), "restored", &backup_path);
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        assert_eq!(
+            manager
+                .lock()
+                .unwrap()
+                .wallet("restored")
+                .unwrap()
+                .balance()
+                .confirmed,
+            Amount::from_sat(10_000)
+        );
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn restored_tip_is_checked() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backup_path = data_dir.path().join("wallet.backup");
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let tx = payment(wallet_spk(0), Amount::from_sat(10_000));
+        node.mine(vec![tx.clone()]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        manager
+            .lock()
+            .unwrap()
+            .backup_wallet("", &backup_path)
+            .unwrap();
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // A node which never saw the tip of the backup.
+        let other_node = MockNode::new();
+        other_node.mine(vec![payment(wallet_spk(5), Amount::from_sat(1_000))]);
+        other_node.mine(vec![]);
+        let rpc = RpcInterface::new(other_node.connect()).await.unwrap();
+        let restore_dir = tempfile::tempdir().unwrap();
+        let options = restore_options(restore_dir.path(), "restored", &backup_path);
+        assert!(wallet_startup(&rpc, &options).await.is_err());
+        assert!(!restore_dir
+            .path()
+            .join("bdk_core_wallets/restored")
+            .exists());
+        rpc.disconnect().await.unwrap();
+
+        // The tip of the backup was reorged out: the wallet resyncs from the common ancestor.
+        node.reorg(1, vec![vec![], vec![tx]]);
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        {
+            let manager = manager.lock().unwrap();
+            let wallet = manager.wallet("restored").unwrap();
+            assert_eq!(wallet.tip().hash, node.tip());
+            assert_eq!(wallet.balance().confirmed, Amount::from_sat(10_000));
+            assert_eq!(wallet.utxos().len(), 1);
+        }
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn backup_file_is_verified() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backup_path = data_dir.path().join("wallet.backup");
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        manager
+            .lock()
+            .unwrap()
+            .backup_wallet("", &backup_path)
+            .unwrap();
+        let key_path = data_dir.path().join("key");
+        fs::write(&key_path, [1; 32]).unwrap();
+        let key = StoreKey::KeyFile(key_path);
+        let encrypted_path = data_dir.path().join("encrypted.backup");
+        let snapshot = manager.lock().unwrap().wallet("").unwrap().backup();
+        crate::backup::write_backup(&encrypted_path, &snapshot, Some(&key)).unwrap();
+        stop_wallet(rpc, subscription).await;
+
+        // A modified snapshot is detected.
+        let content = fs::read_to_string(&backup_path).unwrap();
+        let tampered = content.replacen("\"height\": 1", "\"height\": 2", 1);
+        assert_ne!(tampered, content);
+        fs::write(&backup_path, tampered).unwrap();
+        assert!(read_backup(&backup_path, None).is_err());
+        fs::write(&backup_path, &content[..content.len() / 2]).unwrap();
+        assert!(read_backup(&backup_path, None).is_err());
+
+        // An encrypted backup needs its key.
+        assert!(!fs::read_to_string(&encrypted_path)
+            .unwrap()
+            .contains(&snapshot.descriptor));
+        assert!(read_backup(&encrypted_path, None).is_err());
+        assert_eq!(read_backup(&encrypted_path, Some(&key)).unwrap(), snapshot);
+
+        // Backups of the previous version can still be restored.
+        let v1 = SnapshotV1 {
+            network: snapshot.network,
+            name: &snapshot.name,
+            descriptor: &snapshot.descriptor,
+            tip: snapshot.tip,
+            settings: &snapshot.settings,
+            changeset: &snapshot.changeset,
+        };
+        let v1_file = serde_json::json!({
+            "format": "bdk_core_backup",
+            "version": 1,
+            "encryption": null,
+            "snapshot": v1,
+            "checksum": sha256::Hash::hash(&serde_json::to_vec(&v1).unwrap()),
+            "sealed": null,
+        });
+        let v1_path = data_dir.path().join("v1.backup");
+        fs::write(&v1_path, serde_json::to_vec(&v1_file).unwrap()).unwrap();
+        assert!(snapshot.imported.is_empty());
+        assert_eq!(read_backup(&v1_path, None).unwrap(), snapshot);
+    });
+}
@@ -0,0 +1,186 @@
+//! Tests of the import and export of descriptors.
+
+use bdk_chain::{
+    bitcoin::{Amount, ScriptBuf},
+    miniscript::{descriptor::checksum::desc_checksum, Descriptor, DescriptorPublicKey},
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use std::{path::Path, str::FromStr, sync::Arc};
+
+use super::{
+    balance, mock_node::payment, mock_node::MockNode, run_local, start_wallet, start_wallet_with,
+    stop_wallet, wallet_spk,
+};
+use crate::{
+    backup::read_backup, pending_rescans, persist::StoreConfig, wallet::BdkWallet, Options,
+    DESCRIPTOR,
+};
+
+/// A descriptor of another branch of the default wallet's key, with its checksum.
+fn branch_descriptor(branch: u32) -> String {
+    let body = DESCRIPTOR.replace("/*)", &format!("/{}/*)", branch));
+    let checksum = desc_checksum(&body).unwrap();
+    format!("{}#{}", body, checksum)
+}
+
+fn branch_spk(branch: u32, index: u32) -> ScriptBuf {
+    Descriptor::<DescriptorPublicKey>::from_str(&branch_descriptor(branch))
+        .unwrap()
+        .at_derivation_index(index)
+        .unwrap()
+        .script_pubkey()
+}
+
+fn import(data_dir: &Path, descriptor: &str, birthday: u32, range_end: Option<u32>) -> bool {
+    let store = StoreConfig::default();
+    BdkWallet::import_descriptor(data_dir, "", descriptor, birthday, range_end, &store).is_ok()
+}
+
+#[test]
+fn import_and_export_descriptors() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(branch_spk(1, 3), Amount::from_sat(5_000))]);
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // Descriptors must come with a valid checksum, not be tracked already, and only be given
+        // a range if they're ranged.
+        let descriptor = branch_descriptor(1);
+        let (body, checksum) = descriptor.rsplit_once('#').unwrap();
+        assert!(!import(data_dir.path(), body, 1, None));
+        let mut wrong_checksum = format!("{}#{}", body, checksum);
+        let last = if wrong_checksum.pop() == Some('q') {
+            'p'
+        } else {
+            'q'
+        };
+        wrong_checksum.push(last);
+        assert!(!import(data_dir.path(), &wrong_checksum, 1, None));
+        let primary = format!("{}#{}", DESCRIPTOR, desc_checksum(DESCRIPTOR).unwrap());
+        assert!(!import(data_dir.path(), &primary, 1, None));
+        let single = DESCRIPTOR.replace("/*)", "/1/0)");
+        let single = format!("{}#{}", single, desc_checksum(&single).unwrap());
+        assert!(!import(data_dir.path(), &single, 1, Some(10)));
+        assert!(BdkWallet::import_descriptor(
+            data_dir.path(),
+            "missing",
+            &descriptor,
+            1,
+            None,
+            &StoreConfig::default()
+        )
+        .is_err());
+        assert!(import(data_dir.path(), &descriptor, 1, Some(30)));
+        assert!(!import(data_dir.path(), &descriptor, 0, None));
+        // Born above the wallet's tip, there is nothing to rescan for it.
+        assert!(import(data_dir.path(), &branch_descriptor(2), 10, None));
+
+        // Blocks connected after the import are processed with the new keychain, the blocks from
+        // its birthday are rescanned once started.
+        node.mine(vec![payment(branch_spk(1, 4), Amount::from_sat(2_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(12_000));
+        let first_payment_time = node.block(1).header.time;
+        let cancel = CancellationToken::new();
+        let rescanned = async {
+            while manager
+                .lock()
+                .unwrap()
+                .wallet("")
+                .unwrap()
+                .export_descriptors()[1]
+                .timestamp
+                != first_payment_time
+            {
+                tokio::task::yield_now().await;
+            }
+            cancel.cancel();
+        };
+        tokio::join!(pending_rescans(&rpc, &manager, &cancel), rescanned);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(17_000));
+        let backup_path = data_dir.path().join("wallet.backup");
+        manager
+            .lock()
+            .unwrap()
+            .backup_wallet("", &backup_path)
+            .unwrap();
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // The imported descriptors are only rescanned once.
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            assert_eq!(wallet.take_pending_rescan(), None);
+            assert_eq!(wallet.keychains(), vec![0, 1, 2]);
+
+            let exported = wallet.export_descriptors();
+            assert_eq!(exported.len(), 3);
+            assert!(exported[0].active);
+            assert_eq!(exported[0].internal, Some(false));
+            assert_eq!(exported[0].timestamp, node.block(2).header.time);
+            assert_eq!(exported[1].desc, descriptor);
+            assert!(!exported[1].active);
+            assert_eq!(exported[1].timestamp, first_payment_time);
+            assert_eq!(exported[1].range, Some([0, 30]));
+            assert_eq!(exported[1].next_index, Some(5));
+            let json = serde_json::to_value(&exported[2]).unwrap();
+            assert_eq!(
+                json,
+                serde_json::json!({
+                    "desc": branch_descriptor(2),
+                    "timestamp": 0,
+                    "active": false,
+                    "range": [0, 24],
+                    "next_index": 0,
+                })
+            );
+        }
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // The imported descriptors are part of the backups.
+        assert_eq!(read_backup(&backup_path, None).unwrap().imported.len(), 2);
+        let restore_dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            data_dir: restore_dir.path().to_path_buf(),
+            restore_wallets: vec![("restored".to_string(), backup_path)],
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("restored").unwrap();
+            assert_eq!(wallet.balance().confirmed, Amount::from_sat(17_000));
+            assert_eq!(wallet.take_pending_rescan(), None);
+        }
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn non_ranged_primary_is_not_exported_active() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let body = DESCRIPTOR.replace("/*)", "/0)");
+    let descriptor = format!("{}#{}", body, desc_checksum(&body).unwrap());
+    let notifier = Arc::new(Notify::new());
+    let store = StoreConfig::default();
+    let wallet =
+        BdkWallet::create(data_dir.path(), "single", &descriptor, &store, notifier).unwrap();
+
+    // Core only accepts ranged descriptors as active ones.
+    let exported = wallet.export_descriptors();
+    assert_eq!(exported.len(), 1);
+    assert!(!exported[0].active);
+    assert_eq!(exported[0].internal, None);
+    assert_eq!(exported[0].range, None);
+    assert_eq!(exported[0].next_index, None);
+}
@@ -0,0 +1,294 @@
+//! Tests of the locking of coins and of coin control.
+
+use bdk_chain::bitcoin::{Address, Amount, FeeRate, OutPoint, Psbt, Transaction};
+use serde_json::{json, Value};
+
+use super::{
+    call_command, mock_node::payment, mock_node::MockNode, run_local, start_wallet, stop_wallet,
+    wallet_spk,
+};
+use crate::{
+    rpc_commands::{RPC_INVALID_PARAMETER, RPC_WALLET_INSUFFICIENT_FUNDS},
+    wallet::{BdkWallet, CoinControl, UtxoLock},
+    NETWORK,
+};
+
+fn coin(tx: &Transaction) -> OutPoint {
+    OutPoint::new(tx.compute_txid(), 0)
+}
+
+fn spent(psbt: &Psbt) -> Vec<OutPoint> {
+    psbt.unsigned_tx
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect()
+}
+
+fn lock(reason: &str, expires: Option<u64>) -> UtxoLock {
+    UtxoLock {
+        reason: reason.to_string(),
+        expires,
+    }
+}
+
+fn create_psbt(wallet: &mut BdkWallet, sat: u64, coins: &CoinControl) -> Result<Psbt, String> {
+    wallet
+        .create_psbt(
+            vec![(wallet_spk(10), Amount::from_sat(sat))],
+            FeeRate::from_sat_per_vb_unchecked(1),
+            None,
+            coins,
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// Payments of 30k, 20k and 10k sats to the wallet.
+fn payments() -> Vec<Transaction> {
+    [30_000, 20_000, 10_000]
+        .into_iter()
+        .enumerate()
+        .map(|(i, sat)| payment(wallet_spk(i as u32), Amount::from_sat(sat)))
+        .collect()
+}
+
+#[test]
+fn coin_control() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let payments = payments();
+        node.mine(payments.clone());
+        let (large, medium, small) = (coin(&payments[0]), coin(&payments[1]), coin(&payments[2]));
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            let no_control = CoinControl::default();
+
+            // The largest coin is reserved, the next one is selected instead.
+            wallet.lock_utxos(&[large], lock("payout 1", None)).unwrap();
+            assert_eq!(wallet.locked_utxos(0), [(large, lock("payout 1", None))]);
+            let psbt = create_psbt(wallet, 15_000, &no_control).unwrap();
+            assert_eq!(spent(&psbt), [medium]);
+            let exclude = CoinControl {
+                exclude: vec![medium],
+                ..Default::default()
+            };
+            assert!(create_psbt(wallet, 15_000, &exclude)
+                .unwrap_err()
+                .contains("Insufficient funds"));
+
+            // Included coins are spent first, even if locked.
+            let include = |include| CoinControl {
+                include,
+                ..Default::default()
+            };
+            let psbt = create_psbt(wallet, 15_000, &include(vec![large])).unwrap();
+            assert_eq!(spent(&psbt), [large]);
+            let psbt = create_psbt(wallet, 15_000, &include(vec![small])).unwrap();
+            assert_eq!(spent(&psbt), [small, medium]);
+            let psbt = create_psbt(wallet, 1_000, &include(vec![small, medium])).unwrap();
+            assert_eq!(spent(&psbt), [medium, small]);
+            let both = CoinControl {
+                include: vec![small],
+                exclude: vec![small],
+                ..Default::default()
+            };
+            assert!(create_psbt(wallet, 1_000, &both)
+                .unwrap_err()
+                .contains("both included and excluded"));
+            let unknown = OutPoint::new(large.txid, 1);
+            assert!(create_psbt(wallet, 1_000, &include(vec![unknown]))
+                .unwrap_err()
+                .contains("not an unspent coin"));
+            assert!(wallet.lock_utxos(&[unknown], lock("", None)).is_err());
+
+            // A lock is only in force until it expires.
+            wallet
+                .lock_utxos(&[medium], lock("payout 2", Some(1_000)))
+                .unwrap();
+            let at = |time| CoinControl {
+                exclude: vec![small],
+                time,
+                ..Default::default()
+            };
+            assert!(create_psbt(wallet, 15_000, &at(999)).is_err());
+            assert_eq!(wallet.locked_utxos(999).len(), 2);
+            let psbt = create_psbt(wallet, 15_000, &at(1_000)).unwrap();
+            assert_eq!(spent(&psbt), [medium]);
+            assert_eq!(wallet.locked_utxos(1_000).len(), 1);
+
+            // The coins spent by a PSBT can be locked along with its creation.
+            let locking = CoinControl {
+                lock: Some(lock("payout 3", None)),
+                time: 1_000,
+                ..Default::default()
+            };
+            let psbt = create_psbt(wallet, 15_000, &locking).unwrap();
+            assert_eq!(spent(&psbt), [medium]);
+            let locks = wallet.locked_utxos(1_000);
+            assert_eq!(locks.len(), 2);
+            assert!(locks.contains(&(large, lock("payout 1", None))));
+            assert!(locks.contains(&(medium, lock("payout 3", None))));
+            let psbt = create_psbt(wallet, 5_000, &locking).unwrap();
+            assert_eq!(spent(&psbt), [small]);
+            assert_eq!(wallet.unlock_utxos(Some(&[small])).unwrap(), [small]);
+            assert_eq!(wallet.unlock_utxos(Some(&[small])).unwrap(), []);
+        }
+        stop_wallet(rpc, subscription).await;
+
+        // The locks are persisted.
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let call = |name, params| call_command(&rpc, &manager, None, name, params);
+        let listed = || async { call("listlockunspent", Value::Null).await.unwrap() };
+        let reasons = |listed: Value| -> Vec<String> {
+            let mut reasons: Vec<_> = listed
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["reason"].as_str().unwrap().to_string())
+                .collect();
+            reasons.sort();
+            reasons
+        };
+        assert_eq!(reasons(listed().await), ["payout 1", "payout 3"]);
+
+        let outpoint = |op: OutPoint| json!({"txid": op.txid.to_string(), "vout": op.vout});
+        let params = json!([true, [outpoint(large)]]);
+        assert_eq!(call("lockunspent", params).await.unwrap(), json!(true));
+        assert_eq!(reasons(listed().await), ["payout 3"]);
+        assert_eq!(
+            call("lockunspent", json!([true])).await.unwrap(),
+            json!(true)
+        );
+        assert_eq!(listed().await, json!([]));
+        let params = json!({
+            "unlock": false,
+            "transactions": [outpoint(small)],
+            "reason": "payout 4",
+            "expires": 2_000_000_000,
+        });
+        call("lockunspent", params).await.unwrap();
+        assert_eq!(
+            listed().await,
+            json!([{
+                "txid": small.txid.to_string(),
+                "vout": 0,
+                "reason": "payout 4",
+                "expires": 2_000_000_000,
+            }])
+        );
+        let params = json!([false, [outpoint(OutPoint::new(small.txid, 1))]]);
+        assert!(call("lockunspent", params).await.is_err());
+
+        let address = Address::from_script(&wallet_spk(10), NETWORK).unwrap();
+        let params = json!({
+            "outputs": {address.to_string(): 0.00015},
+            "exclude": [outpoint(large)],
+            "lock": "payout 5",
+        });
+        let created = call("createpsbt", params).await.unwrap();
+        let psbt: Psbt = created["psbt"].as_str().unwrap().parse().unwrap();
+        assert_eq!(spent(&psbt), [medium]);
+        assert_eq!(reasons(listed().await), ["payout 4", "payout 5"]);
+
+        // Only a shortfall of funds is reported as such.
+        let params = json!({"outputs": {address.to_string(): 1.0}});
+        let e = call("createpsbt", params).await.unwrap_err();
+        assert_eq!(e.code, RPC_WALLET_INSUFFICIENT_FUNDS);
+        let unknown = outpoint(OutPoint::new(small.txid, 1));
+        let params = json!({"outputs": {address.to_string(): 0.0001}, "include": [unknown]});
+        let e = call("createpsbt", params).await.unwrap_err();
+        assert_eq!(e.code, RPC_INVALID_PARAMETER);
+
+        // Amounts above the total supply are rejected rather than overflowing.
+        let params = json!({"outputs": {address.to_string(): 184467440737.0}});
+        let e = call("createpsbt", params).await.unwrap_err();
+        assert_eq!(e.code, RPC_INVALID_PARAMETER);
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            let recipients = vec![(wallet_spk(10), Amount::MAX_MONEY); 2];
+            let feerate = FeeRate::from_sat_per_vb_unchecked(1);
+            let e = wallet
+                .create_psbt(recipients, feerate, None, &CoinControl::default())
+                .unwrap_err();
+            assert!(e.to_string().contains("exceed the total supply"));
+            let recipients = vec![(wallet_spk(10), Amount::MAX); 2];
+            let coins = CoinControl::default();
+            assert!(wallet
+                .create_psbt(recipients, feerate, None, &coins)
+                .is_err());
+        }
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn locks_are_released_with_their_spend() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(payments());
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let locked = || {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            wallet
+                .locked_utxos(0)
+                .into_iter()
+                .map(|(outpoint, _)| outpoint)
+                .collect::<Vec<_>>()
+        };
+
+        // Two payment jobs reserve their coins: they can't select the same ones.
+        let (first, second) = {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            let locking = |reason: &str| CoinControl {
+                lock: Some(lock(reason, None)),
+                ..Default::default()
+            };
+            let first = create_psbt(wallet, 15_000, &locking("job 1")).unwrap();
+            let second = create_psbt(wallet, 15_000, &locking("job 2")).unwrap();
+            (first, second)
+        };
+        assert_ne!(spent(&first), spent(&second));
+        assert_eq!(locked().len(), 2);
+
+        // The first transaction is broadcast, then replaced: its coins are still being spent by
+        // the replacement.
+        node.add_to_mempool(&first.unsigned_tx).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(locked().len(), 2);
+        let mut replacement = first.unsigned_tx.clone();
+        replacement.output[0].value -= Amount::from_sat(1_000);
+        node.remove_from_mempool(&first.unsigned_tx, 5).await;
+        node.add_to_mempool(&replacement).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(locked().len(), 2);
+
+        // The replacement is evicted from the mempool, which releases them.
+        node.remove_from_mempool(&replacement, 0).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(locked(), spent(&second));
+
+        // The second one confirms.
+        node.connect_block(vec![second.unsigned_tx.clone()]).await;
+        subscription.flush().await.unwrap();
+        assert!(locked().is_empty());
+        stop_wallet(rpc, subscription).await;
+
+        // The releases were persisted.
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert!(manager
+            .lock()
+            .unwrap()
+            .wallet("")
+            .unwrap()
+            .locked_utxos(0)
+            .is_empty());
+        stop_wallet(rpc, subscription).await;
+    });
+}
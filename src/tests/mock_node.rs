@@ -0,0 +1,665 @@
+//! An in-process stand-in for `bitcoin-node`, serving the `Init` and `Chain` IPC interfaces over a
+//! Unix socket pair. Tests script its chain and mempool and push notifications to the wallet on
+//! demand.
+
+use bdk_chain::bitcoin::{
+    self, absolute, block, consensus::encode::serialize, hashes::Hash, transaction, Amount, Block,
+    BlockHash, CompactTarget, OutPoint, ScriptBuf, Transaction, TxIn, TxMerkleNode, TxOut,
+};
+use capnp::capability::Promise;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use tokio::net::UnixStream;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    chain_capnp::{chain, chain_notifications, found_block_result},
+    handler_capnp::handler,
+    init_capnp::init,
+    proxy_capnp::{thread, thread_map},
+    NETWORK,
+};
+
+/// The state of the mock node, shared by the servers of the interfaces it exposes.
+#[derive(Default)]
+struct NodeState {
+    /// All the blocks we know about, including stale ones, with their height.
+    blocks: HashMap<BlockHash, (u32, Block)>,
+    /// The hashes of the blocks in the active chain, indexed by height.
+    active: Vec<BlockHash>,
+    /// The subscribers to our notifications, by id.
+    subscribers: Vec<(u64, chain_notifications::Client)>,
+    /// The id of the next subscriber.
+    next_subscriber: u64,
+    /// Connected blocks not notified yet, like in Core's validation interface queue.
+    queued: Vec<Block>,
+    /// The (title, progress) reported by the wallet through showProgress.
+    progress: Vec<(String, i32)>,
+    /// Used to make every block we create unique.
+    nonce: u32,
+    /// Fail the requests for the tip and for blocks, as if the node hit an internal error.
+    failing: bool,
+}
+
+impl NodeState {
+    fn tip(&self) -> BlockHash {
+        *self.active.last().expect("Always has a genesis")
+    }
+
+    /// The ancestor of this block at this height, if any.
+    fn ancestor(&self, hash: &BlockHash, height: u32) -> Option<(u32, &Block)> {
+        let (mut h, mut block) = self.blocks.get(hash).map(|(h, b)| (*h, b))?;
+        while h > height {
+            (h, block) = self
+                .blocks
+                .get(&block.header.prev_blockhash)
+                .map(|(h, b)| (*h, b))?;
+        }
+        (h == height).then_some((h, block))
+    }
+
+    fn is_ancestor(&self, hash: &BlockHash, ancestor: &BlockHash) -> bool {
+        let Some((height, _)) = self.blocks.get(ancestor) else {
+            return false;
+        };
+        self.ancestor(hash, *height)
+            .is_some_and(|(_, b)| b.block_hash() == *ancestor)
+    }
+
+    /// Create a block on top of this one, containing these transactions, whose coinbase pays the
+    /// block reward to this script.
+    fn new_block(
+        &mut self,
+        prev_hash: BlockHash,
+        coinbase_script: ScriptBuf,
+        txs: Vec<Transaction>,
+    ) -> Block {
+        let height = self.blocks[&prev_hash].0 + 1;
+        self.nonce += 1;
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                script_sig: ScriptBuf::from_bytes(
+                    [height.to_le_bytes(), self.nonce.to_le_bytes()].concat(),
+                ),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50 * 100_000_000),
+                script_pubkey: coinbase_script,
+            }],
+        };
+        let mut block = Block {
+            header: block::Header {
+                version: block::Version::TWO,
+                prev_blockhash: prev_hash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 1_296_688_602 + height * 600,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: self.nonce,
+            },
+            txdata: [coinbase].into_iter().chain(txs).collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("Not empty");
+        self.blocks
+            .insert(block.block_hash(), (height, block.clone()));
+        block
+    }
+}
+
+/// A mock `bitcoin-node`. Cheap to clone, all clones share the same state.
+#[derive(Clone)]
+pub struct MockNode {
+    state: Rc<RefCell<NodeState>>,
+}
+
+impl MockNode {
+    /// A node with only the genesis block of our network.
+    pub fn new() -> Self {
+        let genesis = bitcoin::constants::genesis_block(NETWORK);
+        let mut state = NodeState::default();
+        state.active.push(genesis.block_hash());
+        state.blocks.insert(genesis.block_hash(), (0, genesis));
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// Start serving a new connection. Returns the client end of the socket, to be passed to
+    /// [`crate::rpc_interface::RpcInterface::new`]. Must be called within a `LocalSet`.
+    pub fn connect(&self) -> UnixStream {
+        let (client, server) = UnixStream::pair().expect("Creating a socket pair");
+        let (reader, writer) = server.into_split();
+        let network = Box::new(twoparty::VatNetwork::new(
+            reader.compat(),
+            writer.compat_write(),
+            rpc_twoparty_capnp::Side::Server,
+            Default::default(),
+        ));
+        let init: init::Client = capnp_rpc::new_client(InitServer(self.clone()));
+        tokio::task::spawn_local(RpcSystem::new(network, Some(init.client)));
+        client
+    }
+
+    pub fn tip(&self) -> bitcoin::BlockHash {
+        self.state.borrow().tip()
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.state.borrow().active.len() - 1) as u32
+    }
+
+    /// The block at this height in the active chain.
+    pub fn block(&self, height: u32) -> Block {
+        let state = self.state.borrow();
+        state.blocks[&state.active[height as usize]].1.clone()
+    }
+
+    /// What was reported by the wallet through `showProgress`.
+    pub fn progress(&self) -> Vec<(String, i32)> {
+        self.state.borrow().progress.clone()
+    }
+
+    /// Extend the active chain with a block containing these transactions, without notifying.
+    pub fn mine(&self, txs: Vec<Transaction>) -> Block {
+        self.mine_to(ScriptBuf::new_op_return([]), txs)
+    }
+
+    /// Like [`Self::mine`], with the block reward paid to this script.
+    pub fn mine_to(&self, coinbase_script: ScriptBuf, txs: Vec<Transaction>) -> Block {
+        let mut state = self.state.borrow_mut();
+        let tip = state.tip();
+        let block = state.new_block(tip, coinbase_script, txs);
+        state.active.push(block.block_hash());
+        block
+    }
+
+    /// Replace the `depth` last blocks of the active chain with blocks containing these
+    /// transactions, without notifying. Returns the disconnected and the connected blocks.
+    pub fn reorg(&self, depth: u32, new_blocks: Vec<Vec<Transaction>>) -> (Vec<Block>, Vec<Block>) {
+        let disconnected = {
+            let mut state = self.state.borrow_mut();
+            assert!(
+                (depth as usize) < state.active.len(),
+                "Can't reorg the genesis"
+            );
+            let fork_height = state.active.len() - depth as usize;
+            let hashes = state.active.split_off(fork_height);
+            hashes
+                .into_iter()
+                .rev()
+                .map(|h| state.blocks[&h].1.clone())
+                .collect()
+        };
+        let connected = new_blocks.into_iter().map(|txs| self.mine(txs)).collect();
+        (disconnected, connected)
+    }
+
+    /// Mine a block and notify it to the subscribers.
+    pub async fn connect_block(&self, txs: Vec<Transaction>) -> Block {
+        let block = self.mine(txs);
+        self.notify_connected(&block).await;
+        self.notify_updated_tip().await;
+        block
+    }
+
+    /// Mine a block but only queue its notification, until the wallet asks for it through
+    /// `waitForNotificationsIfTipChanged`.
+    pub fn mine_queued(&self, txs: Vec<Transaction>) -> Block {
+        let block = self.mine(txs);
+        self.state.borrow_mut().queued.push(block.clone());
+        block
+    }
+
+    /// Notify the blocks connected through [`Self::mine_queued`].
+    pub async fn flush_queued(&self) {
+        let queued = std::mem::take(&mut self.state.borrow_mut().queued);
+        for block in &queued {
+            self.notify_connected(block).await;
+        }
+        if !queued.is_empty() {
+            self.notify_updated_tip().await;
+        }
+    }
+
+    /// Reorg the chain and notify the subscribers, disconnecting the blocks tip first.
+    pub async fn reorg_and_notify(
+        &self,
+        depth: u32,
+        new_blocks: Vec<Vec<Transaction>>,
+    ) -> Vec<Block> {
+        let (disconnected, connected) = self.reorg(depth, new_blocks);
+        for block in &disconnected {
+            self.notify_disconnected(block).await;
+        }
+        for block in &connected {
+            self.notify_connected(block).await;
+        }
+        self.notify_updated_tip().await;
+        connected
+    }
+
+    pub async fn notify_connected(&self, block: &Block) {
+        let height = self.state.borrow().blocks[&block.block_hash()].0;
+        let mut delivered = Vec::new();
+        for (id, sub) in self.subscribers() {
+            let mut req = sub.block_connected_request();
+            let mut info = req.get().init_block();
+            info.set_hash(block.block_hash().as_ref());
+            info.set_prev_hash(block.header.prev_blockhash.as_ref());
+            info.set_height(height as i32);
+            info.set_data(&serialize(block));
+            delivered.push((id, req.send().promise.await.map(|_| ())));
+        }
+        self.drop_disconnected(delivered, "Notifying connected block");
+    }
+
+    pub async fn notify_disconnected(&self, block: &Block) {
+        let height = self.state.borrow().blocks[&block.block_hash()].0;
+        let mut delivered = Vec::new();
+        for (id, sub) in self.subscribers() {
+            let mut req = sub.block_disconnected_request();
+            let mut info = req.get().init_block();
+            info.set_hash(block.block_hash().as_ref());
+            info.set_prev_hash(block.header.prev_blockhash.as_ref());
+            info.set_height(height as i32);
+            info.set_data(&serialize(block));
+            delivered.push((id, req.send().promise.await.map(|_| ())));
+        }
+        self.drop_disconnected(delivered, "Notifying disconnected block");
+    }
+
+    pub async fn notify_updated_tip(&self) {
+        let mut delivered = Vec::new();
+        for (id, sub) in self.subscribers() {
+            let req = sub.updated_block_tip_request();
+            delivered.push((id, req.send().promise.await.map(|_| ())));
+        }
+        self.drop_disconnected(delivered, "Notifying updated tip");
+    }
+
+    /// Notify the subscribers of a transaction entering the mempool.
+    pub async fn add_to_mempool(&self, tx: &Transaction) {
+        self.add_raw_to_mempool(&serialize(tx)).await
+    }
+
+    /// Notify the subscribers of a serialized transaction entering the mempool, valid or not.
+    pub async fn add_raw_to_mempool(&self, tx: &[u8]) {
+        let mut delivered = Vec::new();
+        for (id, sub) in self.subscribers() {
+            let mut req = sub.transaction_added_to_mempool_request();
+            req.get().set_tx(tx);
+            delivered.push((id, req.send().promise.await.map(|_| ())));
+        }
+        self.drop_disconnected(delivered, "Notifying mempool tx");
+    }
+
+    /// Notify the subscribers of a transaction leaving the mempool, for instance evicted when it
+    /// expired (reason 0 in Core's `MemPoolRemovalReason`).
+    pub async fn remove_from_mempool(&self, tx: &Transaction, reason: i32) {
+        let mut delivered = Vec::new();
+        for (id, sub) in self.subscribers() {
+            let mut req = sub.transaction_removed_from_mempool_request();
+            req.get().set_tx(&serialize(tx));
+            req.get().set_reason(reason);
+            delivered.push((id, req.send().promise.await.map(|_| ())));
+        }
+        self.drop_disconnected(delivered, "Notifying removed mempool tx");
+    }
+
+    // Like Core, forget about the subscribers of wallets which went away.
+    fn drop_disconnected(&self, delivered: Vec<(u64, Result<(), capnp::Error>)>, what: &str) {
+        for (id, res) in delivered {
+            match res {
+                Ok(()) => {}
+                Err(e) if e.kind == capnp::ErrorKind::Disconnected => {
+                    self.unsubscribe(id);
+                }
+                Err(e) => panic!("{}: {}", what, e),
+            }
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) -> Option<chain_notifications::Client> {
+        let mut state = self.state.borrow_mut();
+        let i = state
+            .subscribers
+            .iter()
+            .position(|(sub_id, _)| *sub_id == id)?;
+        Some(state.subscribers.remove(i).1)
+    }
+
+    /// Make the requests for the tip and for blocks fail, or succeed again.
+    pub fn set_failing(&self, failing: bool) {
+        self.state.borrow_mut().failing = failing;
+    }
+
+    /// Release the registrations of all the subscribers, as Core does when it stops notifying.
+    pub async fn release_subscribers(&self) {
+        let subscribers = std::mem::take(&mut self.state.borrow_mut().subscribers);
+        for (_, sub) in subscribers {
+            sub.destroy_request()
+                .send()
+                .promise
+                .await
+                .expect("Destroying subscription");
+        }
+    }
+
+    /// The number of wallets subscribed to our notifications.
+    pub fn subscriber_count(&self) -> usize {
+        self.state.borrow().subscribers.len()
+    }
+
+    // Don't hold the borrow across awaits, the servers need it to answer the wallet.
+    fn subscribers(&self) -> Vec<(u64, chain_notifications::Client)> {
+        self.state.borrow().subscribers.clone()
+    }
+}
+
+/// A transaction paying this amount to this script. It spends a made-up coin, which is fine as
+/// the wallet doesn't validate transactions.
+pub fn payment(script_pubkey: ScriptBuf, amount: Amount) -> Transaction {
+    let mut prev_txid = [0; 32];
+    prev_txid[..8].copy_from_slice(&amount.to_sat().to_le_bytes());
+    prev_txid[8..16].copy_from_slice(&script_pubkey.len().to_le_bytes());
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(bitcoin::Txid::from_byte_array(prev_txid), 0),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: amount,
+            script_pubkey,
+        }],
+    }
+}
+
+fn block_hash(data: &[u8]) -> Result<BlockHash, capnp::Error> {
+    BlockHash::from_slice(data).map_err(|e| capnp::Error::failed(e.to_string()))
+}
+
+fn set_found_block(
+    mut result: found_block_result::Builder,
+    (height, block): (u32, &Block),
+    want_data: bool,
+) {
+    result.set_found(true);
+    result.set_hash(block.block_hash().as_ref());
+    result.set_height(height as i32);
+    if want_data {
+        result.set_data(&serialize(block));
+    }
+}
+
+struct InitServer(MockNode);
+
+impl init::Server for InitServer {
+    fn construct(
+        &mut self,
+        _: init::ConstructParams,
+        mut results: init::ConstructResults,
+    ) -> Promise<(), capnp::Error> {
+        results
+            .get()
+            .set_thread_map(capnp_rpc::new_client(ThreadMapServer));
+        Promise::ok(())
+    }
+
+    fn make_chain(
+        &mut self,
+        _: init::MakeChainParams,
+        mut results: init::MakeChainResults,
+    ) -> Promise<(), capnp::Error> {
+        results
+            .get()
+            .set_result(capnp_rpc::new_client(ChainServer(self.0.clone())));
+        Promise::ok(())
+    }
+}
+
+struct ThreadMapServer;
+
+impl thread_map::Server for ThreadMapServer {
+    fn make_thread(
+        &mut self,
+        _: thread_map::MakeThreadParams,
+        mut results: thread_map::MakeThreadResults,
+    ) -> Promise<(), capnp::Error> {
+        results
+            .get()
+            .set_result(capnp_rpc::new_client(ThreadServer));
+        Promise::ok(())
+    }
+}
+
+struct ThreadServer;
+
+impl thread::Server for ThreadServer {}
+
+/// Unsubscribes the wallet from notifications upon disconnection, like Core's.
+struct HandlerServer {
+    node: MockNode,
+    subscriber: u64,
+}
+
+impl handler::Server for HandlerServer {
+    fn disconnect(
+        &mut self,
+        _: handler::DisconnectParams,
+        _: handler::DisconnectResults,
+    ) -> Promise<(), capnp::Error> {
+        let Some(sub) = self.node.unsubscribe(self.subscriber) else {
+            return Promise::ok(());
+        };
+        // Core releases its reference to the notifications interface, which destroys it.
+        Promise::from_future(async move {
+            sub.destroy_request().send().promise.await?;
+            Ok(())
+        })
+    }
+}
+
+struct ChainServer(MockNode);
+
+impl chain::Server for ChainServer {
+    fn get_height(
+        &mut self,
+        _: chain::GetHeightParams,
+        mut results: chain::GetHeightResults,
+    ) -> Promise<(), capnp::Error> {
+        if self.0.state.borrow().failing {
+            return Promise::err(capnp::Error::failed("Failing getHeight".to_string()));
+        }
+        results.get().set_result(self.0.height() as i32);
+        results.get().set_has_result(true);
+        Promise::ok(())
+    }
+
+    fn get_block_hash(
+        &mut self,
+        params: chain::GetBlockHashParams,
+        mut results: chain::GetBlockHashResults,
+    ) -> Promise<(), capnp::Error> {
+        let height = pry!(params.get()).get_height();
+        let state = self.0.state.borrow();
+        let Some(hash) = state.active.get(height as usize) else {
+            return Promise::err(capnp::Error::failed(format!("No block at {}", height)));
+        };
+        results.get().set_result(hash.as_ref());
+        Promise::ok(())
+    }
+
+    fn find_ancestor_by_hash(
+        &mut self,
+        params: chain::FindAncestorByHashParams,
+        mut results: chain::FindAncestorByHashResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let hash = pry!(block_hash(pry!(params.get_block_hash())));
+        let ancestor = pry!(block_hash(pry!(params.get_ancestor_hash())));
+        let state = self.0.state.borrow();
+        let found = state.is_ancestor(&hash, &ancestor);
+        if found {
+            let height = state.blocks[&ancestor].0;
+            let want_data = pry!(params.get_ancestor()).get_want_data();
+            let ancestor = state.ancestor(&hash, height).expect("Just checked");
+            set_found_block(results.get().init_ancestor(), ancestor, want_data);
+        }
+        results.get().set_result(found);
+        Promise::ok(())
+    }
+
+    fn find_ancestor_by_height(
+        &mut self,
+        params: chain::FindAncestorByHeightParams,
+        mut results: chain::FindAncestorByHeightResults,
+    ) -> Promise<(), capnp::Error> {
+        if self.0.state.borrow().failing {
+            return Promise::err(capnp::Error::failed(
+                "Failing findAncestorByHeight".to_string(),
+            ));
+        }
+        let params = pry!(params.get());
+        let hash = pry!(block_hash(pry!(params.get_block_hash())));
+        let Ok(height) = params.get_ancestor_height().try_into() else {
+            return Promise::ok(());
+        };
+        let want_data = pry!(params.get_ancestor()).get_want_data();
+        let state = self.0.state.borrow();
+        if let Some(ancestor) = state.ancestor(&hash, height) {
+            set_found_block(results.get().init_ancestor(), ancestor, want_data);
+            results.get().set_result(true);
+        }
+        Promise::ok(())
+    }
+
+    fn find_common_ancestor(
+        &mut self,
+        params: chain::FindCommonAncestorParams,
+        mut results: chain::FindCommonAncestorResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let hash1 = pry!(block_hash(pry!(params.get_block_hash1())));
+        let hash2 = pry!(block_hash(pry!(params.get_block_hash2())));
+        let want_data = pry!(params.get_ancestor()).get_want_data();
+        let state = self.0.state.borrow();
+        let (Some(&(h1, _)), Some(&(h2, _))) = (state.blocks.get(&hash1), state.blocks.get(&hash2))
+        else {
+            return Promise::ok(());
+        };
+        let mut height = h1.min(h2);
+        loop {
+            let a1 = state.ancestor(&hash1, height).expect("Below the tip");
+            let a2 = state.ancestor(&hash2, height).expect("Below the tip");
+            if a1.1.block_hash() == a2.1.block_hash() {
+                set_found_block(results.get().init_ancestor(), a1, want_data);
+                results.get().set_result(true);
+                return Promise::ok(());
+            }
+            // Genesis is always common.
+            height -= 1;
+        }
+    }
+
+    fn has_blocks(
+        &mut self,
+        params: chain::HasBlocksParams,
+        mut results: chain::HasBlocksResults,
+    ) -> Promise<(), capnp::Error> {
+        // We never prune.
+        let hash = pry!(block_hash(pry!(pry!(params.get()).get_block_hash())));
+        results
+            .get()
+            .set_result(self.0.state.borrow().blocks.contains_key(&hash));
+        Promise::ok(())
+    }
+
+    fn find_coins(
+        &mut self,
+        params: chain::FindCoinsParams,
+        mut results: chain::FindCoinsResults,
+    ) -> Promise<(), capnp::Error> {
+        // Report all coins as not found.
+        let coins = pry!(pry!(params.get()).get_coins());
+        let mut res = results.get().init_coins(coins.len());
+        for i in 0..coins.len() {
+            pry!(res.reborrow().get(i).set_key(pry!(coins.get(i).get_key())));
+        }
+        Promise::ok(())
+    }
+
+    fn is_initial_block_download(
+        &mut self,
+        _: chain::IsInitialBlockDownloadParams,
+        mut results: chain::IsInitialBlockDownloadResults,
+    ) -> Promise<(), capnp::Error> {
+        results.get().set_result(false);
+        Promise::ok(())
+    }
+
+    fn init_message(
+        &mut self,
+        _: chain::InitMessageParams,
+        _: chain::InitMessageResults,
+    ) -> Promise<(), capnp::Error> {
+        Promise::ok(())
+    }
+
+    fn show_progress(
+        &mut self,
+        params: chain::ShowProgressParams,
+        _: chain::ShowProgressResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let title = pry!(pry!(params.get_title()).to_string());
+        self.0
+            .state
+            .borrow_mut()
+            .progress
+            .push((title, params.get_progress()));
+        Promise::ok(())
+    }
+
+    fn wait_for_notifications_if_tip_changed(
+        &mut self,
+        params: chain::WaitForNotificationsIfTipChangedParams,
+        _: chain::WaitForNotificationsIfTipChangedResults,
+    ) -> Promise<(), capnp::Error> {
+        let old_tip = pry!(block_hash(pry!(pry!(params.get()).get_old_tip())));
+        if old_tip == self.0.tip() {
+            return Promise::ok(());
+        }
+        let node = self.0.clone();
+        Promise::from_future(async move {
+            node.flush_queued().await;
+            Ok(())
+        })
+    }
+
+    fn handle_notifications(
+        &mut self,
+        params: chain::HandleNotificationsParams,
+        mut results: chain::HandleNotificationsResults,
+    ) -> Promise<(), capnp::Error> {
+        let notifications = pry!(pry!(params.get()).get_notifications());
+        let subscriber = {
+            let mut state = self.0.state.borrow_mut();
+            let id = state.next_subscriber;
+            state.next_subscriber += 1;
+            state.subscribers.push((id, notifications));
+            id
+        };
+        results
+            .get()
+            .set_result(capnp_rpc::new_client(HandlerServer {
+                node: self.0.clone(),
+                subscriber,
+            }));
+        Promise::ok(())
+    }
+}
@@ -0,0 +1,798 @@
+//! End-to-end tests of the wallet against a mock `bitcoin-node`.
+
+mod backup;
+mod descriptors;
+mod locks;
+mod mock_node;
+mod persist;
+mod policy;
+mod psbt;
+mod reorgs;
+
+use bdk_chain::{
+    bitcoin::{Amount, FeeRate, OutPoint, ScriptBuf},
+    miniscript::{Descriptor, DescriptorPublicKey},
+    Balance, BlockId,
+};
+use serde_json::{json, Value};
+
+use std::{
+    future::Future,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    notifications::Subscription,
+    rpc_commands::{RpcError, RPC_INVALID_PARAMETER, WALLET_COMMANDS},
+    rpc_interface::RpcInterface,
+    wallet::{BdkWallet, CoinControl, TxFilter},
+    wallet_manager::WalletManager,
+    wallet_startup, Options, DESCRIPTOR,
+};
+use mock_node::{payment, MockNode};
+
+/// Run this future to completion on a single-threaded runtime, as in `main`.
+fn run_local<F: Future>(fut: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Building runtime");
+    tokio::task::LocalSet::new().block_on(&rt, fut)
+}
+
+/// The script pubkey of the default wallet at this derivation index.
+fn wallet_spk(index: u32) -> ScriptBuf {
+    Descriptor::<DescriptorPublicKey>::from_str(DESCRIPTOR)
+        .expect("Valid descriptor")
+        .at_derivation_index(index)
+        .expect("Not hardened")
+        .script_pubkey()
+}
+
+/// Connect to the node and start the default wallet persisted in this directory.
+async fn start_wallet(
+    node: &MockNode,
+    data_dir: &Path,
+) -> (RpcInterface, Arc<Mutex<WalletManager>>, Subscription) {
+    let options = Options {
+        data_dir: data_dir.to_path_buf(),
+        ..Default::default()
+    };
+    start_wallet_with(node, options).await
+}
+
+/// Connect to the node and start the wallets with these options.
+async fn start_wallet_with(
+    node: &MockNode,
+    options: Options,
+) -> (RpcInterface, Arc<Mutex<WalletManager>>, Subscription) {
+    let rpc = RpcInterface::new(node.connect())
+        .await
+        .expect("Connecting to the mock node");
+    let (manager, subscription) = wallet_startup(&rpc, &options)
+        .await
+        .expect("Starting the wallet");
+    (rpc, manager, subscription)
+}
+
+/// Unsubscribe once the notifications received so far were processed, and disconnect.
+async fn stop_wallet(rpc: RpcInterface, subscription: Subscription) {
+    subscription.disconnect(&rpc).await.expect("Unsubscribing");
+    rpc.disconnect().await.expect("Disconnecting");
+}
+
+/// Run this wallet command, as requested through one of the RPC servers.
+async fn call_command(
+    rpc: &RpcInterface,
+    manager: &Arc<Mutex<WalletManager>>,
+    wallet_name: Option<&str>,
+    name: &str,
+    params: Value,
+) -> Result<Value, RpcError> {
+    let command = WALLET_COMMANDS.iter().find(|c| c.name == name).unwrap();
+    command.call(rpc, manager, wallet_name, &params).await
+}
+
+fn wallet_tip(manager: &Arc<Mutex<WalletManager>>) -> BlockId {
+    manager.lock().unwrap().wallet("").unwrap().tip()
+}
+
+fn balance(manager: &Arc<Mutex<WalletManager>>) -> Balance {
+    manager.lock().unwrap().wallet("").unwrap().balance()
+}
+
+#[test]
+fn startup_catch_up() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        for _ in 0..5 {
+            node.mine(vec![]);
+        }
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        for _ in 0..4 {
+            node.mine(vec![]);
+        }
+
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(wallet_tip(&manager).height, 10);
+        assert_eq!(manager.lock().unwrap().tip().hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+        assert!(node
+            .progress()
+            .contains(&("BDK Core startup".to_string(), 100)));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn block_notifications() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(balance(&manager).total(), Amount::ZERO);
+
+        node.connect_block(vec![payment(wallet_spk(1), Amount::from_sat(20_000))])
+            .await;
+
+        subscription.flush().await.unwrap();
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(manager.lock().unwrap().tip().hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(20_000));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn mempool_then_confirmation() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        let tx = payment(wallet_spk(0), Amount::from_sat(30_000));
+        node.add_to_mempool(&tx).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(
+            balance(&manager).untrusted_pending,
+            Amount::from_sat(30_000)
+        );
+        assert_eq!(balance(&manager).trusted_pending, Amount::ZERO);
+        assert_eq!(balance(&manager).confirmed, Amount::ZERO);
+
+        node.connect_block(vec![tx]).await;
+
+        subscription.flush().await.unwrap();
+        assert_eq!(balance(&manager).untrusted_pending, Amount::ZERO);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(30_000));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn only_our_own_unconfirmed_spends_are_trusted() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(30_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        // The change of our own spend is trusted.
+        let psbt = manager
+            .lock()
+            .unwrap()
+            .wallet_mut("")
+            .unwrap()
+            .create_psbt(
+                vec![(ScriptBuf::from_bytes(vec![0x51]), Amount::from_sat(10_000))],
+                FeeRate::from_sat_per_vb_unchecked(1),
+                None,
+                &CoinControl::default(),
+            )
+            .unwrap();
+        node.add_to_mempool(&psbt.unsigned_tx).await;
+        subscription.flush().await.unwrap();
+        let change = psbt.unsigned_tx.output[1].value;
+        assert_eq!(balance(&manager).confirmed, Amount::ZERO);
+        assert_eq!(balance(&manager).trusted_pending, change);
+        assert_eq!(balance(&manager).untrusted_pending, Amount::ZERO);
+
+        // A payment from a third party isn't, nor is a transaction spending it even to ourselves.
+        let incoming = payment(wallet_spk(1), Amount::from_sat(20_000));
+        let mut spend = payment(wallet_spk(2), Amount::from_sat(19_000));
+        spend.input[0].previous_output = OutPoint::new(incoming.compute_txid(), 0);
+        node.add_to_mempool(&incoming).await;
+        node.add_to_mempool(&spend).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(balance(&manager).trusted_pending, change);
+        assert_eq!(
+            balance(&manager).untrusted_pending,
+            Amount::from_sat(19_000)
+        );
+
+        // Once confirmed, the third party payment's spend is trusted.
+        node.connect_block(vec![incoming]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(
+            balance(&manager).trusted_pending,
+            change + Amount::from_sat(19_000)
+        );
+        assert_eq!(balance(&manager).untrusted_pending, Amount::ZERO);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn invalid_notifications_are_skipped() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        // The worker keeps processing the events following a transaction which can't be decoded.
+        node.add_raw_to_mempool(&[0xde, 0xad, 0xbe, 0xef]).await;
+        node.add_to_mempool(&payment(wallet_spk(0), Amount::from_sat(30_000)))
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(
+            balance(&manager).untrusted_pending,
+            Amount::from_sat(30_000)
+        );
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn coinbase_maturity() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let reward = Amount::from_btc(50.0).unwrap();
+        node.mine_to(wallet_spk(0), vec![]);
+        node.mine(vec![payment(wallet_spk(1), Amount::from_sat(30_000))]);
+        for _ in 2..99 {
+            node.mine(vec![]);
+        }
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let create_psbt = |amount| {
+            manager.lock().unwrap().wallet_mut("").unwrap().create_psbt(
+                vec![(wallet_spk(2), amount)],
+                FeeRate::from_sat_per_vb_unchecked(1),
+                None,
+                &CoinControl::default(),
+            )
+        };
+        let getbalances = || async {
+            call_command(&rpc, &manager, None, "getbalances", Value::Null)
+                .await
+                .unwrap()
+        };
+
+        // With 99 confirmations, the block reward can't be spent in the next block.
+        assert_eq!(balance(&manager).immature, reward);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(30_000));
+        assert_eq!(getbalances().await["mine"]["immature"], json!(50.0));
+        assert_eq!(getbalances().await["mine"]["trusted"], json!(0.0003));
+        let psbt = create_psbt(Amount::from_sat(20_000)).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert!(create_psbt(Amount::from_btc(1.0).unwrap()).is_err());
+
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(balance(&manager).immature, Amount::ZERO);
+        assert_eq!(
+            balance(&manager).confirmed,
+            reward + Amount::from_sat(30_000)
+        );
+        assert_eq!(getbalances().await["mine"]["immature"], json!(0.0));
+        let psbt = create_psbt(Amount::from_btc(1.0).unwrap()).unwrap();
+        assert_eq!(psbt.inputs[0].witness_utxo.as_ref().unwrap().value, reward);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn change_address_is_never_handed_out() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(100_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let create_psbt = |wallet: &mut BdkWallet, sat| {
+            wallet
+                .create_psbt(
+                    vec![(wallet_spk(20), Amount::from_sat(sat))],
+                    FeeRate::from_sat_per_vb_unchecked(1),
+                    None,
+                    &CoinControl::default(),
+                )
+                .unwrap()
+        };
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+
+            // Without change, no address is revealed.
+            let given = wallet.next_address().unwrap();
+            let revealed = wallet.revealed_addresses().len();
+            let psbt = create_psbt(wallet, 99_800);
+            assert_eq!(psbt.unsigned_tx.output.len(), 1);
+            assert_eq!(wallet.revealed_addresses().len(), revealed);
+
+            // The change goes to a fresh address, which isn't handed out afterwards either.
+            let psbt = create_psbt(wallet, 50_000);
+            let change = &psbt.unsigned_tx.output[1].script_pubkey;
+            assert_ne!(*change, given.script_pubkey());
+            assert_eq!(wallet.revealed_addresses().len(), revealed + 1);
+            assert_ne!(wallet.next_address().unwrap().script_pubkey(), *change);
+            let second = create_psbt(wallet, 50_000);
+            assert_ne!(second.unsigned_tx.output[1].script_pubkey, *change);
+        }
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn applying_transactions_reveals_no_address() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let revealed = || {
+            let manager = manager.lock().unwrap();
+            let wallet = manager.wallet("").unwrap();
+            wallet.print_info().unwrap();
+            wallet.revealed_addresses().len()
+        };
+        assert_eq!(revealed(), 1);
+
+        // Only the addresses paid to are revealed, up to the last one. Printing the wallet's
+        // state doesn't reveal any either.
+        node.add_to_mempool(&payment(wallet_spk(2), Amount::from_sat(10_000)))
+            .await;
+        subscription.flush().await.unwrap();
+        node.connect_block(vec![payment(wallet_spk(3), Amount::from_sat(10_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(revealed(), 4);
+        assert_eq!(revealed(), 4);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn rescans_start_at_first_activity() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let take_rescan = || {
+            let mut manager = manager.lock().unwrap();
+            manager.wallet_mut("").unwrap().take_pending_rescan()
+        };
+        let set_lookahead = |lookahead| {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            wallet.set_lookahead(0, lookahead).unwrap();
+        };
+
+        // Without any activity, the whole chain is rescanned.
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        set_lookahead(30);
+        assert_eq!(take_rescan(), Some((0, 1)));
+
+        // Once paid, it's rescanned from the first block which did.
+        node.connect_block(vec![payment(wallet_spk(0), Amount::from_sat(10_000))])
+            .await;
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(take_rescan(), None);
+        set_lookahead(40);
+        assert_eq!(take_rescan(), Some((2, 3)));
+
+        // Including when the end of the lookahead is used.
+        node.connect_block(vec![payment(wallet_spk(35), Amount::from_sat(10_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(take_rescan(), Some((2, 4)));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn transaction_history() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        for i in 0..3 {
+            node.mine(vec![payment(wallet_spk(i), Amount::from_sat(10_000))]);
+        }
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let psbt = manager
+            .lock()
+            .unwrap()
+            .wallet_mut("")
+            .unwrap()
+            .create_psbt(
+                vec![(wallet_spk(10), Amount::from_sat(5_000))],
+                FeeRate::from_sat_per_vb_unchecked(2),
+                None,
+                &CoinControl::default(),
+            )
+            .unwrap();
+        node.add_to_mempool(&psbt.unsigned_tx).await;
+        subscription.flush().await.unwrap();
+        let list = |filter: TxFilter| {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            wallet.list_transactions(&filter)
+        };
+
+        // The payments, oldest first, and the unconfirmed spend last.
+        let txs = list(TxFilter::default());
+        assert_eq!(txs.len(), 4);
+        assert_eq!(
+            txs.iter().map(|tx| tx.confirmations).collect::<Vec<_>>(),
+            [3, 2, 1, 0]
+        );
+        assert_eq!(txs[0].received, Amount::from_sat(10_000));
+        assert_eq!(txs[0].fee, None);
+        let spend = &txs[3];
+        assert_eq!(spend.txid, psbt.unsigned_tx.compute_txid());
+        assert!(spend.rbf && spend.confirmation.is_none());
+        let fee = spend.fee.unwrap();
+        assert_eq!(spend.net(), -fee.to_signed().unwrap());
+        assert!(spend.feerate.unwrap() >= FeeRate::from_sat_per_vb_unchecked(2));
+
+        // Filtered by height or block time, the mempool transaction only matching open ranges.
+        let txs = list(TxFilter {
+            min_height: Some(2),
+            max_height: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].confirmation.unwrap().block_id.height, 2);
+        let txs = list(TxFilter {
+            min_time: Some(node.block(2).header.time.into()),
+            ..Default::default()
+        });
+        assert_eq!(txs.len(), 3);
+        assert!(txs[2].confirmation.is_none());
+
+        // Pages are counted from the newest transaction.
+        let txs = list(TxFilter {
+            count: Some(2),
+            skip: 1,
+            ..Default::default()
+        });
+        assert_eq!(
+            txs.iter().map(|tx| tx.confirmations).collect::<Vec<_>>(),
+            [2, 1]
+        );
+
+        let params = json!({"count": 5, "max_height": 1});
+        let listed = call_command(&rpc, &manager, None, "listtransactions", params)
+            .await
+            .unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["amount"], json!(0.0001));
+        assert_eq!(listed[0]["blockheight"], json!(1));
+        assert_eq!(listed[0]["confirmations"], json!(3));
+        let listed = call_command(&rpc, &manager, None, "listtransactions", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(listed[3]["fee"], json!(fee.to_btc()));
+        assert_eq!(listed[3]["bip125-replaceable"], json!("yes"));
+        assert!(listed[3].get("blockhash").is_none());
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn reorg_while_running() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        node.connect_block(vec![payment(wallet_spk(0), Amount::from_sat(40_000))])
+            .await;
+
+        subscription.flush().await.unwrap();
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(40_000));
+
+        // The payment is reorged out and not part of the new chain.
+        node.reorg_and_notify(1, vec![vec![], vec![]]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(wallet_tip(&manager).height, 3);
+        assert_eq!(balance(&manager).total(), Amount::ZERO);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn reorg_while_offline() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        for _ in 0..3 {
+            node.mine(vec![]);
+        }
+        let tx = payment(wallet_spk(0), Amount::from_sat(50_000));
+        node.mine(vec![tx.clone()]);
+        node.mine(vec![]);
+
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(50_000));
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // While the wallet is off, the two last blocks are replaced by three blocks with the
+        // payment confirmed in the last one.
+        node.reorg(2, vec![vec![], vec![], vec![tx.clone()]]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(wallet_tip(&manager).height, 6);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(50_000));
+        let utxos = manager.lock().unwrap().wallet("").unwrap().utxos();
+        assert_eq!(utxos.len(), 1);
+        match utxos[0].chain_position {
+            bdk_chain::ChainPosition::Confirmed(anchor) => {
+                assert_eq!(anchor.block_id.height, 6);
+                assert_eq!(anchor.block_id.hash, node.tip());
+            }
+            pos => panic!("Unexpected chain position {:?}", pos),
+        }
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn mismatched_disconnect_is_ignored() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(60_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let tip = wallet_tip(&manager);
+
+        // A block at the wallet's tip height, but not the one it has there.
+        let (_, connected) = node.reorg(1, vec![vec![]]);
+        node.notify_disconnected(&connected[0]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager), tip);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(60_000));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn missed_notifications_are_fetched() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        // A block with a payment is never notified. It's fetched when its child is connected.
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(70_000))]);
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(70_000));
+
+        // Same when the disconnections are missed: the payment is reorged out.
+        node.reorg(2, vec![vec![], vec![]]);
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(wallet_tip(&manager).height, 4);
+        let wallet_chain: Vec<_> = (0..=4)
+            .map(|h| manager.lock().unwrap().wallet("").unwrap().block_hash(h))
+            .collect();
+        let node_chain: Vec<_> = (0..=4).map(|h| Some(node.block(h).block_hash())).collect();
+        assert_eq!(wallet_chain, node_chain);
+        assert_eq!(balance(&manager).total(), Amount::ZERO);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn node_errors_are_survived() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        // The node fails to serve the tip and the missed blocks: the events are skipped.
+        node.set_failing(true);
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(70_000))]);
+        node.connect_block(vec![]).await;
+        node.notify_updated_tip().await;
+        subscription.flush().await.unwrap();
+        subscription.pause(&rpc).await.unwrap();
+        node.connect_block(vec![]).await;
+        assert!(subscription.resume(&rpc).await.is_err());
+
+        // The worker keeps going once the node recovers.
+        node.set_failing(false);
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(70_000));
+        subscription.disconnect(&rpc).await.unwrap();
+        rpc.disconnect().await.unwrap();
+    });
+}
+
+#[test]
+fn sync_to_current_tip() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+
+        node.connect_block(vec![]).await;
+
+        subscription.flush().await.unwrap();
+        assert_eq!(manager.lock().unwrap().node_tip().hash, node.tip());
+
+        // The node connected a block but didn't notify it yet.
+        node.mine_queued(vec![payment(wallet_spk(0), Amount::from_sat(80_000))]);
+        assert_eq!(balance(&manager).total(), Amount::ZERO);
+        let tip = subscription.sync_to_current_tip(&rpc).await.unwrap();
+        assert_eq!(tip.hash, node.tip());
+        assert_eq!(manager.lock().unwrap().node_tip().hash, node.tip());
+        assert!(!manager.lock().unwrap().is_initial_block_download());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(80_000));
+
+        // Nothing to wait for when the tip didn't change.
+        assert_eq!(subscription.sync_to_current_tip(&rpc).await.unwrap(), tip);
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn load_and_unload_wallets_while_running() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let descriptor = DESCRIPTOR.replace("/*)", "/1/*)");
+        let spk = Descriptor::<DescriptorPublicKey>::from_str(&descriptor)
+            .unwrap()
+            .at_derivation_index(0)
+            .unwrap()
+            .script_pubkey();
+        node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        node.mine(vec![payment(spk.clone(), Amount::from_sat(20_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let call =
+            |wallet_name, name, params| call_command(&rpc, &manager, wallet_name, name, params);
+        let bob_balance = || manager.lock().unwrap().wallet("bob").unwrap().balance();
+
+        // A created wallet is synced and served notifications along with the default one.
+        let params = json!(["bob", descriptor]);
+        assert_eq!(
+            call(None, "createwallet", params.clone()).await.unwrap(),
+            json!({"name": "bob"})
+        );
+        assert!(call(None, "createwallet", params).await.is_err());
+        assert_eq!(manager.lock().unwrap().names(), ["", "bob"]);
+        assert_eq!(bob_balance().confirmed, Amount::from_sat(20_000));
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+        node.connect_block(vec![payment(spk.clone(), Amount::from_sat(30_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(bob_balance().confirmed, Amount::from_sat(50_000));
+
+        // Once unloaded, it misses the blocks until it's loaded again.
+        let e = call(Some(""), "unloadwallet", json!(["bob"])).await;
+        assert_eq!(e.unwrap_err().code, RPC_INVALID_PARAMETER);
+        call(Some("bob"), "unloadwallet", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(manager.lock().unwrap().names(), [""]);
+        assert!(call(None, "unloadwallet", json!(["bob"])).await.is_err());
+        node.connect_block(vec![payment(spk, Amount::from_sat(40_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(
+            call(None, "loadwallet", json!({"filename": "bob"}))
+                .await
+                .unwrap(),
+            json!({"name": "bob"})
+        );
+        assert!(call(None, "loadwallet", json!(["bob"])).await.is_err());
+        assert_eq!(bob_balance().confirmed, Amount::from_sat(90_000));
+        let bob_tip = manager.lock().unwrap().wallet("bob").unwrap().tip();
+        assert_eq!(bob_tip.hash, node.tip());
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn pause_and_resume_notifications() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert!(subscription.is_active());
+        assert_eq!(node.subscriber_count(), 1);
+
+        // Blocks connected while paused aren't notified to us.
+        subscription.pause(&rpc).await.unwrap();
+        assert!(!subscription.is_active());
+        assert_eq!(node.subscriber_count(), 0);
+        node.connect_block(vec![payment(wallet_spk(0), Amount::from_sat(90_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        assert_eq!(balance(&manager).total(), Amount::ZERO);
+
+        // They are fetched upon resuming.
+        subscription.resume(&rpc).await.unwrap();
+        assert!(subscription.is_active());
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(manager.lock().unwrap().tip().hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(90_000));
+        node.connect_block(vec![]).await;
+        subscription.flush().await.unwrap();
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+
+        // Same when the node released our registration.
+        node.release_subscribers().await;
+        assert!(!subscription.is_active());
+        node.connect_block(vec![payment(wallet_spk(1), Amount::from_sat(10_000))])
+            .await;
+        subscription.resume(&rpc).await.unwrap();
+        assert!(subscription.is_active());
+        assert_eq!(node.subscriber_count(), 1);
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(100_000));
+
+        subscription.disconnect(&rpc).await.unwrap();
+        assert_eq!(node.subscriber_count(), 0);
+        rpc.disconnect().await.unwrap();
+    });
+}
+
+#[test]
+fn notification_queue_metrics() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(subscription.queue_metrics().processed, 0);
+
+        for i in 0..10 {
+            node.add_to_mempool(&payment(wallet_spk(i), Amount::from_sat(1_000 + i as u64)))
+                .await;
+        }
+        subscription.flush().await.unwrap();
+        let metrics = subscription.queue_metrics();
+        assert_eq!(metrics.depth, 0);
+        assert!(metrics.max_depth >= 1);
+        // The transactions and our flush request.
+        assert_eq!(metrics.processed, 11);
+        assert_eq!(metrics.backpressure_waits, 0);
+        assert_eq!(
+            balance(&manager).untrusted_pending,
+            Amount::from_sat(10_045)
+        );
+        stop_wallet(rpc, subscription).await;
+    });
+}
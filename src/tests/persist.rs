@@ -0,0 +1,672 @@
+//! Tests of the persistence of the wallets' changes.
+
+use bdk_chain::{
+    bitcoin::{Amount, Network},
+    miniscript::descriptor::checksum::desc_checksum,
+    Merge,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use bdk_file_store::Store as BdkStore;
+
+use tokio::sync::Notify;
+
+use std::{collections::BTreeSet, fs, path::Path, sync::Arc};
+
+use super::{
+    balance, mock_node::payment, mock_node::MockNode, run_local, start_wallet, start_wallet_with,
+    stop_wallet, wallet_spk, wallet_tip,
+};
+use crate::{
+    persist::{
+        read_store, Backend, BatchedStore, FileBackend, StoreBackend, StoreConfig, StoreHeader,
+        PERSIST_BATCH_BLOCKS, STORE_VERSION,
+    },
+    sqlite_store::SqliteBackend,
+    store_crypto::{self, StoreKey},
+    store_dump::{dump_store, DumpFilter},
+    wallet::BdkWallet,
+    Options, DESCRIPTOR, NETWORK,
+};
+
+fn header() -> StoreHeader {
+    StoreHeader::new(NETWORK, "checksum")
+}
+
+type Store = BatchedStore<BTreeSet<u32>>;
+
+fn open_store(path: &Path) -> (Store, BTreeSet<u32>) {
+    open_encrypted_store(path, None).unwrap()
+}
+
+fn open_encrypted_store(
+    path: &Path,
+    key: Option<&StoreKey>,
+) -> Result<(Store, BTreeSet<u32>), Box<dyn std::error::Error>> {
+    let backend = FileBackend::open(path, header(), key)?;
+    Store::new(Box::new(backend))
+}
+
+// A key file in this directory, with the key hex encoded.
+fn key_file(dir: &Path, name: &str, byte: u8) -> StoreKey {
+    let path = dir.join(name);
+    fs::write(&path, format!("{:02x}", byte).repeat(32)).unwrap();
+    StoreKey::KeyFile(path)
+}
+
+#[test]
+fn blocks_are_written_per_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    let empty_len = fs::metadata(&path).unwrap().len();
+
+    for i in 0..PERSIST_BATCH_BLOCKS as u32 - 1 {
+        store.stage_block([i].into()).unwrap();
+    }
+    store.stage([u32::MAX].into());
+    assert_eq!(fs::metadata(&path).unwrap().len(), empty_len);
+    let (_, aggregate) = open_store(&path);
+    assert!(aggregate.is_empty());
+
+    // Filling the batch writes it at once, along with the other staged changes.
+    store
+        .stage_block([PERSIST_BATCH_BLOCKS as u32].into())
+        .unwrap();
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate.len(), PERSIST_BATCH_BLOCKS + 1);
+    assert!(aggregate.contains(&u32::MAX));
+
+    // Committed changes are written right away.
+    store.commit([1_000].into()).unwrap();
+    let (_, aggregate) = open_store(&path);
+    assert!(aggregate.contains(&1_000));
+}
+
+#[test]
+fn partially_written_batch_is_discarded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    store.commit([1, 2].into()).unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+    store.commit([1_000].into()).unwrap();
+    drop(store);
+
+    // Simulate a crash in the middle of writing the last batch.
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len + 2).unwrap();
+    drop(file);
+
+    let (mut store, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2].into());
+
+    // The next batch overwrites the partial one.
+    store.commit([3].into()).unwrap();
+    drop(store);
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2, 3].into());
+}
+
+#[test]
+fn corrupted_batch_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    store.commit([1].into()).unwrap();
+    let len = fs::metadata(&path).unwrap().len();
+    store.commit([2].into()).unwrap();
+    store.commit([3].into()).unwrap();
+    drop(store);
+
+    // A batch in the middle of the file can't be decoded: the batches after it aren't dropped.
+    let mut content = fs::read(&path).unwrap();
+    content[len as usize] = 0xff;
+    fs::write(&path, &content).unwrap();
+    let err = open_encrypted_store(&path, None).err().unwrap();
+    assert!(err.to_string().contains("Error reading"));
+    assert_eq!(fs::read(&path).unwrap(), content);
+
+    // Neither is garbage at the end of the file which isn't the start of a batch.
+    content[len as usize] = 0;
+    content.extend([0xff; 5]);
+    fs::write(&path, &content).unwrap();
+    assert!(open_encrypted_store(&path, None).is_err());
+}
+
+#[test]
+fn sqlite_migration_and_queries() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let paid = node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let txid = paid.txdata[1].compute_txid();
+        node.mine(vec![]);
+
+        // Sync a wallet persisted in a file store, then switch it to SQLite.
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+        let sqlite_options = || Options {
+            data_dir: data_dir.path().to_path_buf(),
+            store: StoreConfig {
+                backend: StoreBackend::Sqlite,
+                key: None,
+            },
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, sqlite_options()).await;
+        let path = data_dir.path();
+        assert!(path.join("bdk_core_store.sqlite").exists());
+        assert!(path.join("bdk_core_store.dat.migrated").exists());
+        assert!(!path.join("bdk_core_store.dat").exists());
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+
+        // New blocks are written to the database, and can be queried.
+        let received = node
+            .connect_block(vec![payment(wallet_spk(1), Amount::from_sat(20_000))])
+            .await;
+        subscription.flush().await.unwrap();
+        {
+            let mut manager = manager.lock().unwrap();
+            let wallet = manager.wallet_mut("").unwrap();
+            let stored = wallet.stored_transaction(&txid).unwrap().unwrap();
+            assert_eq!(stored.tx, paid.txdata[1]);
+            assert_eq!(stored.confirmation.unwrap().hash, paid.block_hash());
+            let at_height = wallet.stored_transactions_at_height(4).unwrap();
+            assert_eq!(at_height, vec![received.txdata[1].compute_txid()]);
+            assert!(wallet.stored_transactions_at_height(3).unwrap().is_empty());
+        }
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        // The wallet restarts from the database, and can't be opened from the file store anymore.
+        let (rpc, manager, subscription) = start_wallet_with(&node, sqlite_options()).await;
+        assert_eq!(wallet_tip(&manager).height, 4);
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(30_000));
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+        let rpc = crate::rpc_interface::RpcInterface::new(node.connect())
+            .await
+            .unwrap();
+        let options = Options {
+            data_dir: data_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        assert!(crate::wallet_startup(&rpc, &options).await.is_err());
+        rpc.disconnect().await.unwrap();
+
+        // The database records the network and descriptor of the wallet, and is only opened for
+        // them.
+        let db_path = path.join("bdk_core_store.sqlite");
+        let checksum = desc_checksum(DESCRIPTOR).unwrap();
+        assert!(SqliteBackend::open(&db_path, &StoreHeader::new(NETWORK, &checksum)).is_ok());
+        let other_network = StoreHeader::new(Network::Signet, &checksum);
+        assert!(SqliteBackend::open(&db_path, &other_network).is_err());
+        let other_descriptor = StoreHeader::new(NETWORK, "checksum");
+        assert!(SqliteBackend::open(&db_path, &other_descriptor).is_err());
+    });
+}
+
+#[test]
+fn compaction_keeps_the_aggregate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    for i in 0..50 {
+        store.commit([i % 10].into()).unwrap();
+    }
+    drop(store);
+
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, header(), None).unwrap();
+    let (before, after) = backend.compact().unwrap();
+    assert!(after < before);
+    assert_eq!(fs::metadata(&path).unwrap().len(), after);
+    assert!(!dir.path().join("store.dat.compact").exists());
+
+    // Changes written after the compaction are appended to the aggregate.
+    let (mut store, _) = Store::new(Box::new(backend)).unwrap();
+    store.commit([100].into()).unwrap();
+    drop(store);
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, (0..10).chain([100]).collect());
+}
+
+#[test]
+fn compact_wallet_store() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(wallet_spk(3), Amount::from_sat(10_000))]);
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        let addresses: Vec<_> = (0..5)
+            .map(|_| {
+                manager
+                    .lock()
+                    .unwrap()
+                    .wallet_mut("")
+                    .unwrap()
+                    .next_address()
+                    .unwrap()
+            })
+            .collect();
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        let (before, after) = BdkWallet::compact_store(data_dir.path(), "", None).unwrap();
+        assert!(after < before);
+        assert!(BdkWallet::compact_store(data_dir.path(), "missing", None).is_err());
+
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+        let revealed: Vec<_> = manager
+            .lock()
+            .unwrap()
+            .wallet("")
+            .unwrap()
+            .revealed_addresses()
+            .into_iter()
+            .map(|(_, address)| address)
+            .collect();
+        assert!(addresses.iter().all(|a| revealed.contains(a)));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn dump_store_filters() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![]);
+        let paid = node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        node.mine(vec![payment(wallet_spk(1), Amount::from_sat(20_000))]);
+        let txid = paid.txdata[1].compute_txid();
+        let (rpc, manager, subscription) = start_wallet(&node, data_dir.path()).await;
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        let dump = |filter: DumpFilter| dump_store(data_dir.path(), "", None, &filter).unwrap();
+        let txs = |cs: &Value| cs["graph_cs"]["tx_graph"]["txs"].as_array().unwrap().len();
+        let blocks = |cs: &Value| cs["chain_cs"]["blocks"].as_object().unwrap().len();
+
+        let full = dump(DumpFilter::default());
+        assert!(!full["changesets"].as_array().unwrap().is_empty());
+        assert_eq!(full["header"]["version"], json!(STORE_VERSION));
+        assert_eq!(full["header"]["network"], json!("regtest"));
+        assert_eq!(txs(&full["aggregate"]), 2);
+        assert_eq!(blocks(&full["aggregate"]), 3);
+
+        let by_txid = dump(DumpFilter {
+            txid: Some(txid),
+            height: None,
+        });
+        assert_eq!(txs(&by_txid["aggregate"]), 1);
+        assert_eq!(blocks(&by_txid["aggregate"]), 0);
+        assert_eq!(
+            by_txid["aggregate"]["graph_cs"]["tx_graph"]["anchors"][0][1],
+            json!(txid.to_string())
+        );
+
+        let by_height = dump(DumpFilter {
+            txid: None,
+            height: Some(3),
+        });
+        assert_eq!(txs(&by_height["aggregate"]), 1);
+        assert_eq!(
+            by_height["aggregate"]["chain_cs"]["blocks"]["3"],
+            json!(node.tip().to_string())
+        );
+
+        // Both filters must match.
+        let none = dump(DumpFilter {
+            txid: Some(txid),
+            height: Some(3),
+        });
+        assert!(none["changesets"].as_array().unwrap().is_empty());
+        assert!(dump_store(data_dir.path(), "missing", None, &DumpFilter::default()).is_err());
+    });
+}
+
+#[test]
+fn legacy_store_is_migrated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let mut legacy = BdkStore::<BTreeSet<u32>>::create_new(b"bdk_core_store", &path).unwrap();
+    legacy.append_changeset(&[1, 2].into()).unwrap();
+    legacy.append_changeset(&[3].into()).unwrap();
+    drop(legacy);
+    let legacy_content = fs::read(&path).unwrap();
+    let (header, changesets) = read_store::<BTreeSet<u32>>(&path, None).unwrap();
+    assert_eq!(header, None);
+    assert_eq!(changesets.len(), 2);
+
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2, 3].into());
+    assert_eq!(
+        fs::read(dir.path().join("store.dat.v0.bak")).unwrap(),
+        legacy_content
+    );
+    let (header, changesets) = read_store::<BTreeSet<u32>>(&path, None).unwrap();
+    assert_eq!(header.unwrap().version, STORE_VERSION);
+    assert_eq!(changesets, vec![Ok([1, 2, 3].into())]);
+
+    // Already migrated.
+    let (mut store, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2, 3].into());
+    store.commit([4].into()).unwrap();
+    drop(store);
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2, 3, 4].into());
+}
+
+#[test]
+fn store_header_is_checked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    store.commit([1].into()).unwrap();
+    drop(store);
+
+    let other_desc = StoreHeader::new(NETWORK, "other");
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, other_desc, None).unwrap();
+    assert!(backend.load().is_err());
+    assert!(backend.compact().is_err());
+    let other_network = StoreHeader::new(bdk_chain::bitcoin::Network::Bitcoin, "checksum");
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, other_network, None).unwrap();
+    assert!(backend.load().is_err());
+
+    // A store from a later version can't be opened.
+    let future_path = dir.path().join("future.dat");
+    let future = StoreHeader {
+        version: STORE_VERSION + 1,
+        ..header()
+    };
+    drop(FileBackend::<BTreeSet<u32>>::open(&future_path, future, None).unwrap());
+    assert!(FileBackend::<BTreeSet<u32>>::open(&future_path, header(), None).is_err());
+}
+
+// The format of version 1 stores.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HeaderV1 {
+    version: u32,
+    network: bdk_chain::bitcoin::Network,
+    descriptor_checksum: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct EntryV1 {
+    header: Option<HeaderV1>,
+    changeset: BTreeSet<u32>,
+}
+
+impl Merge for EntryV1 {
+    fn merge(&mut self, other: Self) {
+        self.header = self.header.take().or(other.header);
+        self.changeset.merge(other.changeset);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.header.is_none() && self.changeset.is_empty()
+    }
+}
+
+#[test]
+fn v1_store_is_migrated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let mut v1 = BdkStore::<EntryV1>::create_new(b"bdk_core_vstore", &path).unwrap();
+    v1.append_changeset(&EntryV1 {
+        header: Some(HeaderV1 {
+            version: 1,
+            network: NETWORK,
+            descriptor_checksum: "checksum".to_string(),
+        }),
+        changeset: [1].into(),
+    })
+    .unwrap();
+    v1.append_changeset(&EntryV1 {
+        header: None,
+        changeset: [2].into(),
+    })
+    .unwrap();
+    drop(v1);
+    let (stored, changesets) = read_store::<BTreeSet<u32>>(&path, None).unwrap();
+    assert_eq!(stored.unwrap().version, 1);
+    assert_eq!(changesets.len(), 2);
+
+    // The header of the store is kept, so it's still checked.
+    let other_desc = StoreHeader::new(NETWORK, "other");
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, other_desc, None).unwrap();
+    assert!(backend.load().is_err());
+    assert!(dir.path().join("store.dat.v1.bak").exists());
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2].into());
+    let (stored, _) = read_store::<BTreeSet<u32>>(&path, None).unwrap();
+    assert_eq!(stored.unwrap(), header());
+}
+
+#[test]
+fn encrypted_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = key_file(dir.path(), "key", 1);
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_encrypted_store(&path, Some(&key)).unwrap();
+    store.commit([0xdead_beef].into()).unwrap();
+    store.commit([2].into()).unwrap();
+    drop(store);
+
+    // The records can't be read without the key.
+    let content = fs::read(&path).unwrap();
+    assert!(!content
+        .windows(4)
+        .any(|w| w == 0xdead_beef_u32.to_le_bytes()));
+    let (_, aggregate) = open_encrypted_store(&path, Some(&key)).unwrap();
+    assert_eq!(aggregate, [2, 0xdead_beef].into());
+    assert!(open_encrypted_store(&path, None).is_err());
+    let wrong = key_file(dir.path(), "wrong", 2);
+    assert!(open_encrypted_store(&path, Some(&wrong)).is_err());
+    let password = StoreKey::Password("password".to_string());
+    assert!(open_encrypted_store(&path, Some(&password)).is_err());
+    let (stored, changesets) = read_store::<BTreeSet<u32>>(&path, Some(&key)).unwrap();
+    assert!(stored.unwrap().encryption.is_some());
+    assert_eq!(changesets.len(), 3);
+    assert!(read_store::<BTreeSet<u32>>(&path, None).unwrap().1[0].is_err());
+
+    // Compaction keeps the store encrypted.
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, header(), Some(&key)).unwrap();
+    backend.compact().unwrap();
+    drop(backend);
+    assert!(open_encrypted_store(&path, None).is_err());
+    let (_, aggregate) = open_encrypted_store(&path, Some(&key)).unwrap();
+    assert_eq!(aggregate, [2, 0xdead_beef].into());
+
+    // Records can't be moved to the store of another wallet with the same key.
+    let other_path = dir.path().join("other.dat");
+    let other_header = StoreHeader::new(NETWORK, "other");
+    drop(
+        FileBackend::<BTreeSet<u32>>::open(&other_path, other_header.clone(), Some(&key)).unwrap(),
+    );
+    fs::copy(&path, &other_path).unwrap();
+    let mut backend =
+        FileBackend::<BTreeSet<u32>>::open(&other_path, other_header, Some(&key)).unwrap();
+    assert!(backend.load().is_err());
+
+    // An unencrypted store isn't encrypted by opening it with a key.
+    let plain_path = dir.path().join("plain.dat");
+    drop(open_store(&plain_path));
+    assert!(open_encrypted_store(&plain_path, Some(&key)).is_err());
+}
+
+#[test]
+fn store_key_rotation() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store.dat");
+    let (mut store, _) = open_store(&path);
+    store.commit([1].into()).unwrap();
+    drop(store);
+
+    let password = StoreKey::Password("password".to_string());
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, header(), None).unwrap();
+    backend.rotate_key(Some(&password)).unwrap();
+    // Writing after the rotation uses the new key.
+    let (mut store, _) = Store::new(Box::new(backend)).unwrap();
+    store.commit([2].into()).unwrap();
+    drop(store);
+    assert!(open_encrypted_store(&path, None).is_err());
+    let (_, aggregate) = open_encrypted_store(&path, Some(&password)).unwrap();
+    assert_eq!(aggregate, [1, 2].into());
+
+    let key = key_file(dir.path(), "key", 1);
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, header(), Some(&password)).unwrap();
+    backend.rotate_key(Some(&key)).unwrap();
+    drop(backend);
+    assert!(open_encrypted_store(&path, Some(&password)).is_err());
+    let (_, aggregate) = open_encrypted_store(&path, Some(&key)).unwrap();
+    assert_eq!(aggregate, [1, 2].into());
+
+    let mut backend = FileBackend::<BTreeSet<u32>>::open(&path, header(), Some(&key)).unwrap();
+    backend.rotate_key(None).unwrap();
+    drop(backend);
+    let (_, aggregate) = open_store(&path);
+    assert_eq!(aggregate, [1, 2].into());
+}
+
+#[test]
+fn encrypted_wallet_store() {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let key = key_file(data_dir.path(), "key", 1);
+        let node = MockNode::new();
+        let paid = node.mine(vec![payment(wallet_spk(0), Amount::from_sat(10_000))]);
+        let options = |key: Option<&StoreKey>| Options {
+            data_dir: data_dir.path().to_path_buf(),
+            store: StoreConfig {
+                backend: StoreBackend::File,
+                key: key.cloned(),
+            },
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, options(Some(&key))).await;
+        stop_wallet(rpc, subscription).await;
+        drop(manager);
+
+        let store = fs::read(data_dir.path().join("bdk_core_store.dat")).unwrap();
+        let raw_tx = bdk_chain::bitcoin::consensus::serialize(&paid.txdata[1]);
+        assert!(!store.windows(raw_tx.len()).any(|w| w == raw_tx));
+        let dump = dump_store(data_dir.path(), "", Some(&key), &DumpFilter::default()).unwrap();
+        assert_eq!(
+            dump["aggregate"]["graph_cs"]["tx_graph"]["txs"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // The wallet can't be loaded without its key, nor can descriptors be imported into it.
+        let rpc = crate::rpc_interface::RpcInterface::new(node.connect())
+            .await
+            .unwrap();
+        assert!(crate::wallet_startup(&rpc, &options(None)).await.is_err());
+        rpc.disconnect().await.unwrap();
+        let descriptor = DESCRIPTOR.replace("/*)", "/1/*)");
+        let descriptor = format!("{}#{}", descriptor, desc_checksum(&descriptor).unwrap());
+        let import = |options: Options| {
+            BdkWallet::import_descriptor(data_dir.path(), "", &descriptor, 1, None, &options.store)
+        };
+        let wrong = key_file(data_dir.path(), "wrong", 3);
+        assert!(import(options(None)).is_err());
+        assert!(import(options(Some(&wrong))).is_err());
+        let sqlite = Options {
+            store: StoreConfig {
+                backend: StoreBackend::Sqlite,
+                key: None,
+            },
+            ..options(None)
+        };
+        assert!(import(sqlite).is_err());
+        assert!(!data_dir.path().join("imported_descriptors.json").exists());
+
+        let new_key = key_file(data_dir.path(), "new_key", 2);
+        BdkWallet::rotate_store_key(data_dir.path(), "", Some(&key), Some(&new_key)).unwrap();
+        assert!(BdkWallet::compact_store(data_dir.path(), "", Some(&key)).is_err());
+        let (rpc, manager, subscription) = start_wallet_with(&node, options(Some(&new_key))).await;
+        assert_eq!(wallet_tip(&manager).hash, node.tip());
+        assert_eq!(balance(&manager).confirmed, Amount::from_sat(10_000));
+        stop_wallet(rpc, subscription).await;
+    });
+}
+
+#[test]
+fn files_of_encrypted_wallet_are_sealed() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let key = key_file(data_dir.path(), "key", 1);
+    let config = |key: Option<&StoreKey>| StoreConfig {
+        backend: StoreBackend::File,
+        key: key.cloned(),
+    };
+    let notifier = Arc::new(Notify::new());
+    let mut wallet = BdkWallet::create(
+        data_dir.path(),
+        "named",
+        DESCRIPTOR,
+        &config(None),
+        notifier.clone(),
+    )
+    .unwrap();
+    wallet.set_lookahead(0, 50).unwrap();
+    drop(wallet);
+    let dir = data_dir.path().join("bdk_core_wallets").join("named");
+    let bak = dir.join("bdk_core_store.dat.v1.bak");
+    fs::write(&bak, DESCRIPTOR).unwrap();
+    let files = [
+        "descriptor",
+        "imported_descriptors.json",
+        "bdk_core_settings.dat",
+        "bdk_core_locks.dat",
+    ]
+    .map(|file| dir.join(file));
+    // The xpub of the descriptor is found in the files written in clear.
+    let xpub = &DESCRIPTOR["tr(".len()..DESCRIPTOR.len() - "/*)".len()];
+    let in_clear = |path: &Path| {
+        let content = fs::read(path).unwrap();
+        content.windows(xpub.len()).any(|w| w == xpub.as_bytes())
+    };
+
+    // Encrypting the store seals the files next to it and removes the unencrypted backups.
+    BdkWallet::rotate_store_key(data_dir.path(), "named", None, Some(&key)).unwrap();
+    assert!(!bak.exists());
+    let descriptor = DESCRIPTOR.replace("/*)", "/1/*)");
+    let descriptor = format!("{}#{}", descriptor, desc_checksum(&descriptor).unwrap());
+    let import = |key| {
+        BdkWallet::import_descriptor(data_dir.path(), "named", &descriptor, 1, None, &config(key))
+    };
+    import(Some(&key)).unwrap();
+    for path in &files {
+        assert!(store_crypto::is_sealed(path).unwrap());
+        assert!(!in_clear(path));
+    }
+    assert!(BdkWallet::load(data_dir.path(), "named", &config(None), notifier.clone()).is_err());
+    let wallet = BdkWallet::load(
+        data_dir.path(),
+        "named",
+        &config(Some(&key)),
+        notifier.clone(),
+    )
+    .unwrap();
+    assert_eq!(wallet.lookahead(0), 50);
+    drop(wallet);
+
+    // Decrypting it writes them in clear again.
+    BdkWallet::rotate_store_key(data_dir.path(), "named", Some(&key), None).unwrap();
+    for path in &files {
+        assert!(!store_crypto::is_sealed(path).unwrap());
+    }
+    assert!(in_clear(&files[0]));
+    let wallet = BdkWallet::load(data_dir.path(), "named", &config(None), notifier).unwrap();
+    assert_eq!(wallet.lookahead(0), 50);
+}
@@ -0,0 +1,187 @@
+//! Tests of the spending paths of policy descriptors.
+
+use bdk_chain::bitcoin::{absolute, relative, Amount, FeeRate, Psbt, Sequence};
+
+use super::{
+    mock_node::payment,
+    mock_node::MockNode,
+    psbt::{descriptor, private_descriptor, spk},
+    run_local, start_wallet_with, stop_wallet, wallet_spk,
+};
+use crate::{
+    descriptors::parse_descriptor,
+    policy::compile_policy,
+    signer::{finalize_psbt, sign_psbt},
+    wallet::{BdkWallet, CoinControl, PathChoice, PRIMARY_KEYCHAIN},
+    Options,
+};
+
+const GENESIS_TIME: u64 = 1_296_688_602;
+
+/// Compile this policy template, where `{n}` stands for the key of the cosigner `n`.
+fn compiled(template: &str, taproot: bool) -> String {
+    let descriptor = compile_policy(&descriptor(template), taproot)
+        .unwrap()
+        .to_string();
+    parse_descriptor(&descriptor).expect("Compiled with its checksum");
+    descriptor
+}
+
+/// Whether this cosigner alone can sign the PSBT to completion.
+fn signs(descriptor: &str, psbt: &Psbt, seed: u8) -> bool {
+    let mut psbt = psbt.clone();
+    // The checksum of the descriptor doesn't match with the private key.
+    let (descriptor, _) = descriptor.split_once('#').unwrap();
+    sign_psbt(&mut psbt, &private_descriptor(descriptor, seed)).unwrap();
+    finalize_psbt(&mut psbt)
+}
+
+/// Create a wallet tracking this descriptor with a coin confirmed in the first block, once the
+/// chain is this high, and run this against it.
+fn with_vault<T>(descriptor: &str, height: u32, f: impl FnOnce(&mut BdkWallet) -> T) -> T {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(spk(descriptor, 0), Amount::from_sat(100_000))]);
+        for _ in 1..height {
+            node.mine(vec![]);
+        }
+        let options = Options {
+            data_dir: data_dir.path().to_path_buf(),
+            create_wallets: vec![("vault".to_string(), descriptor.to_string())],
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        let result = f(manager.lock().unwrap().wallet_mut("vault").unwrap());
+        stop_wallet(rpc, subscription).await;
+        result
+    })
+}
+
+fn create_psbt(wallet: &mut BdkWallet, path: Option<PathChoice>) -> Result<Psbt, String> {
+    wallet
+        .create_psbt(
+            vec![(wallet_spk(0), Amount::from_sat(50_000))],
+            FeeRate::from_sat_per_vb_unchecked(2),
+            path,
+            &CoinControl::default(),
+        )
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn relative_timelock_recovery_path() {
+    let vault = compiled("or(9@pk({1}),and(pk({2}),older(3)))", false);
+    assert!(vault.starts_with("wsh("));
+    let choice = |index| PathChoice {
+        keychain: PRIMARY_KEYCHAIN,
+        index,
+        time: GENESIS_TIME,
+    };
+
+    // With a single confirmation, only the hot key can spend the coin.
+    let recovery = with_vault(&vault, 1, |wallet| {
+        let paths = wallet.spending_paths(PRIMARY_KEYCHAIN).unwrap();
+        assert_eq!(paths.len(), 2);
+        let hot = paths.iter().position(|p| p.older.is_none()).unwrap();
+        let recovery = 1 - hot;
+        assert_eq!(paths[hot].keys.len(), 1);
+        assert_eq!(paths[recovery].keys.len(), 1);
+        assert_eq!(
+            paths[recovery].older,
+            Some(relative::LockTime::from_height(3))
+        );
+
+        let coins = wallet.utxo_spending_paths(GENESIS_TIME).unwrap();
+        assert_eq!(coins.len(), 1);
+        assert_eq!(coins[0].keychain, PRIMARY_KEYCHAIN);
+        assert!(coins[0].paths[hot].1);
+        assert!(!coins[0].paths[recovery].1);
+        assert!(create_psbt(wallet, Some(choice(recovery)))
+            .unwrap_err()
+            .contains("No coin can be spent"));
+        assert!(create_psbt(wallet, Some(choice(2)))
+            .unwrap_err()
+            .contains("Unknown spending path"));
+
+        let psbt = create_psbt(wallet, Some(choice(hot))).unwrap();
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence,
+            Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+        assert!(signs(&vault, &psbt, 1));
+        assert!(!signs(&vault, &psbt, 2));
+        recovery
+    });
+
+    // Once it has 3 confirmations in the next block, the recovery key can too, but only with the
+    // sequence set for it.
+    with_vault(&vault, 3, |wallet| {
+        let coins = wallet.utxo_spending_paths(GENESIS_TIME).unwrap();
+        assert!(coins[0].paths.iter().all(|(_, unlocked)| *unlocked));
+        let psbt = create_psbt(wallet, Some(choice(recovery))).unwrap();
+        assert_eq!(psbt.unsigned_tx.input[0].sequence, Sequence::from_height(3));
+        assert!(signs(&vault, &psbt, 2));
+        let psbt = create_psbt(wallet, None).unwrap();
+        assert!(!signs(&vault, &psbt, 2));
+    });
+}
+
+#[test]
+fn absolute_timelock_recovery_path() {
+    let expiry = GENESIS_TIME as u32 + 100 * 600;
+    let vault = compiled(
+        &format!("or(9@pk({{1}}),and(pk({{2}}),after({})))", expiry),
+        true,
+    );
+    // The hot key is the internal key, the recovery path a leaf.
+    assert!(vault.starts_with("tr("));
+
+    with_vault(&vault, 1, |wallet| {
+        let paths = wallet.spending_paths(PRIMARY_KEYCHAIN).unwrap();
+        let recovery = paths.iter().position(|p| p.after.is_some()).unwrap();
+        assert_eq!(
+            paths[recovery].after,
+            Some(absolute::LockTime::from_consensus(expiry))
+        );
+        let coins = wallet.utxo_spending_paths(expiry.into()).unwrap();
+        assert!(!coins[0].paths[recovery].1);
+        let coins = wallet.utxo_spending_paths(u64::from(expiry) + 1).unwrap();
+        assert!(coins[0].paths[recovery].1);
+
+        let choice = PathChoice {
+            keychain: PRIMARY_KEYCHAIN,
+            index: recovery,
+            time: u64::from(expiry) + 1,
+        };
+        let psbt = create_psbt(wallet, Some(choice)).unwrap();
+        assert_eq!(
+            psbt.unsigned_tx.lock_time,
+            absolute::LockTime::from_consensus(expiry)
+        );
+        assert!(signs(&vault, &psbt, 2));
+        // Without the lock time, the recovery key can't spend the coin.
+        let psbt = create_psbt(wallet, None).unwrap();
+        assert!(!signs(&vault, &psbt, 2));
+        assert!(signs(&vault, &psbt, 1));
+    });
+}
+
+#[test]
+fn threshold_spending_paths() {
+    // No key can sign alone, the internal key of the compiled descriptor is unspendable.
+    let compiled = compiled("thresh(2,pk({1}),pk({2}),pk({3}))", true);
+    assert!(compiled
+        .starts_with("tr(50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0,"));
+
+    let multisig = descriptor("wsh(sortedmulti(2,{1},{2},{3}))");
+    for vault in [compiled, multisig] {
+        with_vault(&vault, 1, |wallet| {
+            let paths = wallet.spending_paths(PRIMARY_KEYCHAIN).unwrap();
+            assert_eq!(paths.len(), 3);
+            assert!(paths.iter().all(|p| p.keys.len() == 2 && p.older.is_none()));
+            let coins = wallet.utxo_spending_paths(GENESIS_TIME).unwrap();
+            assert!(coins[0].paths.iter().all(|(_, unlocked)| *unlocked));
+        });
+    }
+}
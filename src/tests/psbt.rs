@@ -0,0 +1,245 @@
+//! Tests of the coordination of the signatures of multisig and miniscript policy wallets.
+
+use bdk_chain::{
+    bitcoin::{
+        bip32::{DerivationPath, Xpriv, Xpub},
+        secp256k1::Secp256k1,
+        Amount, FeeRate, Psbt, ScriptBuf, Transaction,
+    },
+    miniscript::{Descriptor, DescriptorPublicKey},
+};
+
+use std::{fs, str::FromStr};
+
+use super::{
+    mock_node::payment, mock_node::MockNode, run_local, start_wallet_with, stop_wallet, wallet_spk,
+};
+use crate::{
+    rpc_commands::WALLET_COMMANDS,
+    signer::{analyze_psbt, combine_psbts, finalize_psbt, read_key_file, sign_psbt, PsbtRole},
+    wallet::CoinControl,
+    Options, NETWORK,
+};
+
+fn xprv(seed: u8) -> Xpriv {
+    Xpriv::new_master(NETWORK, &[seed; 32]).unwrap()
+}
+
+fn xpub(seed: u8) -> Xpub {
+    Xpub::from_priv(&Secp256k1::new(), &xprv(seed))
+}
+
+/// A descriptor template where `{n}` stands for the key of the cosigner `n`.
+pub(super) fn descriptor(template: &str) -> String {
+    (1..=3).fold(template.to_string(), |desc, seed| {
+        desc.replace(&format!("{{{}}}", seed), &format!("{}/*", xpub(seed)))
+    })
+}
+
+/// The descriptor with the private key of this cosigner.
+pub(super) fn private_descriptor(descriptor: &str, seed: u8) -> String {
+    descriptor.replace(&xpub(seed).to_string(), &xprv(seed).to_string())
+}
+
+pub(super) fn spk(descriptor: &str, index: u32) -> ScriptBuf {
+    Descriptor::<DescriptorPublicKey>::from_str(descriptor)
+        .unwrap()
+        .at_derivation_index(index)
+        .unwrap()
+        .script_pubkey()
+}
+
+/// Fund a wallet tracking this descriptor and create a PSBT spending from it.
+fn create_psbt(descriptor: &str) -> Psbt {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        node.mine(vec![payment(spk(descriptor, 0), Amount::from_sat(100_000))]);
+        let options = Options {
+            data_dir: data_dir.path().to_path_buf(),
+            create_wallets: vec![("treasury".to_string(), descriptor.to_string())],
+            ..Default::default()
+        };
+        let (rpc, manager, subscription) = start_wallet_with(&node, options).await;
+        let psbt = manager
+            .lock()
+            .unwrap()
+            .wallet_mut("treasury")
+            .unwrap()
+            .create_psbt(
+                vec![(wallet_spk(0), Amount::from_sat(50_000))],
+                FeeRate::from_sat_per_vb_unchecked(2),
+                None,
+                &CoinControl::default(),
+            )
+            .unwrap();
+        stop_wallet(rpc, subscription).await;
+        psbt
+    })
+}
+
+/// Have each of these cosigners sign their own copy of the PSBT, and combine them.
+fn cosign(descriptor: &str, psbt: &Psbt, cosigners: &[u8]) -> Psbt {
+    let signed = cosigners.iter().map(|seed| {
+        let mut psbt = psbt.clone();
+        assert_eq!(
+            sign_psbt(&mut psbt, &private_descriptor(descriptor, *seed)).unwrap(),
+            1
+        );
+        psbt
+    });
+    combine_psbts(signed).unwrap()
+}
+
+/// The keys which signed the single input of this PSBT.
+fn signed_keys(psbt: &Psbt) -> usize {
+    let inputs = analyze_psbt(psbt);
+    assert_eq!(inputs.len(), 1);
+    inputs[0].keys.iter().filter(|key| key.signed).count()
+}
+
+/// Finalize this PSBT and check the fee it pays matches its feerate.
+fn finalize(mut psbt: Psbt) -> Transaction {
+    let fee = psbt.fee().unwrap();
+    assert!(finalize_psbt(&mut psbt));
+    assert_eq!(analyze_psbt(&psbt)[0].next, PsbtRole::Extractor);
+    let tx = psbt.extract_tx().unwrap();
+    assert!(
+        fee >= FeeRate::from_sat_per_vb_unchecked(2)
+            .fee_vb(tx.vsize() as u64)
+            .unwrap()
+    );
+    tx
+}
+
+#[test]
+fn sortedmulti_psbt() {
+    let descriptor = descriptor("wsh(sortedmulti(2,{1},{2},{3}))");
+    let psbt = create_psbt(&descriptor);
+    let inputs = analyze_psbt(&psbt);
+    assert_eq!(inputs[0].next, PsbtRole::Signer);
+    assert_eq!(inputs[0].keys.len(), 3);
+    assert_eq!(signed_keys(&psbt), 0);
+
+    // A single signature isn't enough.
+    let mut partial = cosign(&descriptor, &psbt, &[1]);
+    assert_eq!(signed_keys(&partial), 1);
+    assert_eq!(analyze_psbt(&partial)[0].next, PsbtRole::Signer);
+    assert!(!finalize_psbt(&mut partial));
+
+    // The signatures of two cosigners, made separately, are.
+    let combined = cosign(&descriptor, &psbt, &[1, 3]);
+    assert_eq!(signed_keys(&combined), 2);
+    assert_eq!(analyze_psbt(&combined)[0].next, PsbtRole::Finalizer);
+    let tx = finalize(combined);
+    // The dummy element, two signatures and the witness script.
+    assert_eq!(tx.input[0].witness.len(), 4);
+
+    // Only PSBTs for the same transaction can be combined.
+    let mut other = psbt.clone();
+    other.unsigned_tx.lock_time = bdk_chain::bitcoin::absolute::LockTime::ZERO;
+    assert!(combine_psbts([psbt, other]).is_err());
+}
+
+#[test]
+fn taproot_script_tree_psbt() {
+    let descriptor = descriptor("tr({1},{pk({2}),pk({3})})");
+    let psbt = create_psbt(&descriptor);
+    let inputs = analyze_psbt(&psbt);
+    assert_eq!(inputs[0].next, PsbtRole::Signer);
+    assert_eq!(inputs[0].keys.len(), 3);
+
+    // Spending through a leaf of the tree.
+    let signed = cosign(&descriptor, &psbt, &[3]);
+    assert_eq!(signed_keys(&signed), 1);
+    let tx = finalize(signed);
+    // The signature, the leaf script and the control block.
+    assert_eq!(tx.input[0].witness.len(), 3);
+
+    // Spending with the internal key.
+    let tx = finalize(cosign(&descriptor, &psbt, &[1]));
+    assert_eq!(tx.input[0].witness.len(), 1);
+}
+
+#[test]
+fn miniscript_policy_psbt() {
+    let descriptor = descriptor("wsh(or_d(pk({1}),and_v(v:pk({2}),pk({3}))))");
+    let psbt = create_psbt(&descriptor);
+
+    // Either the first key alone, or both others.
+    let mut partial = cosign(&descriptor, &psbt, &[2]);
+    assert!(!finalize_psbt(&mut partial));
+    finalize(cosign(&descriptor, &psbt, &[2, 3]));
+    finalize(cosign(&descriptor, &psbt, &[1]));
+}
+
+#[test]
+fn signing_keys_are_read_from_a_key_file() {
+    // Signing isn't a wallet command, which are also registered with the node.
+    assert!(WALLET_COMMANDS.iter().all(|c| c.name != "signpsbt"));
+
+    let descriptor = descriptor("wsh(sortedmulti(2,{1},{2},{3}))");
+    let psbt = create_psbt(&descriptor);
+    let dir = tempfile::tempdir().unwrap();
+    let keyfile = dir.path().join("keys");
+    assert!(read_key_file(&keyfile).is_err());
+    fs::write(&keyfile, "# Treasury keys\n\n").unwrap();
+    assert!(read_key_file(&keyfile).is_err());
+    let keys = format!(
+        "# Treasury keys\n{}\n\n{}\n",
+        private_descriptor(&descriptor, 1),
+        private_descriptor(&descriptor, 2)
+    );
+    fs::write(&keyfile, keys).unwrap();
+    let descriptors = read_key_file(&keyfile).unwrap();
+    assert_eq!(descriptors.len(), 2);
+
+    let mut signed = psbt.clone();
+    for descriptor in descriptors {
+        sign_psbt(&mut signed, &descriptor).unwrap();
+    }
+    assert_eq!(signed_keys(&signed), 2);
+    finalize(signed);
+}
+
+#[test]
+fn keys_sign_only_for_their_origin() {
+    let secp = Secp256k1::new();
+    let fingerprint = xprv(1).fingerprint(&secp);
+    let account = |path: &str| {
+        let path = DerivationPath::from_str(path).unwrap();
+        xprv(1).derive_priv(&secp, &path).unwrap()
+    };
+    let key = |path: &str, xkey: String, derivation: &str| {
+        format!(
+            "wpkh([{}/{}]{}{})",
+            fingerprint,
+            &path[2..],
+            xkey,
+            derivation
+        )
+    };
+    let xpub = Xpub::from_priv(&secp, &account("m/84'/1'/0'")).to_string();
+    let descriptor = key("m/84'/1'/0'", xpub, "/0/*");
+    let psbt = create_psbt(&descriptor);
+    let signed = |private_descriptor: String| {
+        let mut psbt = psbt.clone();
+        (sign_psbt(&mut psbt, &private_descriptor).unwrap(), psbt)
+    };
+
+    // Another account of the same master key, or another derivation of the right one.
+    let multisig = account("m/48'/1'/0'/2'").to_string();
+    assert_eq!(signed(key("m/48'/1'/0'/2'", multisig, "/0/*")).0, 0);
+    let right = account("m/84'/1'/0'").to_string();
+    assert_eq!(signed(key("m/84'/1'/0'", right.clone(), "/1/*")).0, 0);
+    assert_eq!(signed(key("m/84'/1'/0'", right.clone(), "/0/*h")).0, 0);
+    assert_eq!(signed(key("m/84'/1'/0'", right.clone(), "/0/0")).0, 1);
+
+    // Signing errors are returned rather than skipped over.
+    let multipath = key("m/84'/1'/0'", right.clone(), "/<0;1>/*");
+    assert!(sign_psbt(&mut psbt.clone(), &multipath).is_err());
+
+    let (count, signed) = signed(key("m/84'/1'/0'", right, "/0/*"));
+    assert_eq!(count, 1);
+    finalize(signed);
+}
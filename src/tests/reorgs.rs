@@ -0,0 +1,183 @@
+//! Randomized reorg scenarios. Whatever sequence of blocks (dis)connected while the wallet is
+//! online or offline, it must end up in the same state as a wallet freshly synced from the final
+//! chain.
+
+use bdk_chain::{
+    bitcoin::{Amount, Transaction},
+    FullTxOut,
+};
+use proptest::prelude::*;
+
+use std::sync::{Arc, Mutex};
+
+use super::{mock_node::payment, run_local, start_wallet, stop_wallet, wallet_spk, MockNode};
+use crate::wallet_manager::WalletManager;
+
+// Payments are made to addresses within the default lookahead, so gap limit rescans (which only
+// run from the main loop) never come into play.
+const MAX_SPK_INDEX: u32 = 20;
+const MAX_REORG_DEPTH: u32 = 4;
+
+/// A payment to the wallet: the derivation index of the address, and an amount in sats.
+type Payment = (u32, u64);
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// Mine a block with these payments.
+    Mine(Vec<Payment>),
+    /// Replace the `depth` last blocks with these ones. If `reinclude` is set, the transactions of
+    /// the disconnected blocks are confirmed again in the first new block.
+    Reorg {
+        depth: u32,
+        blocks: Vec<Vec<Payment>>,
+        reinclude: bool,
+    },
+    /// Stop the wallet. Until it's restarted, blocks are not notified to it.
+    Stop,
+    /// Restart the wallet, which catches up with the chain.
+    Start,
+}
+
+fn payments() -> impl Strategy<Value = Vec<Payment>> {
+    prop::collection::vec((0..MAX_SPK_INDEX, 1..1_000u64), 0..3)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let reorg = (1..=MAX_REORG_DEPTH).prop_flat_map(|depth| {
+        // Never reorg to a chain with less blocks, Core wouldn't either.
+        let blocks = prop::collection::vec(payments(), depth as usize..depth as usize + 3);
+        (Just(depth), blocks, any::<bool>()).prop_map(|(depth, blocks, reinclude)| Op::Reorg {
+            depth,
+            blocks,
+            reinclude,
+        })
+    });
+    prop_oneof![
+        4 => payments().prop_map(Op::Mine),
+        2 => reorg,
+        1 => Just(Op::Stop),
+        1 => Just(Op::Start),
+    ]
+}
+
+/// Builds transactions which are all unique, even when paying the same amount to the same address.
+#[derive(Default)]
+struct TxFactory(u64);
+
+impl TxFactory {
+    fn txs(&mut self, payments: &[Payment]) -> Vec<Transaction> {
+        payments
+            .iter()
+            .map(|(index, amount)| {
+                self.0 += 1;
+                payment(
+                    wallet_spk(*index),
+                    Amount::from_sat(amount * 1_000_000 + self.0),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The state of the default wallet to compare: its tip, balance and coins.
+fn wallet_state(
+    manager: &Arc<Mutex<WalletManager>>,
+) -> (bdk_chain::BlockId, bdk_chain::Balance, Vec<String>) {
+    let manager = manager.lock().unwrap();
+    let wallet = manager.wallet("").unwrap();
+    let mut utxos: Vec<_> = wallet
+        .utxos()
+        .into_iter()
+        .map(
+            |FullTxOut {
+                 outpoint,
+                 txout,
+                 chain_position,
+                 ..
+             }| format!("{} {} {:?}", outpoint, txout.value, chain_position),
+        )
+        .collect();
+    utxos.sort();
+    (wallet.tip(), wallet.balance(), utxos)
+}
+
+fn run_scenario(ops: Vec<Op>) {
+    run_local(async {
+        let data_dir = tempfile::tempdir().unwrap();
+        let node = MockNode::new();
+        let mut factory = TxFactory::default();
+        node.mine(vec![]);
+        let mut wallet = Some(start_wallet(&node, data_dir.path()).await);
+
+        for op in ops {
+            match op {
+                Op::Mine(payments) => {
+                    let txs = factory.txs(&payments);
+                    if wallet.is_some() {
+                        node.connect_block(txs).await;
+                    } else {
+                        node.mine(txs);
+                    }
+                }
+                Op::Reorg {
+                    depth,
+                    blocks,
+                    reinclude,
+                } => {
+                    let depth = depth.min(node.height());
+                    if depth == 0 {
+                        continue;
+                    }
+                    let mut new_blocks: Vec<_> = blocks.iter().map(|p| factory.txs(p)).collect();
+                    if reinclude {
+                        let tip_height = node.height();
+                        let stale_txs = (tip_height - depth + 1..=tip_height)
+                            .flat_map(|h| node.block(h).txdata.into_iter().skip(1));
+                        new_blocks[0].extend(stale_txs);
+                    }
+                    if wallet.is_some() {
+                        node.reorg_and_notify(depth, new_blocks).await;
+                    } else {
+                        node.reorg(depth, new_blocks);
+                    }
+                }
+                Op::Stop => {
+                    if let Some((rpc, _, subscription)) = wallet.take() {
+                        stop_wallet(rpc, subscription).await;
+                    }
+                }
+                Op::Start => {
+                    if wallet.is_none() {
+                        wallet = Some(start_wallet(&node, data_dir.path()).await);
+                    }
+                }
+            }
+        }
+
+        // Stopping the wallet processes the notifications still queued.
+        let (rpc, manager, subscription) = match wallet {
+            Some(wallet) => wallet,
+            None => start_wallet(&node, data_dir.path()).await,
+        };
+        stop_wallet(rpc, subscription).await;
+        let state = wallet_state(&manager);
+
+        let fresh_dir = tempfile::tempdir().unwrap();
+        let (fresh_rpc, fresh_manager, fresh_subscription) =
+            start_wallet(&node, fresh_dir.path()).await;
+        stop_wallet(fresh_rpc, fresh_subscription).await;
+        let fresh_state = wallet_state(&fresh_manager);
+
+        assert_eq!(state.0.hash, node.tip());
+        assert_eq!(state, fresh_state);
+    });
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn same_state_as_fresh_sync(ops in prop::collection::vec(op(), 1..20)) {
+        run_scenario(ops);
+    }
+}
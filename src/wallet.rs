@@ -0,0 +1,1766 @@
+//! The state of a single BDK wallet, persisted to its own store.
+
+use bdk_chain::{
+    self,
+    bitcoin,
+    keychain_txout::{KeychainTxOutIndex, DEFAULT_LOOKAHEAD},
+    local_chain::LocalChain,
+    miniscript::{psbt::PsbtExt, DefiniteDescriptorKey, Descriptor, DescriptorPublicKey},
+    Balance, BlockId, ChainPosition, CheckPoint, ConfirmationBlockTime, DescriptorExt,
+    DescriptorId, FullTxOut, IndexedTxGraph, Merge,
+};
+use tokio::sync::Notify;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    backup::WalletBackup,
+    descriptors::{self, CoreDescriptor, ImportedDescriptor},
+    persist::{
+        self, Backend, BatchedStore, FileBackend, Queries, SideStore, StoreBackend, StoreConfig,
+        StoreContent, StoreHeader, StoredTx,
+    },
+    policy::{self, SpendingPath},
+    sqlite_store::SqliteBackend,
+    store_crypto::{self, FileSealer, StoreKey},
+    DESCRIPTOR, NETWORK,
+};
+
+// Persistence for the BDK wallet state
+const BDK_STORE_PATH: &str = "bdk_core_store.dat";
+// Or, with the SQLite backend:
+const BDK_SQLITE_PATH: &str = "bdk_core_store.sqlite";
+// Persistence for the wallet settings (such as the lookahead of each keychain). Kept separate from
+// the wallet state so the format of existing stores isn't affected.
+const BDK_SETTINGS_PATH: &str = "bdk_core_settings.dat";
+const BDK_SETTINGS_MAGIC: &[u8] = b"bdk_core_settings";
+// Persistence for the coins locked by the users of the wallet. Locks are short-lived reservations,
+// they aren't part of backups.
+const BDK_LOCKS_PATH: &str = "bdk_core_locks.dat";
+const BDK_LOCKS_MAGIC: &[u8] = b"bdk_core_locks";
+// Named wallets each live in their own directory under this one, with their descriptor stored in
+// a text file next to their store, sealed if the store is encrypted.
+const WALLETS_DIR: &str = "bdk_core_wallets";
+const DESCRIPTOR_FILE: &str = "descriptor";
+
+/// The keychain of the descriptor the wallet was created with, which new addresses are derived
+/// from. Each imported descriptor gets the next keychain, in the order they were imported.
+pub const PRIMARY_KEYCHAIN: u32 = 0;
+
+/// The directory holding the files of the wallet with this name. The default wallet (with an
+/// empty name) lives at the root of the data directory, where its store always was.
+fn wallet_dir(data_dir: &Path, name: &str) -> PathBuf {
+    if name.is_empty() {
+        data_dir.to_path_buf()
+    } else {
+        data_dir.join(WALLETS_DIR).join(name)
+    }
+}
+
+/// Open the store of the wallet in this directory as configured. Switching an existing wallet
+/// to SQLite migrates its file store.
+fn open_backend(
+    dir: &Path,
+    desc: &Descriptor<DescriptorPublicKey>,
+    config: &StoreConfig,
+) -> Result<Box<dyn Backend<ChangeSet>>, Box<dyn error::Error>> {
+    let file_path = dir.join(BDK_STORE_PATH);
+    let sqlite_path = dir.join(BDK_SQLITE_PATH);
+    match config.backend {
+        StoreBackend::File => {
+            if sqlite_path.exists() {
+                return Err(format!(
+                    "The wallet store in {} was migrated to SQLite, use '--store sqlite'.",
+                    dir.display()
+                )
+                .into());
+            }
+            Ok(Box::new(FileBackend::open(
+                &file_path,
+                store_header(desc),
+                config.key.as_ref(),
+            )?))
+        }
+        StoreBackend::Sqlite if config.key.is_some() => {
+            Err("Only the file store can be encrypted.".into())
+        }
+        StoreBackend::Sqlite => Ok(Box::new(SqliteBackend::open_or_migrate(
+            &sqlite_path,
+            &file_path,
+            store_header(desc),
+        )?)),
+    }
+}
+
+/// The header of the file store of a wallet tracking this descriptor.
+fn store_header(desc: &Descriptor<DescriptorPublicKey>) -> StoreHeader {
+    let desc = desc.to_string();
+    let checksum = desc.rsplit_once('#').map_or("", |(_, checksum)| checksum);
+    StoreHeader::new(NETWORK, checksum)
+}
+
+/// The descriptor of the wallet with this name in this directory. The default wallet (with an
+/// empty name) tracks the [`DESCRIPTOR`]. A sealed descriptor needs the key of the wallet's store.
+fn wallet_descriptor(
+    dir: &Path,
+    name: &str,
+    key: Option<&StoreKey>,
+) -> Result<Descriptor<DescriptorPublicKey>, Box<dyn error::Error>> {
+    if name.is_empty() {
+        return Ok(Descriptor::from_str(DESCRIPTOR)
+            .expect("DESCRIPTOR constant must be a valid descriptor."));
+    }
+    let desc_path = dir.join(DESCRIPTOR_FILE);
+    if !desc_path.exists() {
+        return Err(format!("Wallet '{}' does not exist.", name).into());
+    }
+    let desc = if store_crypto::is_sealed(&desc_path)? {
+        FileSealer::open::<String>(&desc_path, key, DESCRIPTOR_FILE.as_bytes())?.1
+    } else {
+        fs::read_to_string(desc_path)?
+    };
+    Ok(Descriptor::from_str(desc.trim())?)
+}
+
+/// Write the descriptor of a named wallet in its directory, sealed with the key of the wallet's
+/// store if there's one.
+fn write_descriptor(
+    dir: &Path,
+    desc: &Descriptor<DescriptorPublicKey>,
+    key: Option<&StoreKey>,
+) -> Result<(), Box<dyn error::Error>> {
+    let desc_path = dir.join(DESCRIPTOR_FILE);
+    match key {
+        Some(key) => {
+            FileSealer::new(key, DESCRIPTOR_FILE.as_bytes())?.write(&desc_path, &desc.to_string())
+        }
+        None => Ok(fs::write(desc_path, desc.to_string())?),
+    }
+}
+
+/// Wallet names are used as directory names so restrict them to a safe set of characters.
+fn check_wallet_name(name: &str) -> Result<(), Box<dyn error::Error>> {
+    if name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(format!("Invalid wallet name '{}'.", name).into())
+    }
+}
+
+/// The block confirming this coin, if any.
+fn confirmation(utxo: &FullTxOut<ConfirmationBlockTime>) -> Option<&ConfirmationBlockTime> {
+    match &utxo.chain_position {
+        ChainPosition::Confirmed(anchor) => Some(anchor),
+        ChainPosition::Unconfirmed(_) => None,
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChangeSet {
+    pub chain_cs: bdk_chain::local_chain::ChangeSet,
+    pub graph_cs: bdk_chain::indexed_tx_graph::ChangeSet<
+        ConfirmationBlockTime,
+        bdk_chain::indexer::keychain_txout::ChangeSet,
+    >,
+}
+
+impl Merge for ChangeSet {
+    fn merge(&mut self, other: Self) {
+        Merge::merge(&mut self.chain_cs, other.chain_cs);
+        Merge::merge(&mut self.graph_cs, other.graph_cs);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chain_cs.is_empty() && self.graph_cs.is_empty()
+    }
+}
+
+/// Wallet settings, keyed by descriptor id so they are independent from the keychain type.
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SettingsChangeSet {
+    /// The number of script pubkeys to derive past the last revealed one, for each keychain.
+    lookahead: BTreeMap<DescriptorId, u32>,
+}
+
+impl Merge for SettingsChangeSet {
+    fn merge(&mut self, other: Self) {
+        Merge::merge(&mut self.lookahead, other.lookahead);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lookahead.is_empty()
+    }
+}
+
+/// A coin reserved by a user of the wallet, which coin selection leaves out.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UtxoLock {
+    /// Why the coin is locked, for instance the payment it's reserved for.
+    pub reason: String,
+    /// The time at which the lock expires, if it does.
+    pub expires: Option<u64>,
+}
+
+impl UtxoLock {
+    /// Whether the lock is still in force at this time.
+    pub fn is_active(&self, time: u64) -> bool {
+        self.expires.is_none_or(|expires| time < expires)
+    }
+}
+
+/// Why a transaction was removed from the mempool, as in Core's `MemPoolRemovalReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MempoolRemovalReason {
+    Expiry,
+    SizeLimit,
+    Reorg,
+    Block,
+    Conflict,
+    Replaced,
+}
+
+impl MempoolRemovalReason {
+    /// The reason as sent in Core's notifications, if known.
+    pub fn from_core(reason: i32) -> Option<Self> {
+        Some(match reason {
+            0 => Self::Expiry,
+            1 => Self::SizeLimit,
+            2 => Self::Reorg,
+            3 => Self::Block,
+            4 => Self::Conflict,
+            5 => Self::Replaced,
+            _ => return None,
+        })
+    }
+}
+
+/// Changes to the locked coins of the wallet. Coins mapped to `None` were unlocked.
+#[derive(Default, Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LocksChangeSet {
+    locks: BTreeMap<bitcoin::OutPoint, Option<UtxoLock>>,
+}
+
+impl Merge for LocksChangeSet {
+    fn merge(&mut self, other: Self) {
+        Merge::merge(&mut self.locks, other.locks);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+}
+
+/// A transaction involving the wallet.
+#[derive(Debug, Clone)]
+pub struct WalletTx {
+    pub txid: bitcoin::Txid,
+    /// The value of the wallet's outputs spent by this transaction.
+    pub sent: bitcoin::Amount,
+    /// The value of the outputs of this transaction paying to the wallet.
+    pub received: bitcoin::Amount,
+    /// The fee it pays, if all the outputs it spends are known to the wallet.
+    pub fee: Option<bitcoin::Amount>,
+    /// The feerate it pays, if its fee is known.
+    pub feerate: Option<bitcoin::FeeRate>,
+    /// Whether it signals that it can be replaced (BIP 125).
+    pub rbf: bool,
+    /// The block it's confirmed in, if any.
+    pub confirmation: Option<ConfirmationBlockTime>,
+    /// The number of blocks confirming it, 0 if unconfirmed.
+    pub confirmations: u32,
+}
+
+impl WalletTx {
+    /// The amount this transaction adds to (or, if negative, removes from) the wallet's balance.
+    pub fn net(&self) -> bitcoin::SignedAmount {
+        self.received
+            .to_signed()
+            .unwrap_or(bitcoin::SignedAmount::MAX)
+            - self.sent.to_signed().unwrap_or(bitcoin::SignedAmount::MAX)
+    }
+}
+
+/// Which of the wallet's transactions to list with [`BdkWallet::list_transactions`]. Unconfirmed
+/// transactions have neither a height nor a time: they only match filters without a maximum.
+#[derive(Debug, Clone, Default)]
+pub struct TxFilter {
+    /// Only transactions confirmed at or above this height.
+    pub min_height: Option<u32>,
+    /// Only transactions confirmed at or below this height.
+    pub max_height: Option<u32>,
+    /// Only transactions confirmed in a block with this time or later.
+    pub min_time: Option<u64>,
+    /// Only transactions confirmed in a block with this time or earlier.
+    pub max_time: Option<u64>,
+    /// Leave out this many of the most recent matching transactions.
+    pub skip: usize,
+    /// List at most this many transactions, all of them if unset.
+    pub count: Option<usize>,
+}
+
+impl TxFilter {
+    fn matches(&self, tx: &WalletTx) -> bool {
+        match tx.confirmation {
+            Some(conf) => {
+                self.min_height.is_none_or(|h| conf.block_id.height >= h)
+                    && self.max_height.is_none_or(|h| conf.block_id.height <= h)
+                    && self.min_time.is_none_or(|t| conf.confirmation_time >= t)
+                    && self.max_time.is_none_or(|t| conf.confirmation_time <= t)
+            }
+            None => self.max_height.is_none() && self.max_time.is_none(),
+        }
+    }
+}
+
+/// A spending path of the coins of a keychain to create a PSBT with.
+#[derive(Debug, Clone, Copy)]
+pub struct PathChoice {
+    pub keychain: u32,
+    /// The index of the path among the [`BdkWallet::spending_paths`] of the keychain.
+    pub index: usize,
+    /// The current time, which time locks are checked against.
+    pub time: u64,
+}
+
+/// The coins to fund a PSBT with, on top of those picked by coin selection.
+#[derive(Debug, Clone, Default)]
+pub struct CoinControl {
+    /// Coins which must be spent, even if they are locked.
+    pub include: Vec<bitcoin::OutPoint>,
+    /// Coins which must not be spent.
+    pub exclude: Vec<bitcoin::OutPoint>,
+    /// Lock the coins spent by the PSBT with this lock, so they aren't selected again.
+    pub lock: Option<UtxoLock>,
+    /// The current time, which the expiry of the locks is checked against.
+    pub time: u64,
+}
+
+/// Why a PSBT couldn't be created.
+#[derive(Debug)]
+pub enum CreatePsbtError {
+    /// The coins which may be spent don't cover the amounts paid and the fee.
+    InsufficientFunds {
+        available: bitcoin::Amount,
+        needed: bitcoin::Amount,
+    },
+    /// The recipients, feerate, spending path or coins asked for can't be used.
+    InvalidParameter(String),
+    /// Any other failure, such as when persisting the change address or the locks.
+    Wallet(Box<dyn error::Error>),
+}
+
+impl CreatePsbtError {
+    fn wallet(e: impl Into<Box<dyn error::Error>>) -> Self {
+        Self::Wallet(e.into())
+    }
+}
+
+impl fmt::Display for CreatePsbtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InsufficientFunds { available, needed } => write!(
+                f,
+                "Insufficient funds: {} available, {} needed.",
+                available, needed
+            ),
+            Self::InvalidParameter(e) => f.write_str(e),
+            Self::Wallet(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for CreatePsbtError {}
+
+/// An unspent coin of the wallet, along with the spending paths of its descriptor.
+#[derive(Debug, Clone)]
+pub struct CoinPaths {
+    pub utxo: FullTxOut<ConfirmationBlockTime>,
+    pub keychain: u32,
+    /// The paths, with the keys derived for this coin, and whether their time locks are met.
+    pub paths: Vec<(SpendingPath<DefiniteDescriptorKey>, bool)>,
+}
+
+/// The wallet state. Maintains the BDK transaction graph and chain state.
+pub struct BdkWallet {
+    name: String,
+    chain: LocalChain,
+    tx_graph: IndexedTxGraph<ConfirmationBlockTime, KeychainTxOutIndex<u32>>,
+    store: BatchedStore<ChangeSet>,
+    settings: SettingsChangeSet,
+    settings_store: SideStore<SettingsChangeSet>,
+    // The locked coins, expired locks included.
+    locks: BTreeMap<bitcoin::OutPoint, UtxoLock>,
+    locks_store: SideStore<LocksChangeSet>,
+    // The directory the wallet is persisted in, and the key its files are sealed with if any.
+    dir: PathBuf,
+    key: Option<StoreKey>,
+    // The descriptors imported next to the primary one, in the order of their keychains.
+    imported: Vec<ImportedDescriptor>,
+    // Range of heights of the wallet's chain which needs to be rescanned because the script
+    // pubkeys being watched were extended past what was used to process these blocks, or
+    // descriptors were imported.
+    pending_rescan: Option<(u32, u32)>,
+    rescan_notifier: Arc<Notify>,
+}
+
+impl BdkWallet {
+    /// Create a new named wallet tracking this descriptor in the data directory. Fails if it
+    /// already exists.
+    pub fn create(
+        data_dir: &Path,
+        name: &str,
+        descriptor: &str,
+        store: &StoreConfig,
+        rescan_notifier: Arc<Notify>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        check_wallet_name(name)?;
+        if name.is_empty() {
+            return Err("The default wallet can't be created, it always exists.".into());
+        }
+        let desc = Descriptor::<DescriptorPublicKey>::from_str(descriptor)?;
+        let dir = wallet_dir(data_dir, name);
+        if dir.exists() {
+            return Err(format!("Wallet '{}' already exists.", name).into());
+        }
+        fs::create_dir_all(&dir)?;
+        write_descriptor(&dir, &desc, store.key.as_ref())?;
+        Self::open(dir, name, desc, store, rescan_notifier)
+    }
+
+    /// Load the wallet with this name from the data directory. The default wallet (with an empty
+    /// name) tracks the [`DESCRIPTOR`] and is created if its store doesn't exist yet.
+    pub fn load(
+        data_dir: &Path,
+        name: &str,
+        store: &StoreConfig,
+        rescan_notifier: Arc<Notify>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        check_wallet_name(name)?;
+        let dir = wallet_dir(data_dir, name);
+        let desc = wallet_descriptor(&dir, name, store.key.as_ref())?;
+        let mut wallet = Self::open(dir, name, desc, store, rescan_notifier)?;
+        wallet.schedule_import_rescans()?;
+        Ok(wallet)
+    }
+
+    /// Restore a wallet from this snapshot in the data directory, under this name. Fails if the
+    /// wallet already exists. The default wallet (with an empty name) can only be restored from a
+    /// snapshot of a wallet tracking the [`DESCRIPTOR`].
+    pub fn restore(
+        data_dir: &Path,
+        name: &str,
+        snapshot: &WalletBackup,
+        store: &StoreConfig,
+        rescan_notifier: Arc<Notify>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        check_wallet_name(name)?;
+        let desc = Descriptor::<DescriptorPublicKey>::from_str(&snapshot.descriptor)?;
+        let dir = wallet_dir(data_dir, name);
+        if name.is_empty() {
+            if desc != wallet_descriptor(&dir, name, None)? {
+                return Err("The default wallet can't be restored with another descriptor.".into());
+            }
+            if dir.join(BDK_STORE_PATH).exists() || dir.join(BDK_SQLITE_PATH).exists() {
+                return Err("The default wallet already exists.".into());
+            }
+        } else {
+            if dir.exists() {
+                return Err(format!("Wallet '{}' already exists.", name).into());
+            }
+            fs::create_dir_all(&dir)?;
+            write_descriptor(&dir, &desc, store.key.as_ref())?;
+        }
+        let restored = (|| {
+            let key = store.key.as_ref();
+            SideStore::open(&dir.join(BDK_SETTINGS_PATH), BDK_SETTINGS_MAGIC, key)?
+                .0
+                .append(&snapshot.settings)?;
+            if !snapshot.imported.is_empty() {
+                descriptors::write_imported(&dir, &snapshot.imported, key)?;
+            }
+            let mut wallet = Self::open(dir.clone(), name, desc, store, rescan_notifier)?;
+            wallet.chain.apply_changeset(&snapshot.changeset.chain_cs)?;
+            wallet
+                .tx_graph
+                .apply_changeset(snapshot.changeset.graph_cs.clone());
+            wallet.replenish_lookahead();
+            if wallet.tip() != snapshot.tip {
+                return Err("The state of the backup doesn't match its tip.".into());
+            }
+            wallet.store.commit(snapshot.changeset.clone())?;
+            wallet.schedule_import_rescans()?;
+            Ok(wallet)
+        })();
+        if restored.is_err() && !name.is_empty() {
+            fs::remove_dir_all(&dir)?;
+        }
+        restored
+    }
+
+    /// A snapshot of the wallet's state, along with what's needed to restore it.
+    pub fn backup(&self) -> WalletBackup {
+        WalletBackup {
+            network: NETWORK,
+            name: self.name.clone(),
+            descriptor: self
+                .tx_graph
+                .index
+                .get_descriptor(PRIMARY_KEYCHAIN)
+                .expect("The wallet's keychain is always inserted.")
+                .to_string(),
+            imported: self.imported.clone(),
+            tip: self.tip(),
+            settings: self.settings.clone(),
+            changeset: ChangeSet {
+                chain_cs: self.chain.initial_changeset(),
+                graph_cs: self.tx_graph.initial_changeset(),
+            },
+        }
+    }
+
+    /// Import this descriptor, with its checksum, into the wallet with this name in the data
+    /// directory. It's watched from the `birthday` height, up to the `range_end` derivation index
+    /// at least if it's ranged. The wallet's chain is rescanned from the birthday the next time
+    /// the wallet is started, so it must not be in use. Its store is opened as configured, and
+    /// the wallet's files are sealed with the key of its store if it's encrypted.
+    pub fn import_descriptor(
+        data_dir: &Path,
+        name: &str,
+        descriptor: &str,
+        birthday: u32,
+        range_end: Option<u32>,
+        store: &StoreConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        check_wallet_name(name)?;
+        let dir = wallet_dir(data_dir, name);
+        let key = store.key.as_ref();
+        let primary = wallet_descriptor(&dir, name, key)?;
+        // Opening the store checks its backend and its key, before the key is used to seal the
+        // imported descriptors.
+        if dir.join(BDK_STORE_PATH).exists() || dir.join(BDK_SQLITE_PATH).exists() {
+            open_backend(&dir, &primary, store)?;
+        }
+        let desc = descriptors::parse_descriptor(descriptor)?;
+        if range_end.is_some() && !desc.has_wildcard() {
+            return Err("A range can only be given for a ranged descriptor.".into());
+        }
+        let mut imported = descriptors::read_imported(&dir, key)?;
+        let already_tracked = desc.descriptor_id() == primary.descriptor_id()
+            || imported.iter().any(|import| {
+                descriptors::parse_descriptor(&import.descriptor)
+                    .is_ok_and(|d| d.descriptor_id() == desc.descriptor_id())
+            });
+        if already_tracked {
+            return Err(format!("Wallet '{}' already tracks this descriptor.", name).into());
+        }
+        imported.push(ImportedDescriptor {
+            descriptor: desc.to_string(),
+            birthday,
+            range_end,
+            rescanned: false,
+        });
+        descriptors::write_imported(&dir, &imported, key)
+    }
+
+    /// The wallet's descriptors, in the format of Bitcoin Core's `importdescriptors`. Only the
+    /// primary one is active, and only if it's ranged as Core requires. A descriptor is scanned for
+    /// from the time of the first block which paid to it, or from the genesis block if there is
+    /// none or if it was imported but not rescanned yet.
+    pub fn export_descriptors(&self) -> Vec<CoreDescriptor> {
+        self.tx_graph
+            .index
+            .keychains()
+            .map(|(keychain, desc)| {
+                let rescanned = self.import(keychain).is_none_or(|import| import.rescanned);
+                let first_payment = self
+                    .first_activity(keychain)
+                    .map(|anchor| anchor.confirmation_time);
+                let ranged = desc.has_wildcard();
+                let next_index = self
+                    .tx_graph
+                    .index
+                    .last_revealed_index(keychain)
+                    .map_or(0, |i| i + 1);
+                let active = keychain == PRIMARY_KEYCHAIN && ranged;
+                CoreDescriptor {
+                    desc: desc.to_string(),
+                    timestamp: first_payment
+                        .filter(|_| rescanned)
+                        .and_then(|time| time.try_into().ok())
+                        .unwrap_or(0),
+                    active,
+                    internal: active.then_some(false),
+                    range: self
+                        .last_watched_index(keychain)
+                        .filter(|_| ranged)
+                        .map(|end| [0, end]),
+                    next_index: ranged.then_some(next_index),
+                }
+            })
+            .collect()
+    }
+
+    /// The path to the file store of the wallet with this name in the data directory.
+    fn store_file(data_dir: &Path, name: &str) -> Result<PathBuf, Box<dyn error::Error>> {
+        check_wallet_name(name)?;
+        let dir = wallet_dir(data_dir, name);
+        let path = dir.join(BDK_STORE_PATH);
+        if !path.exists() {
+            if dir.join(BDK_SQLITE_PATH).exists() {
+                return Err(format!(
+                    "Wallet '{}' is persisted in SQLite, not in a file store.",
+                    name
+                )
+                .into());
+            }
+            return Err(format!("Wallet '{}' does not exist.", name).into());
+        }
+        Ok(path)
+    }
+
+    /// Open the file store of the wallet with this name in the data directory, with its key if
+    /// it's encrypted. The wallet must not be in use.
+    fn open_store_file(
+        data_dir: &Path,
+        name: &str,
+        key: Option<&StoreKey>,
+    ) -> Result<FileBackend<ChangeSet>, Box<dyn error::Error>> {
+        let path = Self::store_file(data_dir, name)?;
+        let desc = wallet_descriptor(&wallet_dir(data_dir, name), name, key)?;
+        FileBackend::open(&path, store_header(&desc), key)
+    }
+
+    /// Compact the file store of the wallet with this name in the data directory. Returns its size
+    /// before and after. The wallet must not be in use.
+    pub fn compact_store(
+        data_dir: &Path,
+        name: &str,
+        key: Option<&StoreKey>,
+    ) -> Result<(u64, u64), Box<dyn error::Error>> {
+        Self::open_store_file(data_dir, name, key)?.compact()
+    }
+
+    /// Encrypt the file store of the wallet with this name in the data directory with `new_key`
+    /// instead of `key`. Either may be missing, to encrypt a store which isn't or to decrypt one.
+    /// The files next to the store are sealed with the new key too, or written in clear. The
+    /// wallet must not be in use.
+    pub fn rotate_store_key(
+        data_dir: &Path,
+        name: &str,
+        key: Option<&StoreKey>,
+        new_key: Option<&StoreKey>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        // Everything is read with the current key before anything is rewritten.
+        let mut backend = Self::open_store_file(data_dir, name, key)?;
+        let dir = wallet_dir(data_dir, name);
+        let desc = wallet_descriptor(&dir, name, key)?;
+        let imported = descriptors::read_imported(&dir, key)?;
+        if !name.is_empty() {
+            write_descriptor(&dir, &desc, new_key)?;
+        }
+        if !imported.is_empty() {
+            descriptors::write_imported(&dir, &imported, new_key)?;
+        }
+        let settings_path = dir.join(BDK_SETTINGS_PATH);
+        SideStore::<SettingsChangeSet>::rotate_key(
+            &settings_path,
+            BDK_SETTINGS_MAGIC,
+            key,
+            new_key,
+        )?;
+        let locks_path = dir.join(BDK_LOCKS_PATH);
+        SideStore::<LocksChangeSet>::rotate_key(&locks_path, BDK_LOCKS_MAGIC, key, new_key)?;
+        backend.rotate_key(new_key)
+    }
+
+    /// Read the header and the changesets of the file store of the wallet with this name in the
+    /// data directory, to inspect it. See [`persist::read_store`].
+    pub fn read_store_file(
+        data_dir: &Path,
+        name: &str,
+        key: Option<&StoreKey>,
+    ) -> Result<StoreContent<ChangeSet>, Box<dyn error::Error>> {
+        let path = Self::store_file(data_dir, name)?;
+        persist::read_store(&path, key)
+    }
+
+    /// Open the wallet's store in this directory, creating it if it's not available.
+    fn open(
+        dir: PathBuf,
+        name: &str,
+        desc: Descriptor<DescriptorPublicKey>,
+        store: &StoreConfig,
+        rescan_notifier: Arc<Notify>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let (mut chain, _) =
+            LocalChain::from_genesis_hash(bitcoin::constants::genesis_block(NETWORK).block_hash());
+        // Opening the store checks the key, before it's used to seal the other files.
+        let backend = open_backend(&dir, &desc, store)?;
+        let key = store.key.as_ref();
+        let (settings_store, settings) = SideStore::<SettingsChangeSet>::open(
+            &dir.join(BDK_SETTINGS_PATH),
+            BDK_SETTINGS_MAGIC,
+            key,
+        )?;
+        let (locks_store, locks) =
+            SideStore::<LocksChangeSet>::open(&dir.join(BDK_LOCKS_PATH), BDK_LOCKS_MAGIC, key)?;
+        let locks = locks
+            .locks
+            .into_iter()
+            .filter_map(|(outpoint, lock)| Some((outpoint, lock?)))
+            .collect();
+        let imported = descriptors::read_imported(&dir, key)?;
+        // Seal the files written in clear before they were sealed along with the store.
+        if let Some(key) = key {
+            let desc_path = dir.join(DESCRIPTOR_FILE);
+            if desc_path.exists() && !store_crypto::is_sealed(&desc_path)? {
+                write_descriptor(&dir, &desc, Some(key))?;
+            }
+            if descriptors::imported_in_clear(&dir)? {
+                descriptors::write_imported(&dir, &imported, Some(key))?;
+            }
+        }
+        // The lookahead of the index is used when new script pubkeys are revealed while
+        // processing a block. Keychains configured with a larger one are extended after each
+        // update.
+        let lookahead = settings
+            .lookahead
+            .get(&desc.descriptor_id())
+            .copied()
+            .unwrap_or(DEFAULT_LOOKAHEAD);
+        let mut index = KeychainTxOutIndex::new(lookahead);
+        index
+            .insert_descriptor(PRIMARY_KEYCHAIN, desc)
+            .expect("First to be inserted");
+        for (keychain, import) in (PRIMARY_KEYCHAIN + 1..).zip(&imported) {
+            let desc = descriptors::parse_descriptor(&import.descriptor)?;
+            index.insert_descriptor(keychain, desc).map_err(|_| {
+                format!(
+                    "Descriptor {} is imported more than once.",
+                    import.descriptor
+                )
+            })?;
+        }
+        let mut tx_graph = IndexedTxGraph::new(index);
+        let (store, cs) = BatchedStore::new(backend)?;
+        chain.apply_changeset(&cs.chain_cs)?;
+        tx_graph.apply_changeset(cs.graph_cs);
+        let mut wallet = Self {
+            name: name.to_string(),
+            chain,
+            tx_graph,
+            store,
+            settings,
+            settings_store,
+            locks,
+            locks_store,
+            dir,
+            key: key.cloned(),
+            imported,
+            pending_rescan: None,
+            rescan_notifier,
+        };
+        wallet.replenish_lookahead();
+        Ok(wallet)
+    }
+
+    /// The keychains of the wallet: its primary one followed by those of the imported
+    /// descriptors.
+    pub fn keychains(&self) -> Vec<u32> {
+        self.tx_graph
+            .index
+            .keychains()
+            .map(|(keychain, _)| keychain)
+            .collect()
+    }
+
+    /// The lookahead configured for this keychain.
+    pub fn lookahead(&self, keychain: u32) -> u32 {
+        self.tx_graph
+            .index
+            .get_descriptor(keychain)
+            .and_then(|desc| self.settings.lookahead.get(&desc.descriptor_id()))
+            .copied()
+            .unwrap_or(DEFAULT_LOOKAHEAD)
+    }
+
+    /// Set the number of script pubkeys to watch past the last revealed one for this keychain.
+    /// Persist it to disk. If the lookahead is extended, the wallet's chain is scheduled for a
+    /// rescan with the new script pubkeys, from the first activity of the keychain.
+    pub fn set_lookahead(
+        &mut self,
+        keychain: u32,
+        lookahead: u32,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let previous = self.lookahead(keychain);
+        if previous == lookahead {
+            return Ok(());
+        }
+        let did = self
+            .tx_graph
+            .index
+            .get_descriptor(keychain)
+            .ok_or("Unknown keychain.")?
+            .descriptor_id();
+        let cs = SettingsChangeSet {
+            lookahead: [(did, lookahead)].into(),
+        };
+        self.settings_store.append(&cs)?;
+        self.settings.merge(cs);
+        self.replenish_lookahead();
+        if lookahead > previous {
+            self.schedule_rescan(self.rescan_start(keychain), self.tip().height);
+        }
+        Ok(())
+    }
+
+    /// The imported descriptor of this keychain, if it's not the primary one.
+    fn import(&self, keychain: u32) -> Option<&ImportedDescriptor> {
+        let i = keychain.checked_sub(PRIMARY_KEYCHAIN + 1)?;
+        self.imported.get(usize::try_from(i).ok()?)
+    }
+
+    /// The last derivation index watched for this keychain: as per its lookahead past its last
+    /// revealed index, or up to the end of the range it was imported with if further.
+    fn last_watched_index(&self, keychain: u32) -> Option<u32> {
+        let next_index = self
+            .tx_graph
+            .index
+            .last_revealed_index(keychain)
+            .map_or(0, |i| i + 1);
+        let range_end = self.import(keychain).and_then(|import| import.range_end);
+        (next_index + self.lookahead(keychain))
+            .checked_sub(1)
+            .max(range_end)
+    }
+
+    /// Make sure the index derives enough script pubkeys past the last revealed index of each
+    /// keychain, as per its configured lookahead.
+    fn replenish_lookahead(&mut self) {
+        for keychain in self.keychains() {
+            if let Some(target) = self.last_watched_index(keychain) {
+                self.tx_graph.index.lookahead_to_target(keychain, target);
+            }
+        }
+    }
+
+    /// Extend the script pubkeys being watched after an update revealed new indexes, and detect
+    /// whether any keychain was used close to the end of its previous lookahead window. If so,
+    /// there might be more script pubkeys in use past the window, which we would have missed in
+    /// the blocks we already processed. In this case schedule a rescan of the wallet's chain, from
+    /// the first activity of the keychain up to `height`, with the extended set of script pubkeys.
+    fn check_gap_limit(&mut self, revealed_before: &BTreeMap<u32, u32>, height: u32) {
+        let revealed_after = self.tx_graph.index.last_revealed_indices();
+        self.replenish_lookahead();
+        for (keychain, index) in revealed_after {
+            let prev_index = revealed_before.get(&keychain).copied();
+            if prev_index >= Some(index) {
+                continue;
+            }
+            let lookahead = self.lookahead(keychain);
+            let window_end = prev_index.map_or(0, |i| i + 1) + lookahead;
+            let margin = (lookahead / 4).max(1);
+            if index + margin >= window_end {
+                println!(
+                    "Index {} used close to the end of the lookahead (ending at {}). Scheduling a rescan with the extended script pubkeys.",
+                    index, window_end
+                );
+                self.schedule_rescan(self.rescan_start(keychain), height);
+            }
+        }
+    }
+
+    /// The first block of the wallet's chain which paid to this keychain, if any.
+    fn first_activity(&self, keychain: u32) -> Option<ConfirmationBlockTime> {
+        let graph = self.tx_graph.graph();
+        self.tx_graph
+            .index
+            .keychain_outpoints(keychain)
+            .filter_map(|(_, op)| {
+                match graph.get_chain_position(&self.chain, self.tip(), op.txid)? {
+                    ChainPosition::Confirmed(anchor) => Some(*anchor),
+                    ChainPosition::Unconfirmed(_) => None,
+                }
+            })
+            .min_by_key(|anchor| anchor.block_id.height)
+    }
+
+    /// The height to rescan from when more script pubkeys of this keychain are watched. None of
+    /// them can have been used before the keychain's first activity. Without any, it's rescanned
+    /// from the birthday of the descriptor if it was imported, or else from the genesis block.
+    fn rescan_start(&self, keychain: u32) -> u32 {
+        self.first_activity(keychain)
+            .map(|anchor| anchor.block_id.height)
+            .or_else(|| self.import(keychain).map(|import| import.birthday))
+            .unwrap_or(0)
+    }
+
+    /// Record that the wallet's chain needs to be rescanned between these heights (inclusive).
+    fn schedule_rescan(&mut self, from_height: u32, to_height: u32) {
+        self.pending_rescan = Some(match self.pending_rescan {
+            Some((from, to)) => (from.min(from_height), to.max(to_height)),
+            None => (from_height, to_height),
+        });
+        self.rescan_notifier.notify_one();
+    }
+
+    /// Get the range of heights of the wallet's chain which needs to be rescanned, if any, and
+    /// clear it.
+    pub fn take_pending_rescan(&mut self) -> Option<(u32, u32)> {
+        self.pending_rescan.take()
+    }
+
+    /// Schedule the rescan of the wallet's chain from the birthday of the descriptors imported
+    /// since it was last running. The blocks above its tip are processed with all its keychains
+    /// anyway, so there is nothing to rescan for a descriptor born above it.
+    fn schedule_import_rescans(&mut self) -> Result<(), Box<dyn error::Error>> {
+        let tip_height = self.tip().height;
+        let mut born_above_tip = false;
+        let mut birthdays = Vec::new();
+        for import in self.imported.iter_mut().filter(|import| !import.rescanned) {
+            if import.birthday > tip_height {
+                import.rescanned = true;
+                born_above_tip = true;
+            } else {
+                birthdays.push(import.birthday);
+            }
+        }
+        for birthday in birthdays {
+            println!(
+                "Scheduling a rescan from height {} of wallet '{}' for an imported descriptor.",
+                birthday, self.name
+            );
+            self.schedule_rescan(birthday, tip_height);
+        }
+        if born_above_tip {
+            descriptors::write_imported(&self.dir, &self.imported, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Record that the pending rescan starting at this height, as returned by
+    /// [`Self::take_pending_rescan`], was completed. The imported descriptors it was scheduled for
+    /// are not rescanned again. As they're only scheduled when the wallet is opened, any pending
+    /// rescan starting at or below their birthday covers them.
+    pub fn rescan_completed(&mut self, from_height: u32) -> Result<(), Box<dyn error::Error>> {
+        let mut completed = false;
+        for import in self.imported.iter_mut() {
+            if !import.rescanned && import.birthday >= from_height {
+                import.rescanned = true;
+                completed = true;
+            }
+        }
+        if completed {
+            descriptors::write_imported(&self.dir, &self.imported, self.key.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// The name of this wallet. Empty for the default wallet.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn genesis_hash(&self) -> bitcoin::BlockHash {
+        self.chain.genesis_hash()
+    }
+
+    pub fn tip(&self) -> BlockId {
+        self.chain.tip().block_id()
+    }
+
+    /// The hash of the block at this height in the wallet's local chain, if any.
+    pub fn block_hash(&self, height: u32) -> Option<bitcoin::BlockHash> {
+        self.chain.get(height).map(|cp| cp.hash())
+    }
+
+    pub fn list_unspent(&self) -> Vec<bitcoin::OutPoint> {
+        self.tx_graph
+            .index
+            .outpoints()
+            .iter()
+            .map(|(_, op)| *op)
+            .collect()
+    }
+
+    /// Apply the effects of a block on the wallet. The changes are persisted with the current
+    /// batch.
+    pub fn apply_block(
+        &mut self,
+        block: &bitcoin::Block,
+        height: i32,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let h: u32 = height.try_into().expect("Must never be negative");
+        let revealed_before = self.tx_graph.index.last_revealed_indices();
+        let graph_cs = self.tx_graph.apply_block_relevant(block, h);
+        self.check_gap_limit(&revealed_before, h);
+        let chain_cs = self
+            .chain
+            .apply_update(CheckPoint::from_header(&block.header, h))?;
+        self.store.stage_block(ChangeSet { graph_cs, chain_cs })?;
+        self.release_locks(&block.txdata)?;
+        Ok(())
+    }
+
+    /// Re-apply the transactions of a block which is already part of the wallet's local chain,
+    /// for instance when rescanning. Contrary to [`Self::apply_block`] this never updates the
+    /// local chain, so it can safely be interleaved with blocks being connected or disconnected
+    /// by notifications. Returns `false` if the block is not (anymore) part of the local chain,
+    /// in which case nothing is applied.
+    pub fn apply_rescanned_block(
+        &mut self,
+        block: &bitcoin::Block,
+        height: u32,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        match self.chain.get(height) {
+            Some(cp) if cp.hash() == block.block_hash() => {}
+            _ => return Ok(false),
+        }
+        let revealed_before = self.tx_graph.index.last_revealed_indices();
+        let graph_cs = self.tx_graph.apply_block_relevant(block, height);
+        self.check_gap_limit(&revealed_before, height);
+        if !graph_cs.is_empty() {
+            self.store.stage_block(ChangeSet {
+                graph_cs,
+                ..Default::default()
+            })?;
+            println!("Rescan found new wallet transactions in block at height {}.", height);
+        }
+        Ok(true)
+    }
+
+    /// Apply the effects of a transaction on the wallet. The changes are persisted with the
+    /// current batch.
+    pub fn apply_tx(&mut self, tx: bitcoin::Transaction) -> Result<(), Box<dyn error::Error>> {
+        let revealed_before = self.tx_graph.index.last_revealed_indices();
+        let graph_cs = self
+            .tx_graph
+            .batch_insert_relevant_unconfirmed([(tx, /*TODO*/ 0)]);
+        self.check_gap_limit(&revealed_before, self.tip().height);
+        self.store.stage(ChangeSet {
+            graph_cs,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// Release the locks on the coins spent by this transaction if it was evicted from the
+    /// mempool, as they don't need to be reserved anymore. A replaced transaction's coins are
+    /// still being spent by its replacement, and those of a confirmed one are released along with
+    /// its block.
+    pub fn remove_mempool_tx(
+        &mut self,
+        tx: &bitcoin::Transaction,
+        reason: MempoolRemovalReason,
+    ) -> Result<(), Box<dyn error::Error>> {
+        match reason {
+            MempoolRemovalReason::Replaced | MempoolRemovalReason::Block => Ok(()),
+            MempoolRemovalReason::Expiry
+            | MempoolRemovalReason::SizeLimit
+            | MempoolRemovalReason::Reorg
+            | MempoolRemovalReason::Conflict => self.release_locks(std::slice::from_ref(tx)),
+        }
+    }
+
+    /// Mark a block as disconnected, along with any block above it. The changes are persisted
+    /// with the current batch. The block must be the one we have at this height, otherwise
+    /// nothing is disconnected and the mismatch is logged.
+    pub fn disconnect(&mut self, block_id: BlockId) -> Result<(), Box<dyn error::Error>> {
+        match self.chain.get(block_id.height) {
+            Some(cp) if cp.hash() == block_id.hash => {}
+            Some(cp) => {
+                eprintln!(
+                    "Wallet '{}' not disconnecting block {} at height {}: it has block {} at this height.",
+                    self.name,
+                    block_id.hash,
+                    block_id.height,
+                    cp.hash()
+                );
+                return Ok(());
+            }
+            None => {
+                eprintln!(
+                    "Wallet '{}' not disconnecting block {} at height {}: it has no block at this height.",
+                    self.name, block_id.hash, block_id.height
+                );
+                return Ok(());
+            }
+        }
+        let chain_cs = self
+            .chain
+            .disconnect_from(block_id)
+            .map_err(|_| "Can't disconnect the genesis block.")?;
+        self.store.stage(ChangeSet {
+            chain_cs,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// Disconnect all the blocks above this fork point, which becomes the tip.
+    pub fn disconnect_above(&mut self, fork_point: BlockId) -> Result<(), Box<dyn error::Error>> {
+        if self.block_hash(fork_point.height) != Some(fork_point.hash) {
+            return Err(format!(
+                "Fork point {:?} is not part of the wallet's chain.",
+                fork_point
+            )
+            .into());
+        }
+        let first_above = self
+            .chain
+            .iter_checkpoints()
+            .take_while(|cp| cp.height() > fork_point.height)
+            .last();
+        match first_above {
+            Some(cp) => self.disconnect(cp.block_id()),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the changes of the current batch to disk.
+    pub fn persist(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.store.write()
+    }
+
+    /// Look up a transaction in the wallet's store, which must support queries. The current
+    /// batch is written first.
+    pub fn stored_transaction(
+        &mut self,
+        txid: &bitcoin::Txid,
+    ) -> Result<Option<StoredTx>, Box<dyn error::Error>> {
+        self.persist()?;
+        self.store_queries()?.transaction(txid)
+    }
+
+    /// The transactions of the wallet's store confirmed at this height, which must support
+    /// queries. The current batch is written first.
+    pub fn stored_transactions_at_height(
+        &mut self,
+        height: u32,
+    ) -> Result<Vec<bitcoin::Txid>, Box<dyn error::Error>> {
+        self.persist()?;
+        self.store_queries()?.transactions_at_height(height)
+    }
+
+    fn store_queries(&self) -> Result<&dyn Queries, Box<dyn error::Error>> {
+        self.store
+            .queries()
+            .ok_or_else(|| "Querying the store requires the SQLite backend.".into())
+    }
+
+    /// The first address to have never been revealed by this wallet.
+    pub fn next_address(&mut self) -> Result<bitcoin::Address, Box<dyn error::Error>> {
+        let ((_, script), cs) = self
+            .tx_graph
+            .index
+            .reveal_next_spk(PRIMARY_KEYCHAIN)
+            .expect("We assume a ranged descriptor is in use");
+        let graph_cs = bdk_chain::indexed_tx_graph::ChangeSet {
+            indexer: cs,
+            ..Default::default()
+        };
+        self.store.commit(ChangeSet {
+            graph_cs,
+            ..Default::default()
+        })?;
+        Ok(bitcoin::Address::from_script(&script, NETWORK)
+            .expect("We assume the descriptor type used has defined addresses"))
+    }
+
+    /// The wallet's balance. As in Core, an unconfirmed transaction is only trusted if it only
+    /// spends the wallet's own outputs, from confirmed or trusted transactions. Payments from
+    /// third parties are untrusted until they confirm. Coinbase outputs are immature until they
+    /// have 100 confirmations.
+    pub fn balance(&self) -> Balance {
+        // Index the outpoints by themselves, so the trust predicate knows their transaction.
+        let outpoints = self
+            .tx_graph
+            .index
+            .outpoints()
+            .iter()
+            .map(|(_, op)| (*op, *op));
+        let mut trusted = BTreeSet::new();
+        self.tx_graph
+            .graph()
+            .balance(&self.chain, self.tip(), outpoints, |op, _| {
+                self.is_trusted(op.txid, &mut trusted)
+            })
+    }
+
+    // Whether this transaction is confirmed, or unconfirmed but only spending our own outputs from
+    // trusted transactions. Those found trusted are cached in `trusted`.
+    fn is_trusted(&self, txid: bitcoin::Txid, trusted: &mut BTreeSet<bitcoin::Txid>) -> bool {
+        if trusted.contains(&txid) {
+            return true;
+        }
+        let graph = self.tx_graph.graph();
+        let is_trusted = match graph.get_chain_position(&self.chain, self.tip(), txid) {
+            Some(ChainPosition::Confirmed(_)) => true,
+            Some(ChainPosition::Unconfirmed(_)) => graph.get_tx(txid).is_some_and(|tx| {
+                tx.input.iter().all(|txin| {
+                    let prevout = txin.previous_output;
+                    self.tx_graph.index.txout(prevout).is_some()
+                        && self.is_trusted(prevout.txid, trusted)
+                })
+            }),
+            None => false,
+        };
+        if is_trusted {
+            trusted.insert(txid);
+        }
+        is_trusted
+    }
+
+    /// The wallet's unspent outputs, confirmed or not.
+    pub fn utxos(&self) -> Vec<FullTxOut<ConfirmationBlockTime>> {
+        let outpoints = self.tx_graph.index.outpoints().iter().cloned();
+        self.tx_graph
+            .graph()
+            .filter_chain_unspents(&self.chain, self.tip(), outpoints)
+            .map(|(_, utxo)| utxo)
+            .collect()
+    }
+
+    /// The coins locked at this time, along with their lock.
+    pub fn locked_utxos(&self, time: u64) -> Vec<(bitcoin::OutPoint, UtxoLock)> {
+        self.locks
+            .iter()
+            .filter(|(_, lock)| lock.is_active(time))
+            .map(|(outpoint, lock)| (*outpoint, lock.clone()))
+            .collect()
+    }
+
+    /// Lock these unspent coins of the wallet, so they aren't selected to fund new transactions
+    /// until they are unlocked or the lock expires. The locks of coins which were already locked
+    /// are replaced. Persist them to disk.
+    pub fn lock_utxos(
+        &mut self,
+        outpoints: &[bitcoin::OutPoint],
+        lock: UtxoLock,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let utxos = self.utxos();
+        if let Some(unknown) = outpoints
+            .iter()
+            .find(|op| !utxos.iter().any(|u| u.outpoint == **op))
+        {
+            return Err(format!("{} is not an unspent coin of the wallet.", unknown).into());
+        }
+        let cs = LocksChangeSet {
+            locks: outpoints
+                .iter()
+                .map(|op| (*op, Some(lock.clone())))
+                .collect(),
+        };
+        self.locks_store.append(&cs)?;
+        self.locks
+            .extend(outpoints.iter().map(|op| (*op, lock.clone())));
+        Ok(())
+    }
+
+    /// Unlock these coins, or all of them if `None`. Persist it to disk. Returns the coins which
+    /// were locked, even if their lock had expired.
+    pub fn unlock_utxos(
+        &mut self,
+        outpoints: Option<&[bitcoin::OutPoint]>,
+    ) -> Result<Vec<bitcoin::OutPoint>, Box<dyn error::Error>> {
+        let unlocked: Vec<_> = match outpoints {
+            Some(outpoints) => outpoints
+                .iter()
+                .filter(|op| self.locks.contains_key(op))
+                .copied()
+                .collect(),
+            None => self.locks.keys().copied().collect(),
+        };
+        if !unlocked.is_empty() {
+            let cs = LocksChangeSet {
+                locks: unlocked.iter().map(|op| (*op, None)).collect(),
+            };
+            self.locks_store.append(&cs)?;
+            for op in &unlocked {
+                self.locks.remove(op);
+            }
+        }
+        Ok(unlocked)
+    }
+
+    // Release the locks on the coins spent by these transactions, which no longer need to be
+    // reserved once their spend confirmed or was evicted.
+    fn release_locks(&mut self, txs: &[bitcoin::Transaction]) -> Result<(), Box<dyn error::Error>> {
+        if self.locks.is_empty() {
+            return Ok(());
+        }
+        let spent: Vec<_> = txs
+            .iter()
+            .flat_map(|tx| &tx.input)
+            .map(|txin| txin.previous_output)
+            .collect();
+        for outpoint in self.unlock_utxos(Some(&spent))? {
+            println!(
+                "Released the lock on coin {} of wallet '{}'.",
+                outpoint, self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// The spending paths of the descriptor of this keychain.
+    pub fn spending_paths(
+        &self,
+        keychain: u32,
+    ) -> Result<Vec<SpendingPath<DescriptorPublicKey>>, Box<dyn error::Error>> {
+        let desc = self
+            .tx_graph
+            .index
+            .get_descriptor(keychain)
+            .ok_or_else(|| format!("Unknown keychain {}.", keychain))?;
+        policy::spending_paths(desc)
+    }
+
+    /// The wallet's unspent coins along with their spending paths, and whether the time locks of
+    /// these are met at this time.
+    pub fn utxo_spending_paths(&self, time: u64) -> Result<Vec<CoinPaths>, Box<dyn error::Error>> {
+        let tip_height = self.tip().height;
+        let keychain_paths = self
+            .keychains()
+            .into_iter()
+            .map(|keychain| Ok((keychain, self.spending_paths(keychain)?)))
+            .collect::<Result<BTreeMap<_, _>, Box<dyn error::Error>>>()?;
+        let mut coins = Vec::new();
+        for utxo in self.utxos() {
+            let ((keychain, index), _) = self
+                .tx_graph
+                .index
+                .txout(utxo.outpoint)
+                .expect("The wallet's coins are indexed");
+            let paths = keychain_paths[&keychain]
+                .iter()
+                .map(|path| {
+                    let unlocked = path.is_unlocked(tip_height, time, confirmation(&utxo));
+                    Ok((path.at_derivation_index(index)?, unlocked))
+                })
+                .collect::<Result<_, Box<dyn error::Error>>>()?;
+            coins.push(CoinPaths {
+                utxo,
+                keychain,
+                paths,
+            });
+        }
+        Ok(coins)
+    }
+
+    /// The transactions involving the wallet which are in the best chain or in the mempool,
+    /// along with the amounts they send from and to the wallet and the fee they pay.
+    pub fn transactions(&self) -> Vec<WalletTx> {
+        let tip_height = self.tip().height;
+        let graph = self.tx_graph.graph();
+        graph
+            .list_canonical_txs(&self.chain, self.tip())
+            .map(|ctx| {
+                let tx = &ctx.tx_node.tx;
+                let (sent, received) = self.tx_graph.index.sent_and_received(tx, ..);
+                // A coinbase transaction pays no fee, it collects them.
+                let fee = (!tx.is_coinbase())
+                    .then(|| graph.calculate_fee(tx).ok())
+                    .flatten();
+                let confirmation = match ctx.chain_position {
+                    ChainPosition::Confirmed(anchor) => Some(*anchor),
+                    ChainPosition::Unconfirmed(_) => None,
+                };
+                WalletTx {
+                    txid: ctx.tx_node.txid,
+                    sent,
+                    received,
+                    fee,
+                    feerate: fee.map(|fee| fee / tx.weight()),
+                    rbf: tx.is_explicitly_rbf(),
+                    confirmation,
+                    confirmations: confirmation
+                        .map_or(0, |c| (tip_height + 1).saturating_sub(c.block_id.height)),
+                }
+            })
+            .collect()
+    }
+
+    /// The transactions matching this filter, oldest first and unconfirmed ones last.
+    pub fn list_transactions(&self, filter: &TxFilter) -> Vec<WalletTx> {
+        let mut txs: Vec<_> = self
+            .transactions()
+            .into_iter()
+            .filter(|tx| filter.matches(tx))
+            .collect();
+        txs.sort_by_key(|tx| tx.confirmation.map_or(u32::MAX, |c| c.block_id.height));
+        txs.truncate(txs.len().saturating_sub(filter.skip));
+        let start = filter
+            .count
+            .map_or(0, |count| txs.len().saturating_sub(count));
+        txs.split_off(start)
+    }
+
+    /// The addresses revealed so far from the primary descriptor, along with their derivation
+    /// index.
+    pub fn revealed_addresses(&self) -> Vec<(u32, bitcoin::Address)> {
+        self.tx_graph
+            .index
+            .revealed_keychain_spks(PRIMARY_KEYCHAIN)
+            .filter_map(|(i, spk)| Some((i, self.address(&spk)?)))
+            .collect()
+    }
+
+    /// Create a PSBT paying these amounts, funded with the wallet's coins at this feerate. Coins
+    /// are selected largest first, leaving out immature coinbase outputs, and the change (if not
+    /// dust) is sent to a newly revealed address. The inputs and change output are populated with
+    /// the information needed to sign them.
+    ///
+    /// If a spending path is chosen, only the coins of its keychain whose time locks it meets are
+    /// selected, and the lock time and sequences of the transaction are set for its time locks.
+    ///
+    /// Locked and excluded coins are left out, and the included ones are spent first whatever
+    /// their lock. If asked to, the coins spent are locked before the PSBT is returned.
+    pub fn create_psbt(
+        &mut self,
+        recipients: Vec<(bitcoin::ScriptBuf, bitcoin::Amount)>,
+        feerate: bitcoin::FeeRate,
+        path: Option<PathChoice>,
+        coins: &CoinControl,
+    ) -> Result<bitcoin::Psbt, CreatePsbtError> {
+        let invalid = |e: String| CreatePsbtError::InvalidParameter(e);
+        if recipients.is_empty() {
+            return Err(invalid("No recipient.".to_string()));
+        }
+        let spending_path = match path {
+            Some(choice) => Some(
+                self.spending_paths(choice.keychain)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .get(choice.index)
+                    .cloned()
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "Unknown spending path {} of keychain {}.",
+                            choice.index, choice.keychain
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+        // Time based absolute locks must be met by the lock time itself, otherwise the current
+        // height meets any height based lock of the path.
+        let lock_time = match spending_path.as_ref().and_then(|p| p.after) {
+            Some(lock @ bitcoin::absolute::LockTime::Seconds(_)) => lock,
+            _ => bitcoin::absolute::LockTime::from_height(self.tip().height)
+                .map_err(CreatePsbtError::wallet)?,
+        };
+        let sequence = spending_path
+            .as_ref()
+            .and_then(|p| p.older)
+            .map_or(bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME, |lock| {
+                lock.to_sequence()
+            });
+        let amount_overflow = || invalid("The amounts exceed the total supply.".to_string());
+        let target = recipients
+            .iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, (_, amount)| {
+                sum.checked_add(*amount)
+            })
+            .filter(|target| *target <= bitcoin::Amount::MAX_MONEY)
+            .ok_or_else(amount_overflow)?;
+        let mut tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time,
+            input: Vec::new(),
+            output: recipients
+                .into_iter()
+                .map(|(script_pubkey, value)| bitcoin::TxOut {
+                    value,
+                    script_pubkey,
+                })
+                .collect(),
+        };
+        // Account for the segwit marker and flag.
+        let mut weight = tx.weight() + bitcoin::Weight::from_wu(2);
+        let fee_overflow = || invalid("Fee overflow.".to_string());
+        let mut fee = feerate.fee_wu(weight).ok_or_else(fee_overflow)?;
+
+        // Coinbase outputs can't be spent until they have 100 confirmations.
+        let tip_height = self.tip().height;
+        let mut utxos = self.utxos();
+        for outpoint in &coins.include {
+            if coins.exclude.contains(outpoint) {
+                return Err(invalid(format!(
+                    "Coin {} is both included and excluded.",
+                    outpoint
+                )));
+            }
+            let utxo = utxos
+                .iter()
+                .find(|u| u.outpoint == *outpoint)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "{} is not an unspent coin of the wallet.",
+                        outpoint
+                    ))
+                })?;
+            if !utxo.is_mature(tip_height) {
+                return Err(invalid(format!(
+                    "Coin {} is an immature coinbase output.",
+                    outpoint
+                )));
+            }
+        }
+        utxos.retain(|utxo| utxo.is_mature(tip_height));
+        if let (Some(choice), Some(spending_path)) = (path, &spending_path) {
+            utxos.retain(|utxo| {
+                let keychain = self
+                    .tx_graph
+                    .index
+                    .txout(utxo.outpoint)
+                    .map(|((k, _), _)| k);
+                keychain == Some(choice.keychain)
+                    && spending_path.is_unlocked(tip_height, choice.time, confirmation(utxo))
+            });
+            if utxos.is_empty() {
+                return Err(invalid(format!(
+                    "No coin can be spent through spending path {} of keychain {} yet.",
+                    choice.index, choice.keychain
+                )));
+            }
+            if let Some(outpoint) = coins
+                .include
+                .iter()
+                .find(|op| !utxos.iter().any(|u| u.outpoint == **op))
+            {
+                return Err(invalid(format!(
+                    "Coin {} can't be spent through spending path {} of keychain {} yet.",
+                    outpoint, choice.index, choice.keychain
+                )));
+            }
+        }
+        utxos.retain(|utxo| {
+            coins.include.contains(&utxo.outpoint)
+                || !coins.exclude.contains(&utxo.outpoint)
+                    && self
+                        .locks
+                        .get(&utxo.outpoint)
+                        .is_none_or(|lock| !lock.is_active(coins.time))
+        });
+        // The included coins first, then the largest ones.
+        utxos.sort_by_key(|utxo| {
+            (
+                !coins.include.contains(&utxo.outpoint),
+                std::cmp::Reverse(utxo.txout.value),
+            )
+        });
+        let mut selected = Vec::new();
+        let mut selected_value = bitcoin::Amount::ZERO;
+        for utxo in utxos {
+            let needed = target.checked_add(fee).ok_or_else(amount_overflow)?;
+            if selected_value >= needed && !coins.include.contains(&utxo.outpoint) {
+                break;
+            }
+            let desc = self
+                .derived_descriptor(&utxo.outpoint)
+                .map_err(CreatePsbtError::Wallet)?;
+            let satisfaction_weight = desc
+                .max_weight_to_satisfy()
+                .map_err(CreatePsbtError::wallet)?;
+            weight += bitcoin::Weight::from_non_witness_data_size(41) + satisfaction_weight;
+            fee = feerate.fee_wu(weight).ok_or_else(fee_overflow)?;
+            tx.input.push(bitcoin::TxIn {
+                previous_output: utxo.outpoint,
+                sequence,
+                ..Default::default()
+            });
+            selected_value += utxo.txout.value;
+            selected.push((utxo.txout, desc));
+        }
+        let needed = target.checked_add(fee).ok_or_else(amount_overflow)?;
+        if selected_value < needed {
+            return Err(CreatePsbtError::InsufficientFunds {
+                available: selected_value,
+                needed,
+            });
+        }
+
+        // Only add a change output if it's worth its cost. There is no internal keychain, the
+        // change is sent to the next index of the wallet's descriptor. It's only revealed if the
+        // change is kept, and never handed out to a payer.
+        let (change_index, _) = self
+            .tx_graph
+            .index
+            .next_index(PRIMARY_KEYCHAIN)
+            .expect("The wallet's keychain is always inserted.");
+        let change_desc = self
+            .tx_graph
+            .index
+            .get_descriptor(PRIMARY_KEYCHAIN)
+            .expect("The wallet's keychain is always inserted.")
+            .at_derivation_index(change_index)
+            .map_err(CreatePsbtError::wallet)?;
+        let change_txout = bitcoin::TxOut {
+            value: bitcoin::Amount::ZERO,
+            script_pubkey: change_desc.script_pubkey(),
+        };
+        let change_fee = feerate
+            .fee_wu(bitcoin::Weight::from_non_witness_data_size(change_txout.size() as u64))
+            .ok_or_else(fee_overflow)?;
+        let change_value = (selected_value - needed).checked_sub(change_fee);
+        let has_change = match change_value {
+            Some(value) if value >= change_txout.script_pubkey.minimal_non_dust() => {
+                tx.output.push(bitcoin::TxOut {
+                    value,
+                    ..change_txout
+                });
+                true
+            }
+            _ => false,
+        };
+
+        let mut psbt = bitcoin::Psbt::from_unsigned_tx(tx).map_err(CreatePsbtError::wallet)?;
+        for (i, (txout, desc)) in selected.into_iter().enumerate() {
+            let txid = psbt.unsigned_tx.input[i].previous_output.txid;
+            psbt.inputs[i].witness_utxo = Some(txout);
+            if !matches!(desc, Descriptor::Tr(_)) {
+                psbt.inputs[i].non_witness_utxo =
+                    self.tx_graph.graph().get_tx(txid).map(|tx| (*tx).clone());
+            }
+            psbt.update_input_with_descriptor(i, &desc)
+                .map_err(CreatePsbtError::wallet)?;
+        }
+        if has_change {
+            let last = psbt.outputs.len() - 1;
+            psbt.update_output_with_descriptor(last, &change_desc)
+                .map_err(CreatePsbtError::wallet)?;
+            // Until the transaction is seen, the change address must not be the next unused one.
+            let (_, indexer) = self
+                .tx_graph
+                .index
+                .reveal_to_target(PRIMARY_KEYCHAIN, change_index)
+                .expect("The wallet's keychain is always inserted.");
+            self.tx_graph
+                .index
+                .mark_used(PRIMARY_KEYCHAIN, change_index);
+            self.store
+                .commit(ChangeSet {
+                    graph_cs: bdk_chain::indexed_tx_graph::ChangeSet {
+                        indexer,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .map_err(CreatePsbtError::Wallet)?;
+        }
+        if let Some(lock) = &coins.lock {
+            let spent: Vec<_> = psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect();
+            self.lock_utxos(&spent, lock.clone())
+                .map_err(CreatePsbtError::Wallet)?;
+        }
+        Ok(psbt)
+    }
+
+    /// The wallet descriptor derived at the index of the script pubkey of this coin.
+    fn derived_descriptor(
+        &self,
+        outpoint: &bitcoin::OutPoint,
+    ) -> Result<Descriptor<DefiniteDescriptorKey>, Box<dyn error::Error>> {
+        let ((keychain, index), _) = self
+            .tx_graph
+            .index
+            .txout(*outpoint)
+            .ok_or_else(|| format!("Unknown coin {}.", outpoint))?;
+        let desc = self
+            .tx_graph
+            .index
+            .get_descriptor(keychain)
+            .expect("Indexed coins are always from a known keychain");
+        Ok(desc.at_derivation_index(index)?)
+    }
+
+    /// The address corresponding to this script pubkey on our network, if any.
+    pub fn address(&self, script: &bitcoin::Script) -> Option<bitcoin::Address> {
+        bitcoin::Address::from_script(script, NETWORK).ok()
+    }
+
+    /// Print the wallet state (addresses, coins, transactions, balance, ..). The addresses are
+    /// only peeked at, nothing is revealed or persisted.
+    pub fn print_info(&self) -> Result<(), Box<dyn error::Error>> {
+        let index = &self.tx_graph.index;
+        let (next_index, _) = index
+            .next_index(PRIMARY_KEYCHAIN)
+            .expect("The wallet's keychain is always inserted.");
+        let next_spk = index
+            .get_descriptor(PRIMARY_KEYCHAIN)
+            .expect("The wallet's keychain is always inserted.")
+            .at_derivation_index(next_index)?
+            .script_pubkey();
+        let next_unused_spk = index
+            .unused_keychain_spks(PRIMARY_KEYCHAIN)
+            .next()
+            .map_or(next_spk.clone(), |(_, spk)| spk);
+        let next_unused_addr = bitcoin::Address::from_script(&next_unused_spk, NETWORK)?;
+        let next_addr = bitcoin::Address::from_script(&next_spk, NETWORK)?;
+        let outpoints = self
+            .tx_graph
+            .index
+            .outpoints()
+            .iter()
+            .map(|((keychain, _), op)| (*keychain, *op));
+
+        let graph = self.tx_graph.graph();
+        let balance = graph.balance(&self.chain, self.tip(), outpoints.clone(), |_, _| true);
+        let utxos = graph.filter_chain_unspents(&self.chain, self.tip(), outpoints);
+        let txs = self.list_transactions(&TxFilter::default());
+
+        if self.name.is_empty() {
+            println!("Wallet info:");
+        } else {
+            println!("Wallet '{}' info:", self.name);
+        }
+        println!("      Next unused address: {}.", next_unused_addr);
+        println!("      Next unrevealed address: {}.", next_addr);
+        println!(
+            "      Balance (confirmed + unconfirmed): {}.",
+            balance.trusted_spendable()
+        );
+        println!(
+            "      Confirmed: {}, trusted pending: {}, untrusted pending: {}, immature: {}.",
+            balance.confirmed, balance.trusted_pending, balance.untrusted_pending, balance.immature
+        );
+        print!("      Utxos: ");
+        for (_, utxo) in utxos {
+            print!("{} ({}), ", utxo.outpoint, utxo.txout.value);
+        }
+        print!("\n      Transactions: ");
+        for tx in txs {
+            print!(
+                "{} ({}, {} confirmations), ",
+                tx.txid,
+                tx.net(),
+                tx.confirmations
+            );
+        }
+        println!();
+
+        Ok(())
+    }
+}
+
+impl Drop for BdkWallet {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.write() {
+            eprintln!("Error persisting wallet '{}' on shutdown: '{}'", self.name, e);
+        }
+    }
+}
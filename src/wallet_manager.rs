@@ -0,0 +1,268 @@
+//! Serve multiple wallets from a single subscription to Bitcoin Core's notifications.
+
+use bdk_chain::{bitcoin, BlockId};
+use tokio::sync::Notify;
+
+use std::{
+    collections::BTreeMap,
+    error,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    backup::{self, WalletBackup},
+    persist::StoreConfig,
+    store_crypto::StoreKey,
+    wallet::{BdkWallet, MempoolRemovalReason},
+};
+
+/// The set of loaded wallets. Blocks and transactions notified by Core are decoded once and
+/// applied to every loaded wallet.
+pub struct WalletManager {
+    wallets: BTreeMap<String, BdkWallet>,
+    // The node's tip as of the last block (dis)connection we processed. Wallets are only loaded
+    // once synced up to this tip, so they never miss a notification.
+    tip: BlockId,
+    // The node's tip as of its last updatedBlockTip notification. May be ahead of `tip` while the
+    // notifications for the blocks in between are still queued.
+    node_tip: BlockId,
+    // Whether the node was still in initial block download as of its last tip update.
+    initial_block_download: bool,
+    // Shared by all the wallets we open, notified whenever one of them schedules a rescan.
+    rescan_notifier: Arc<Notify>,
+    // Where the wallets are persisted, and how.
+    data_dir: PathBuf,
+    store: StoreConfig,
+}
+
+impl WalletManager {
+    /// Create a manager with no loaded wallet, starting from this node tip. Wallets are created
+    /// in and opened from this data directory, and persisted as configured.
+    pub fn new(tip: BlockId, data_dir: PathBuf, store: StoreConfig) -> Self {
+        Self {
+            wallets: BTreeMap::new(),
+            tip,
+            node_tip: tip,
+            initial_block_download: false,
+            rescan_notifier: Arc::new(Notify::new()),
+            data_dir,
+            store,
+        }
+    }
+
+    /// The directory the wallets are persisted in.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// The node's tip, as far as the loaded wallets are concerned.
+    pub fn tip(&self) -> BlockId {
+        self.tip
+    }
+
+    /// The node's tip as of its last tip update, whether or not the loaded wallets processed the
+    /// blocks up to it yet.
+    pub fn node_tip(&self) -> BlockId {
+        self.node_tip
+    }
+
+    /// Whether the node is still syncing the chain, as of its last tip update.
+    pub fn is_initial_block_download(&self) -> bool {
+        self.initial_block_download
+    }
+
+    /// Record the node's new tip, upon an updatedBlockTip notification.
+    pub fn set_node_tip(&mut self, node_tip: BlockId, initial_block_download: bool) {
+        self.node_tip = node_tip;
+        self.initial_block_download = initial_block_download;
+    }
+
+    /// Set the node's tip once all the loaded wallets were synced to it outside of notifications.
+    pub fn set_tip(&mut self, tip: BlockId) {
+        self.tip = tip;
+    }
+
+    /// Notified whenever a loaded wallet needs a rescan.
+    pub fn rescan_notifier(&self) -> Arc<Notify> {
+        self.rescan_notifier.clone()
+    }
+
+    /// Create a new named wallet tracking this descriptor. It isn't loaded until passed to
+    /// [`Self::insert`], which requires it to be synced to [`Self::tip`] first.
+    pub fn create_wallet(
+        &self,
+        name: &str,
+        descriptor: &str,
+    ) -> Result<BdkWallet, Box<dyn error::Error>> {
+        BdkWallet::create(
+            &self.data_dir,
+            name,
+            descriptor,
+            &self.store,
+            self.rescan_notifier.clone(),
+        )
+    }
+
+    /// Open an existing wallet from its store. It isn't loaded until passed to [`Self::insert`],
+    /// which requires it to be synced to [`Self::tip`] first.
+    pub fn open_wallet(&self, name: &str) -> Result<BdkWallet, Box<dyn error::Error>> {
+        if self.wallets.contains_key(name) {
+            return Err(format!("Wallet '{}' is already loaded.", name).into());
+        }
+        BdkWallet::load(
+            &self.data_dir,
+            name,
+            &self.store,
+            self.rescan_notifier.clone(),
+        )
+    }
+
+    /// Restore a wallet from this snapshot, under this name. It isn't loaded until passed to
+    /// [`Self::insert`], which requires it to be synced to [`Self::tip`] first.
+    pub fn restore_wallet(
+        &self,
+        name: &str,
+        snapshot: &WalletBackup,
+    ) -> Result<BdkWallet, Box<dyn error::Error>> {
+        if self.wallets.contains_key(name) {
+            return Err(format!("Wallet '{}' is already loaded.", name).into());
+        }
+        BdkWallet::restore(
+            &self.data_dir,
+            name,
+            snapshot,
+            &self.store,
+            self.rescan_notifier.clone(),
+        )
+    }
+
+    /// Write a backup of this loaded wallet to this path, encrypted with the stores' key if there
+    /// is one. As notifications are processed under the same lock as the manager, the backup is
+    /// always of the wallet as of [`Self::tip`].
+    pub fn backup_wallet(&self, name: &str, path: &Path) -> Result<(), Box<dyn error::Error>> {
+        let wallet = self
+            .wallets
+            .get(name)
+            .ok_or_else(|| format!("Wallet '{}' is not loaded.", name))?;
+        backup::write_backup(path, &wallet.backup(), self.store_key())
+    }
+
+    /// The key the stores, and backups, are encrypted with.
+    pub fn store_key(&self) -> Option<&StoreKey> {
+        self.store.key.as_ref()
+    }
+
+    /// Start serving notifications to a wallet synced to [`Self::tip`].
+    pub fn insert(&mut self, wallet: BdkWallet) -> Result<(), Box<dyn error::Error>> {
+        if wallet.tip() != self.tip {
+            return Err(format!(
+                "Wallet '{}' is not synced to the node tip {:?}.",
+                wallet.name(),
+                self.tip
+            )
+            .into());
+        }
+        if self.wallets.contains_key(wallet.name()) {
+            return Err(format!("Wallet '{}' is already loaded.", wallet.name()).into());
+        }
+        self.wallets.insert(wallet.name().to_string(), wallet);
+        Ok(())
+    }
+
+    /// Stop serving notifications to this wallet. Its state is already persisted.
+    pub fn unload(&mut self, name: &str) -> Option<BdkWallet> {
+        self.wallets.remove(name)
+    }
+
+    /// The names of the loaded wallets.
+    pub fn names(&self) -> Vec<String> {
+        self.wallets.keys().cloned().collect()
+    }
+
+    pub fn wallet(&self, name: &str) -> Option<&BdkWallet> {
+        self.wallets.get(name)
+    }
+
+    pub fn wallet_mut(&mut self, name: &str) -> Option<&mut BdkWallet> {
+        self.wallets.get_mut(name)
+    }
+
+    /// Apply a connected block to all loaded wallets. Errors are logged per wallet so one
+    /// failing wallet doesn't prevent the others from being updated.
+    pub fn apply_block(&mut self, block: &bitcoin::Block, height: i32) {
+        for wallet in self.wallets.values_mut() {
+            if let Err(e) = wallet.apply_block(block, height) {
+                eprintln!(
+                    "Error when applying connected block {} to wallet '{}': '{}'",
+                    block.block_hash(),
+                    wallet.name(),
+                    e
+                );
+            }
+        }
+        self.tip = BlockId {
+            height: height.try_into().expect("Must never be negative"),
+            hash: block.block_hash(),
+        };
+    }
+
+    /// Apply a mempool transaction to all loaded wallets.
+    pub fn apply_tx(&mut self, tx: &bitcoin::Transaction) {
+        for wallet in self.wallets.values_mut() {
+            if let Err(e) = wallet.apply_tx(tx.clone()) {
+                eprintln!(
+                    "Error applying tx {} to wallet '{}': {}",
+                    tx.compute_txid(),
+                    wallet.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Apply the removal of a transaction from the mempool to all loaded wallets.
+    pub fn remove_mempool_tx(&mut self, tx: &bitcoin::Transaction, reason: MempoolRemovalReason) {
+        for wallet in self.wallets.values_mut() {
+            if let Err(e) = wallet.remove_mempool_tx(tx, reason) {
+                eprintln!(
+                    "Error removing mempool tx {} from wallet '{}': {}",
+                    tx.compute_txid(),
+                    wallet.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Write the current batch of changes of all loaded wallets to disk. Errors are logged per
+    /// wallet, the changes of a failing wallet stay staged for the next attempt.
+    pub fn persist(&mut self) {
+        for wallet in self.wallets.values_mut() {
+            if let Err(e) = wallet.persist() {
+                eprintln!("Error persisting wallet '{}': '{}'", wallet.name(), e);
+            }
+        }
+    }
+
+    /// Disconnect a block from all loaded wallets. `prev_hash` is the hash of its parent, the
+    /// new node tip.
+    pub fn disconnect(&mut self, block_id: BlockId, prev_hash: bitcoin::BlockHash) {
+        for wallet in self.wallets.values_mut() {
+            if let Err(e) = wallet.disconnect(block_id) {
+                eprintln!(
+                    "Error when disconnecting block {} from wallet '{}': '{}'",
+                    block_id.hash,
+                    wallet.name(),
+                    e
+                );
+            }
+        }
+        if let Some(height) = block_id.height.checked_sub(1) {
+            self.tip = BlockId {
+                height,
+                hash: prev_hash,
+            };
+        }
+    }
+}